@@ -26,7 +26,7 @@ use crate::isupport::{
 use crate::message::{message_id, server_time, source};
 use crate::target::{self, Target};
 use crate::time::Posix;
-use crate::user::{ChannelUsers, Nick, NickRef};
+use crate::user::{AccountIndex, ChannelUsers, Nick, NickRef};
 use crate::{
     Server, User, buffer, compression, config, ctcp, dcc, environment,
     file_transfer, isupport, message, mode, server,
@@ -35,7 +35,6 @@ use crate::{
 pub mod on_connect;
 
 const HIGHLIGHT_BLACKOUT_INTERVAL: Duration = Duration::from_secs(5);
-const CLIENT_CHATHISTORY_LIMIT: u16 = 500;
 const CHATHISTORY_REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
 
 #[derive(Debug, Clone, Copy)]
@@ -157,13 +156,21 @@ pub struct Client {
     supports_bouncer_networks: bool,
     sasl_succeeded: bool,
     chathistory_requests: HashMap<Target, ChatHistoryRequest>,
-    chathistory_exhausted: HashMap<Target, bool>,
+    chathistory_states: isupport::ChatHistoryStates,
+    chathistory_fuzz_windows: HashMap<Target, isupport::FuzzWindow>,
+    chathistory_page_stalls: HashMap<Target, isupport::PageStall>,
     chathistory_targets_request: Option<ChatHistoryRequest>,
     highlight_notification_blackout: HighlightNotificationBlackout,
     registration_required_channels: Vec<target::Channel>,
-    isupport: HashMap<isupport::Kind, isupport::Parameter>,
+    isupport: isupport::ISupport,
+    // Parameters as received, before finalize validation or overrides
+    isupport_received: HashMap<isupport::Kind, isupport::Parameter>,
+    isupport_overrides: Vec<isupport::Operation>,
+    // Parameters advertised via their draft/ spelling
+    isupport_drafts: HashSet<isupport::Kind>,
     who_polls: VecDeque<WhoPoll>,
     who_poll_interval: BackoffInterval,
+    accounts: AccountIndex,
     resolved_netid: Option<String>,
 }
 
@@ -179,6 +186,20 @@ impl Client {
         config: Arc<config::Server>,
         sender: mpsc::Sender<proto::Message>,
     ) -> Self {
+        let isupport_overrides = config
+            .isupport_overrides
+            .iter()
+            .filter_map(|token| {
+                isupport::parse_override(token)
+                    .inspect_err(|error| {
+                        log::warn!(
+                            "[{server}] invalid ISUPPORT override: {token} ({error})"
+                        );
+                    })
+                    .ok()
+            })
+            .collect();
+
         Self {
             server,
             handle: sender,
@@ -205,13 +226,19 @@ impl Client {
             supports_bouncer_networks: false,
             sasl_succeeded: false,
             chathistory_requests: HashMap::new(),
-            chathistory_exhausted: HashMap::new(),
+            chathistory_states: isupport::ChatHistoryStates::default(),
+            chathistory_fuzz_windows: HashMap::new(),
+            chathistory_page_stalls: HashMap::new(),
             chathistory_targets_request: None,
             highlight_notification_blackout:
                 HighlightNotificationBlackout::Blackout(Instant::now()),
             registration_required_channels: vec![],
-            isupport: HashMap::new(),
+            isupport: isupport::ISupport::default(),
+            isupport_received: HashMap::new(),
+            isupport_overrides,
+            isupport_drafts: HashSet::new(),
             who_polls: VecDeque::new(),
+            accounts: AccountIndex::default(),
             who_poll_interval: BackoffInterval::from_duration(
                 config.who_poll_interval,
             ),
@@ -357,6 +384,21 @@ impl Client {
         buffer: &buffer::Upstream,
         mut message: message::Encoded,
     ) {
+        // Split KICKs exceeding the KICK target limit into multiple commands
+        if let Command::KICK(channel, users, comment) = &message.command
+            && let Some(target_limit) =
+                isupport::find_target_limit(&self.isupport, "KICK")
+            && users.split(',').count() > target_limit as usize
+        {
+            for kick in
+                group_kicks(channel, users, comment.as_deref(), target_limit)
+            {
+                self.send(buffer, message::Encoded::from(kick));
+            }
+
+            return;
+        }
+
         if self.supports_labels {
             let label = generate_label();
             let context = Context::new(&message, buffer.clone());
@@ -570,12 +612,19 @@ impl Client {
                                                 limit,
                                             ) = subcommand
                                             {
-                                                self.chathistory_exhausted.insert(
-                                                    batch_target.clone(),
+                                                self.chathistory_states.receive(
+                                                    batch_target,
                                                     finished.events.len() < *limit as usize,
                                                 );
                                             }
 
+                                                update_fuzz_window(
+                                                    &mut self.chathistory_fuzz_windows,
+                                                    batch_target,
+                                                    subcommand,
+                                                    finished.events.len(),
+                                                );
+
                                                 match subcommand {
                                                 ChatHistorySubcommand::Latest(
                                                     target,
@@ -623,6 +672,21 @@ impl Client {
 
                                                     None
                                                 }
+                                                ChatHistorySubcommand::After(
+                                                    target,
+                                                    message_reference,
+                                                    _,
+                                                ) => {
+                                                    log::debug!(
+                                                        "[{}] received {} messages in {} after {}",
+                                                        self.server,
+                                                        finished.events.len(),
+                                                        target,
+                                                        message_reference,
+                                                    );
+
+                                                    None
+                                                }
                                                 ChatHistorySubcommand::Between(
                                                     target,
                                                     start_message_reference,
@@ -805,9 +869,11 @@ impl Client {
                                         message.user(self.casemapping())
                                     {
                                         // If direct message, update resolved queries with user
-                                        if target
-                                            == &self.nickname().to_string()
-                                        {
+                                        if isupport::is_self(
+                                            &self.isupport,
+                                            target,
+                                            &self.nickname().to_string(),
+                                        ) {
                                             self.resolved_queries.replace(
                                                 target::Query::from(user),
                                             );
@@ -1461,6 +1527,8 @@ impl Client {
                 let new_nick =
                     Nick::from_str(nick.as_str(), self.casemapping());
 
+                self.accounts.rename(old_user.nickname(), new_nick.clone());
+
                 self.chanmap.values_mut().for_each(|channel| {
                     if let Some(user) = channel.users.take(&old_user) {
                         channel
@@ -1519,6 +1587,8 @@ impl Client {
                     channel.users.remove(&user);
                 });
 
+                self.accounts.remove(user.nickname());
+
                 return Ok(vec![Event::Broadcast(Broadcast::Quit {
                     user,
                     comment: comment.clone(),
@@ -1530,14 +1600,17 @@ impl Client {
                 let user = ok!(message.user(self.casemapping()));
 
                 if user.nickname() == self.nickname() {
-                    self.chanmap.shift_remove(&context!(
-                        target::Channel::parse(
-                            channel,
-                            self.chantypes(),
-                            self.statusmsg(),
-                            self.casemapping(),
-                        )
+                    let channel = context!(target::Channel::parse(
+                        channel,
+                        self.chantypes(),
+                        self.statusmsg(),
+                        self.casemapping(),
                     ));
+
+                    self.reset_chathistory(&Target::Channel(channel.clone()));
+                    self.chanmap.shift_remove(&channel);
+
+                    self.forget_unseen_accounts();
                 } else if let Some(channel) =
                     self.chanmap.get_mut(&context!(target::Channel::parse(
                         channel,
@@ -1547,6 +1620,10 @@ impl Client {
                     )))
                 {
                     channel.users.remove(&user);
+
+                    if self.user_channels(user.nickname()).is_empty() {
+                        self.accounts.remove(user.nickname());
+                    }
                 }
             }
             Command::JOIN(channel, accountname) => {
@@ -1605,6 +1682,12 @@ impl Client {
                         user
                     };
 
+                    if self.supports_extended_join
+                        && let Some(accountname) = accountname
+                    {
+                        self.accounts.set(user.nickname(), accountname);
+                    }
+
                     channel.users.insert(user);
                 }
             }
@@ -1620,8 +1703,13 @@ impl Client {
                     if casemapping.normalize(victim)
                         == self.nickname().as_normalized_str()
                     {
+                        self.reset_chathistory(&Target::Channel(
+                            channel.clone(),
+                        ));
                         self.chanmap.shift_remove(&channel);
 
+                        self.forget_unseen_accounts();
+
                         return Ok(vec![
                             Event::Broadcast(Broadcast::Kick {
                                 kicker: ok!(message.user(casemapping)),
@@ -1634,10 +1722,14 @@ impl Client {
                         ]);
                     } else if let Some(channel) = self.chanmap.get_mut(&channel)
                     {
-                        channel.users.remove(&User::from(Nick::from_str(
-                            victim.as_str(),
-                            casemapping,
-                        )));
+                        let victim =
+                            Nick::from_str(victim.as_str(), casemapping);
+
+                        channel.users.remove(&User::from(victim.clone()));
+
+                        if self.user_channels(victim.as_nickref()).is_empty() {
+                            self.accounts.remove(victim.as_nickref());
+                        }
                     }
                 }
             }
@@ -1657,11 +1749,16 @@ impl Client {
                     if let Some(client_channel) =
                         self.chanmap.get_mut(&target_channel)
                     {
-                        client_channel.update_user_away(
-                            ok!(args.get(5)),
-                            ok!(args.get(6)),
-                            casemapping,
-                        );
+                        if let Some(record) =
+                            isupport::parse_who_reply(&message, &self.isupport)
+                            && let Some(away) = record.flags.away
+                        {
+                            client_channel.update_user_away(
+                                &record.nick,
+                                away,
+                                casemapping,
+                            );
+                        }
 
                         if let Some(who_poll) = self
                             .who_polls
@@ -1754,38 +1851,44 @@ impl Client {
                             _ => (),
                         }
 
-                        if let WhoStatus::Receiving(WhoSource::Poll, Some(_)) =
-                            &who_poll.status
+                        if let WhoStatus::Receiving(
+                            WhoSource::Poll,
+                            Some(request_token),
+                        ) = &who_poll.status
                         {
-                            // Check token to ~ensure reply is to poll request
+                            // Require an exact token match to ensure the
+                            // reply is to the poll request, discarding others
                             if let Ok(token) =
                                 ok!(args.get(1)).parse::<WhoToken>()
+                                && token == *request_token
+                                && let Some(record) =
+                                    isupport::WhoxRecord::parse(
+                                        args,
+                                        &self.isupport,
+                                    )
                             {
-                                if token == WhoXPollParameters::Default.token()
-                                {
+                                if let Some(away) = record.flags.away {
                                     client_channel.update_user_away(
-                                        ok!(args.get(3)),
-                                        ok!(args.get(4)),
-                                        casemapping,
-                                    );
-                                } else if token
-                                    == WhoXPollParameters::WithAccountName
-                                        .token()
-                                {
-                                    let user = ok!(args.get(3));
-
-                                    client_channel.update_user_away(
-                                        user,
-                                        ok!(args.get(4)),
+                                        &record.nick,
+                                        away,
                                         casemapping,
                                     );
+                                }
 
+                                if WhoXPollParameters::WithAccountName
+                                    .matches_token(&token)
+                                {
                                     client_channel.update_user_accountname(
-                                        user,
-                                        ok!(args.get(5)),
+                                        &record.nick,
+                                        record
+                                            .account
+                                            .as_deref()
+                                            .unwrap_or("0"),
                                         casemapping,
                                     );
                                 }
+
+                                self.accounts.update(&record, casemapping);
                             }
                         }
                     }
@@ -2194,6 +2297,12 @@ impl Client {
 
                     match operation {
                         Ok(operation) => {
+                            isupport::record_draft(
+                                &mut self.isupport_drafts,
+                                arg,
+                                &operation,
+                            );
+
                             match operation {
                                 isupport::Operation::Add(parameter) => {
                                     if let Some(kind) = parameter.kind() {
@@ -2204,6 +2313,11 @@ impl Client {
                                         );
 
                                         self.isupport.insert(
+                                            isupport::Operation::Add(
+                                                parameter.clone(),
+                                            ),
+                                        );
+                                        self.isupport_received.insert(
                                             kind.clone(),
                                             parameter.clone(),
                                         );
@@ -2214,11 +2328,32 @@ impl Client {
                                                     resolved_nick.renormalize(casemapping);
                                                 }
                                                 self.configured_nick.renormalize(casemapping);
+                                                self.accounts.renormalize(casemapping);
+
+                                                // CASEMAPPING may arrive in a
+                                                // later RPL_ISUPPORT line than
+                                                // targets were created in, so
+                                                // state keyed by a target is
+                                                // re-keyed whenever it arrives
+                                                self.chanmap = self
+                                                    .chanmap
+                                                    .drain(..)
+                                                    .map(|(channel, state)| {
+                                                        (channel.renormalized(casemapping), state)
+                                                    })
+                                                    .collect();
+                                                for channel in &mut self.registration_required_channels {
+                                                    *channel = channel.renormalized(casemapping);
+                                                }
+                                                target::renormalize_keys(&mut self.batches, casemapping);
+                                                target::renormalize_keys(&mut self.chathistory_requests, casemapping);
+                                                self.chathistory_states.renormalize(casemapping);
+                                                target::renormalize_keys(&mut self.chathistory_fuzz_windows, casemapping);
+                                                target::renormalize_keys(&mut self.chathistory_page_stalls, casemapping);
 
                                                 // TODO: When casemapping
-                                                // changes, ChannelUsers,
-                                                // Targets, etc should be
-                                                // renormalized and resorted
+                                                // changes, ChannelUsers should
+                                                // be renormalized and resorted
                                             }
                                             isupport::Parameter::MONITOR(
                                                 target_limit,
@@ -2256,14 +2391,15 @@ impl Client {
                                         );
                                     }
                                 }
-                                isupport::Operation::Remove(_) => {
+                                isupport::Operation::Remove(ref parameter) => {
                                     if let Some(kind) = operation.kind() {
                                         log::info!(
                                             "[{}] removing ISUPPORT parameter: {:?}",
                                             self.server,
                                             kind
                                         );
-                                        self.isupport.remove(&kind);
+                                        self.isupport.remove(parameter);
+                                        self.isupport_received.remove(&kind);
                                     }
                                 }
                             };
@@ -2271,8 +2407,10 @@ impl Client {
                         Err(error) => {
                             // Last argument is :are supported by this server
                             if index != args_len - 1 {
-                                if error == isupport::UNKNOWN_ISUPPORT_PARAMETER
-                                {
+                                if matches!(
+                                    error,
+                                    isupport::ParseError::UnknownParameter(_)
+                                ) {
                                     log::info!(
                                         "[{}] unable to parse ISUPPORT parameter: {} ({})",
                                         self.server,
@@ -2292,6 +2430,16 @@ impl Client {
                     }
                 }
 
+                // Validation depends on parameters which may arrive in any
+                // order, so it is re-run from the received parameters (and
+                // overrides re-applied) after every update
+                self.isupport.restore_received(&self.isupport_received);
+                self.isupport.apply_overrides(&self.isupport_overrides);
+
+                for warning in self.isupport.finalize() {
+                    log::warn!("[{}] {warning}", self.server);
+                }
+
                 return Ok(events);
             }
             Command::TAGMSG(_) => {
@@ -2308,6 +2456,8 @@ impl Client {
                     }
                 });
 
+                self.accounts.set(old_user.nickname(), accountname);
+
                 if old_user.nickname() == self.nickname()
                     && accountname != "*"
                     && !self.registration_required_channels.is_empty()
@@ -2670,14 +2820,7 @@ impl Client {
     }
 
     pub fn chathistory_limit(&self) -> u16 {
-        if let Some(isupport::Parameter::CHATHISTORY(server_limit)) =
-            self.isupport.get(&isupport::Kind::CHATHISTORY)
-            && *server_limit != 0
-        {
-            return std::cmp::min(*server_limit, CLIENT_CHATHISTORY_LIMIT);
-        }
-
-        CLIENT_CHATHISTORY_LIMIT
+        self.isupport.chathistory_limit()
     }
 
     pub fn chathistory_message_reference_types(
@@ -2701,36 +2844,94 @@ impl Client {
             .map(|request| request.subcommand.clone())
     }
 
+    pub fn account_for_nick(&self, nick: NickRef) -> Option<&str> {
+        self.accounts.account_for_nick(nick)
+    }
+
+    pub fn isupport_is_draft(&self, kind: &isupport::Kind) -> bool {
+        self.isupport_drafts.contains(kind)
+    }
+
+    pub fn chathistory_fuzz_window(
+        &self,
+        target: &Target,
+    ) -> isupport::FuzzWindow {
+        self.chathistory_fuzz_windows
+            .get(target)
+            .copied()
+            .unwrap_or_default()
+    }
+
     pub fn send_chathistory_request(
         &mut self,
-        subcommand: ChatHistorySubcommand,
+        mut subcommand: ChatHistorySubcommand,
     ) {
         use std::collections::hash_map;
 
         if self.supports_chathistory {
-            if let Some(target) = subcommand.target() {
-                if let hash_map::Entry::Vacant(entry) =
-                    self.chathistory_requests.entry(Target::parse(
-                        target,
-                        self.chantypes(),
-                        self.statusmsg(),
-                        self.casemapping(),
-                    ))
+            let batch = ChatHistoryBatch::from_subcommand(
+                &subcommand,
+                self.chantypes(),
+                self.statusmsg(),
+                self.casemapping(),
+            );
+
+            let supports_msgid =
+                isupport::supports_msgid_references(&self.isupport);
+
+            let mut fuzz_window = batch
+                .target()
+                .map_or_else(isupport::FuzzWindow::default, |target| {
+                    self.chathistory_fuzz_window(&target)
+                });
+
+            if let Some(target) = batch.target()
+                && !self.chathistory_requests.contains_key(&target)
+            {
+                let max_limit = self.chathistory_limit();
+
+                if matches!(subcommand, ChatHistorySubcommand::Latest(..)) {
+                    // Paging starts over from the latest messages
+                    self.chathistory_page_stalls.remove(&target);
+                } else if self
+                    .chathistory_page_stalls
+                    .entry(target.clone())
+                    .or_default()
+                    .nudge(&mut subcommand, &mut fuzz_window, max_limit)
                 {
-                    entry.insert(ChatHistoryRequest {
-                        subcommand: subcommand.clone(),
-                        requested_at: Instant::now(),
-                    });
-                } else {
-                    return;
+                    log::info!(
+                        "[{}] paging stalled in {target}, nudging request",
+                        self.server,
+                    );
+
+                    self.chathistory_fuzz_windows.insert(target, fuzz_window);
+                }
+            }
+
+            match batch {
+                ChatHistoryBatch::Target(target) => {
+                    if let hash_map::Entry::Vacant(entry) =
+                        self.chathistory_requests.entry(target)
+                    {
+                        entry.insert(ChatHistoryRequest {
+                            subcommand: subcommand.clone(),
+                            requested_at: Instant::now(),
+                        });
+                    } else {
+                        return;
+                    }
+                }
+                ChatHistoryBatch::Targets => {
+                    if self.chathistory_targets_request.is_some() {
+                        return;
+                    }
+
+                    self.chathistory_targets_request =
+                        Some(ChatHistoryRequest {
+                            subcommand: subcommand.clone(),
+                            requested_at: Instant::now(),
+                        });
                 }
-            } else if self.chathistory_targets_request.is_some() {
-                return;
-            } else {
-                self.chathistory_targets_request = Some(ChatHistoryRequest {
-                    subcommand: subcommand.clone(),
-                    requested_at: Instant::now(),
-                });
             }
 
             match subcommand {
@@ -2742,6 +2943,8 @@ impl Client {
                     let command_message_reference =
                         isupport::fuzz_start_message_reference(
                             message_reference,
+                            fuzz_window,
+                            supports_msgid,
                         );
 
                     log::debug!(
@@ -2764,7 +2967,11 @@ impl Client {
                     limit,
                 ) => {
                     let command_message_reference =
-                        isupport::fuzz_end_message_reference(message_reference);
+                        isupport::fuzz_end_message_reference(
+                            message_reference,
+                            fuzz_window,
+                            supports_msgid,
+                        );
 
                     log::debug!(
                         "[{}] requesting {limit} messages in {target} before {}",
@@ -2780,6 +2987,32 @@ impl Client {
                         limit.to_string(),
                     ));
                 }
+                ChatHistorySubcommand::After(
+                    target,
+                    message_reference,
+                    limit,
+                ) => {
+                    let command_message_reference =
+                        isupport::fuzz_start_message_reference(
+                            message_reference,
+                            fuzz_window,
+                            supports_msgid,
+                        );
+
+                    log::debug!(
+                        "[{}] requesting {limit} messages in {target} after {}",
+                        self.server,
+                        command_message_reference,
+                    );
+
+                    let _ = self.handle.try_send(command!(
+                        "CHATHISTORY",
+                        "AFTER",
+                        target.to_string(),
+                        command_message_reference.to_string(),
+                        limit.to_string(),
+                    ));
+                }
                 ChatHistorySubcommand::Between(
                     target,
                     start_message_reference,
@@ -2792,6 +3025,8 @@ impl Client {
                     ) = isupport::fuzz_message_reference_range(
                         start_message_reference,
                         end_message_reference,
+                        fuzz_window,
+                        supports_msgid,
                     );
 
                     log::debug!(
@@ -2841,6 +3076,9 @@ impl Client {
                     ) = isupport::fuzz_message_reference_range(
                         command_start_message_reference,
                         command_end_message_reference,
+                        fuzz_window,
+                        // TARGETS only takes timestamps
+                        false,
                     );
 
                     log::debug!(
@@ -2870,11 +3108,14 @@ impl Client {
         }
     }
 
+    // History is paged from scratch after leaving the channel
+    fn reset_chathistory(&mut self, target: &Target) {
+        self.chathistory_states.reset(target);
+        self.chathistory_page_stalls.remove(target);
+    }
+
     pub fn chathistory_exhausted(&self, target: &Target) -> bool {
-        self.chathistory_exhausted
-            .get(target)
-            .copied()
-            .unwrap_or_default()
+        self.chathistory_states.state(target) == ChatHistoryState::Exhausted
     }
 
     pub fn load_chathistory_targets_timestamp(
@@ -2962,6 +3203,18 @@ impl Client {
         self.chanmap.get(channel).map(|chanimpl| &chanimpl.users)
     }
 
+    // Accounts of users no longer in any joined channel are dropped, since
+    // their nick may be taken by someone else
+    fn forget_unseen_accounts(&mut self) {
+        let chanmap = &self.chanmap;
+
+        self.accounts.retain(|nick| {
+            chanmap
+                .values()
+                .any(|channel| channel.users.get_by_nick(nick).is_some())
+        });
+    }
+
     fn user_channels(&self, nick: NickRef) -> Vec<target::Channel> {
         self.chanmap
             .iter()
@@ -3053,11 +3306,13 @@ impl Client {
                         Some(whox_params.token()),
                     );
 
-                    self.handle.try_send(command!(
-                        "WHO",
-                        who_poll.channel.to_string(),
-                        whox_params.fields().to_string(),
-                        whox_params.token().to_owned()
+                    self.handle.try_send(proto::Message::from(
+                        isupport::build_whox(
+                            &self.isupport,
+                            who_poll.channel.as_str(),
+                            whox_params.fields(),
+                            Some(whox_params.token()),
+                        )?,
                     ))?;
                 } else {
                     who_poll.status = WhoStatus::Requested(
@@ -3093,23 +3348,23 @@ impl Client {
     }
 
     pub fn casemapping(&self) -> isupport::CaseMap {
-        isupport::get_casemapping_or_default(&self.isupport)
+        self.isupport.casemapping()
     }
 
     pub fn chanmodes(&self) -> &[isupport::ModeKind] {
-        isupport::get_chanmodes_or_default(&self.isupport)
+        self.isupport.chanmodes()
     }
 
     pub fn chantypes(&self) -> &[char] {
-        isupport::get_chantypes_or_default(&self.isupport)
+        self.isupport.chantypes()
     }
 
     pub fn prefix(&self) -> &[isupport::PrefixMap] {
-        isupport::get_prefix_or_default(&self.isupport)
+        self.isupport.prefix()
     }
 
     pub fn statusmsg(&self) -> &[char] {
-        isupport::get_statusmsg_or_default(&self.isupport)
+        self.isupport.statusmsg()
     }
 
     pub fn is_channel(&self, target: &str) -> bool {
@@ -3149,6 +3404,26 @@ fn continue_chathistory_between(
     })
 }
 
+// Adapts the fuzz window of a target to how many messages were received for
+// a request with a limit
+fn update_fuzz_window(
+    fuzz_windows: &mut HashMap<Target, isupport::FuzzWindow>,
+    target: &Target,
+    subcommand: &ChatHistorySubcommand,
+    received: usize,
+) {
+    if let ChatHistorySubcommand::Latest(_, _, limit)
+    | ChatHistorySubcommand::Before(_, _, limit)
+    | ChatHistorySubcommand::After(_, _, limit)
+    | ChatHistorySubcommand::Between(_, _, _, limit) = subcommand
+    {
+        fuzz_windows
+            .entry(target.clone())
+            .or_default()
+            .update(received, *limit);
+    }
+}
+
 async fn chathistory_targets_path(server: &Server) -> Result<PathBuf, Error> {
     let data_dir = environment::data_dir();
 
@@ -3391,7 +3666,7 @@ impl Map {
         server: &Server,
     ) -> HashMap<isupport::Kind, isupport::Parameter> {
         self.client(server)
-            .map(|client| client.isupport.clone())
+            .map(|client| client.isupport.as_map().clone())
             .unwrap_or_default()
     }
 
@@ -3449,7 +3724,7 @@ impl Map {
 
     pub fn get_server_chathistory_limit(&self, server: &Server) -> u16 {
         self.client(server)
-            .map_or(CLIENT_CHATHISTORY_LIMIT, |client| {
+            .map_or(isupport::CLIENT_CHATHISTORY_LIMIT, |client| {
                 client.chathistory_limit()
             })
     }
@@ -3619,6 +3894,25 @@ pub enum ChatHistoryBatch {
 }
 
 impl ChatHistoryBatch {
+    // Requests without a target (i.e. TARGETS) are tracked by, and have their
+    // replies routed to, the targets batch
+    pub fn from_subcommand(
+        subcommand: &ChatHistorySubcommand,
+        chantypes: &[char],
+        statusmsg: &[char],
+        casemapping: isupport::CaseMap,
+    ) -> Self {
+        match subcommand.target() {
+            Some(target) => ChatHistoryBatch::Target(Target::parse(
+                target,
+                chantypes,
+                statusmsg,
+                casemapping,
+            )),
+            None => ChatHistoryBatch::Targets,
+        }
+    }
+
     pub fn target(&self) -> Option<Target> {
         match self {
             ChatHistoryBatch::Target(batch_target) => {
@@ -3673,23 +3967,14 @@ impl Channel {
     pub fn update_user_away(
         &mut self,
         user: &str,
-        flags: &str,
+        away: bool,
         casemapping: isupport::CaseMap,
     ) {
         let user = User::from(Nick::from_str(user, casemapping));
 
-        if let Some(away_flag) = flags.chars().next() {
-            // H = Here, G = gone (away)
-            let away = match away_flag {
-                'G' => true,
-                'H' => false,
-                _ => return,
-            };
-
-            if let Some(mut user) = self.users.take(&user) {
-                user.update_away(away);
-                self.users.insert(user);
-            }
+        if let Some(mut user) = self.users.take(&user) {
+            user.update_away(away);
+            self.users.insert(user);
         }
     }
 
@@ -3892,6 +4177,26 @@ fn group_monitors(
     .map(|targets| command!("MONITOR", "+", targets.into_iter().join(",")))
 }
 
+fn group_kicks(
+    channel: &str,
+    users: &str,
+    comment: Option<&str>,
+    target_limit: u16,
+) -> Vec<proto::Message> {
+    users
+        .split(',')
+        .chunks(usize::from(target_limit))
+        .into_iter()
+        .map(|users| {
+            proto::Message::from(Command::KICK(
+                channel.to_string(),
+                users.into_iter().join(","),
+                comment.map(String::from),
+            ))
+        })
+        .collect()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -3905,3 +4210,73 @@ pub enum Error {
     #[error(transparent)]
     BouncerNetwork(#[from] bouncer::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chathistory_batch_routing() {
+        let casemapping = isupport::CaseMap::default();
+
+        let latest = ChatHistorySubcommand::Latest(
+            Target::parse("#halloy", &['#'], &[], casemapping),
+            MessageReference::None,
+            100,
+        );
+
+        assert!(matches!(
+            ChatHistoryBatch::from_subcommand(&latest, &['#'], &[], casemapping),
+            ChatHistoryBatch::Target(Target::Channel(channel))
+                if channel.as_str() == "#halloy"
+        ));
+
+        let targets = ChatHistorySubcommand::Targets(
+            MessageReference::None,
+            MessageReference::Timestamp(Utc::now()),
+            100,
+        );
+
+        assert!(matches!(
+            ChatHistoryBatch::from_subcommand(
+                &targets,
+                &['#'],
+                &[],
+                casemapping
+            ),
+            ChatHistoryBatch::Targets
+        ));
+    }
+
+    #[test]
+    fn kicks_grouped_by_target_limit() {
+        let comment = Some("bye".to_string());
+
+        let kicks = group_kicks("#halloy", "a,b,c", comment.as_deref(), 1)
+            .into_iter()
+            .map(|message| message.command)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            kicks,
+            vec![
+                Command::KICK("#halloy".into(), "a".into(), comment.clone()),
+                Command::KICK("#halloy".into(), "b".into(), comment.clone()),
+                Command::KICK("#halloy".into(), "c".into(), comment.clone()),
+            ]
+        );
+
+        let kicks = group_kicks("#halloy", "a,b,c", None, 2)
+            .into_iter()
+            .map(|message| message.command)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            kicks,
+            vec![
+                Command::KICK("#halloy".into(), "a,b".into(), None),
+                Command::KICK("#halloy".into(), "c".into(), None),
+            ]
+        );
+    }
+}
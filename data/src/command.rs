@@ -170,8 +170,9 @@ pub fn parse(
                                     if channels
                                         .iter()
                                         .filter(|channel| {
-                                            channel
-                                                .starts_with(chan_limit.prefix)
+                                            channel.starts_with(|c: char| {
+                                                chan_limit.prefixes.contains(c)
+                                            })
                                         })
                                         .count()
                                         > limit
@@ -485,20 +486,8 @@ pub fn parse(
                                 (channel, users, comment)
                             };
 
-                        let target_limit = find_target_limit(isupport, "KICK")
-                            .map(|limit| limit as usize);
-
-                        if let Some(target_limit) = target_limit {
-                            let users = users.split(',').collect::<Vec<_>>();
-
-                            if users.len() > target_limit {
-                                return Err(Error::TooManyTargets {
-                                    name: "users",
-                                    number: users.len(),
-                                    max_number: target_limit,
-                                });
-                            }
-                        }
+                        // Users exceeding the KICK target limit are split
+                        // into multiple KICK commands when sent
 
                         if let Some(ref comment) = comment
                             && let Some(isupport::Parameter::KICKLEN(max_len)) =
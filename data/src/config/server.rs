@@ -97,6 +97,9 @@ pub struct Server {
     /// A list of nicknames to monitor (if MONITOR is supported by the server).
     pub monitor: Vec<String>,
     pub chathistory: bool,
+    /// ISUPPORT parameters which take precedence over those advertised by
+    /// the server.
+    pub isupport_overrides: Vec<String>,
     #[serde(skip)]
     pub order: u16,
 }
@@ -209,6 +212,7 @@ impl Default for Server {
             who_poll_interval: Duration::from_secs(2),
             monitor: Vec::default(),
             chathistory: true,
+            isupport_overrides: Vec::default(),
             order: 0,
         }
     }
@@ -1,7 +1,10 @@
+use std::collections::HashMap;
 use std::fmt;
 
 use irc::proto;
 
+use crate::isupport;
+
 // Reference: https://rawgit.com/DanielOaks/irc-rfcs/master/dist/draft-oakley-irc-ctcp-latest.html
 
 #[derive(Debug, Clone)]
@@ -56,6 +59,86 @@ pub fn is_query(text: &str) -> bool {
     text.starts_with('\u{1}')
 }
 
+const SPECIAL: [char; 4] = ['\u{1}', '\0', '\r', '\n'];
+
+// Text containing the CTCP delimiter would be mistaken for (or break) a CTCP
+// query, and NUL/CR/LF cannot be sent within a single message.  For a CTCP
+// query only its payload (between the delimiters) is considered.
+pub fn needs_ctcp_escape(text: &str) -> bool {
+    if is_query(text) {
+        ctcp_payload(text).contains(SPECIAL)
+    } else {
+        text.contains(SPECIAL)
+    }
+}
+
+// Plain text is never rewritten, so a character that can't be sent in it is
+// reported instead.  The payload of a CTCP query is quoted rather than
+// rejected (see prepare_privmsg_text).
+pub fn invalid_character(text: &str) -> Option<char> {
+    if is_query(text) {
+        None
+    } else {
+        text.chars().find(|c| SPECIAL.contains(c))
+    }
+}
+
+// Prepares outgoing PRIVMSG text.  The payload of a CTCP query is quoted as the
+// CTCP spec describes (see quote_ctcp_payload); any other text is returned
+// as-is and should be checked with invalid_character first.  A leading colon
+// needs no handling here, the trailing parameter is always encoded with its
+// ':' marker when it starts with one.
+pub fn prepare_privmsg_text(
+    isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    text: &str,
+) -> String {
+    if !is_query(text) || !needs_ctcp_escape(text) {
+        return text.to_string();
+    }
+
+    format!(
+        "\u{1}{}\u{1}",
+        quote_ctcp_payload(isupport, ctcp_payload(text))
+    )
+}
+
+// The delimiter is X-quoted (along with backslashes, so the quoting is
+// reversible) and NUL/CR/LF are low-level quoted with M-QUOTE (along with
+// M-QUOTE itself).  Each quoting is only applied when the payload needs it.
+// With UTF8ONLY the legacy 8-bit quoting is not used, and NUL/CR/LF are
+// dropped instead.
+fn quote_ctcp_payload(
+    isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    payload: &str,
+) -> String {
+    let x_quote = payload.contains('\u{1}');
+    let m_quote = !isupport.contains_key(&isupport::Kind::UTF8ONLY)
+        && payload.contains(['\0', '\r', '\n']);
+
+    let mut quoted = String::with_capacity(payload.len());
+
+    for c in payload.chars() {
+        match c {
+            '\u{1}' => quoted.push_str("\\a"),
+            '\\' if x_quote => quoted.push_str("\\\\"),
+            '\0' | '\r' | '\n' if !m_quote => (),
+            '\0' => quoted.push_str("\u{10}0"),
+            '\r' => quoted.push_str("\u{10}r"),
+            '\n' => quoted.push_str("\u{10}n"),
+            '\u{10}' if m_quote => quoted.push_str("\u{10}\u{10}"),
+            _ => quoted.push(c),
+        }
+    }
+
+    quoted
+}
+
+fn ctcp_payload(query: &str) -> &str {
+    let payload = query.strip_prefix('\u{1}').unwrap_or(query);
+
+    payload.strip_suffix('\u{1}').unwrap_or(payload)
+}
+
 pub fn parse_query(text: &str) -> Option<Query<'_>> {
     let query = text
         .strip_suffix('\u{1}')
@@ -108,3 +191,68 @@ pub fn response_message(
 ) -> proto::Message {
     proto::command!("NOTICE", target, format(command, params))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn plain_text() {
+        let text = "hello, world \\o/ \u{10}";
+
+        assert!(!needs_ctcp_escape(text));
+        assert_eq!(prepare_privmsg_text(&HashMap::new(), text), text);
+    }
+
+    #[test]
+    fn text_with_delimiter() {
+        let text = "a \u{1}b\\c\r\n";
+
+        assert!(needs_ctcp_escape(text));
+        assert_eq!(invalid_character(text), Some('\u{1}'));
+        assert_eq!(invalid_character("a\r\nb"), Some('\r'));
+        assert_eq!(prepare_privmsg_text(&HashMap::new(), text), text);
+    }
+
+    #[test]
+    fn text_with_leading_colon() {
+        let text = ":) hi";
+
+        assert!(!needs_ctcp_escape(text));
+        assert_eq!(
+            proto::format::message(proto::command!(
+                "PRIVMSG",
+                "#halloy",
+                prepare_privmsg_text(&HashMap::new(), text)
+            )),
+            "PRIVMSG #halloy ::) hi\r\n"
+        );
+    }
+
+    #[test]
+    fn ctcp_payload_quoting() {
+        let action = "\u{1}ACTION waves \\o/\u{1}";
+
+        assert!(!needs_ctcp_escape(action));
+        assert_eq!(prepare_privmsg_text(&HashMap::new(), action), action);
+
+        let text = "\u{1}ACTION a\0b\u{10}c\u{1}d\\\u{1}";
+
+        assert!(needs_ctcp_escape(text));
+        assert_eq!(invalid_character(text), None);
+        assert_eq!(
+            prepare_privmsg_text(&HashMap::new(), text),
+            "\u{1}ACTION a\u{10}0b\u{10}\u{10}c\\ad\\\\\u{1}"
+        );
+
+        let isupport = HashMap::from([(
+            isupport::Kind::UTF8ONLY,
+            isupport::Parameter::UTF8ONLY,
+        )]);
+
+        assert_eq!(
+            prepare_privmsg_text(&isupport, text),
+            "\u{1}ACTION ab\u{10}c\\ad\\\\\u{1}"
+        );
+    }
+}
@@ -67,7 +67,23 @@ impl Dashboard {
 
         let bytes = std::fs::read(path)?;
 
-        Ok(compression::decompress(&bytes)?)
+        Self::decode(&bytes)
+    }
+
+    // Invalid layouts (e.g. a split of two empty panes, as saved from the
+    // pane grid) are repaired rather than failing to load the dashboard
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut dashboard: Self = compression::decompress(bytes)?;
+
+        for pane in std::iter::once(&mut dashboard.pane)
+            .chain(dashboard.popout_panes.iter_mut())
+        {
+            for error in pane.repair() {
+                log::warn!("repaired invalid dashboard layout: {error}");
+            }
+        }
+
+        Ok(dashboard)
     }
 
     pub async fn save(self) -> Result<(), Error> {
@@ -98,3 +114,51 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pane::Axis;
+
+    #[test]
+    fn load_repairs_empty_split() {
+        let empty_split = || Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: Box::new(Pane::Empty),
+            b: Box::new(Pane::Empty),
+        };
+
+        let logs = || {
+            Box::new(Pane::Buffer {
+                buffer: Buffer::Internal(buffer::Internal::Logs),
+            })
+        };
+
+        let dashboard = Dashboard {
+            pane: empty_split(),
+            popout_panes: vec![Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 1.5,
+                a: logs(),
+                b: Box::new(empty_split()),
+            }],
+            buffer_settings: BufferSettings::default(),
+            focus_buffer: None,
+        };
+
+        let bytes = compression::compress(&dashboard).unwrap();
+        let dashboard = Dashboard::decode(&bytes).unwrap();
+
+        assert_eq!(dashboard.pane, Pane::Empty);
+        assert_eq!(
+            dashboard.popout_panes,
+            vec![Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: logs(),
+                b: Box::new(Pane::Empty),
+            }]
+        );
+    }
+}
@@ -8,7 +8,7 @@ use crate::message::formatting;
 use crate::target::Target;
 use crate::user::{ChannelUsers, NickRef};
 use crate::{
-    Command, Config, Message, Server, User, command, isupport, message,
+    Command, Config, Message, Server, User, command, ctcp, isupport, message,
 };
 
 const INPUT_HISTORY_LENGTH: usize = 100;
@@ -25,6 +25,12 @@ pub fn parse(
             Ok(Command::Internal(command)) => {
                 return Ok(Parsed::Internal(command));
             }
+            Ok(Command::Irc(command::Irc::Msg(targets, text))) => {
+                Content::Command(command::Irc::Msg(
+                    targets,
+                    prepare_text(isupport, &text)?,
+                ))
+            }
             Ok(Command::Irc(command)) => Content::Command(command),
             Err(command::Error::MissingSlash) => {
                 let text = match auto_format {
@@ -33,7 +39,7 @@ pub fn parse(
                     AutoFormat::All => formatting::encode(input, false),
                 };
 
-                Content::Text(text)
+                Content::Text(prepare_text(isupport, &text)?)
             }
             Err(error) => return Err(Error::Command(error)),
         };
@@ -49,6 +55,17 @@ pub fn parse(
     Ok(Parsed::Input(Input { buffer, content }))
 }
 
+fn prepare_text(
+    isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    text: &str,
+) -> Result<String, Error> {
+    if let Some(character) = ctcp::invalid_character(text) {
+        return Err(Error::InvalidCharacter(character));
+    }
+
+    Ok(ctcp::prepare_privmsg_text(isupport, text))
+}
+
 pub enum Parsed {
     Input(Input),
     Internal(command::Internal),
@@ -254,6 +271,8 @@ pub enum Error {
         format::BYTE_LIMIT
     )]
     ExceedsByteLimit { message_bytes: usize },
+    #[error("message contains an invalid character ({0:?})")]
+    InvalidCharacter(char),
     #[error(transparent)]
     Command(#[from] command::Error),
 }
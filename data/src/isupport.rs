@@ -1,20 +1,24 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::fmt;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::{fmt, iter};
 
 use chrono::format::SecondsFormat;
 use chrono::{DateTime, Utc};
 use irc::proto;
+use itertools::{Either, Itertools};
 
 use crate::Message;
+use crate::message::formatting;
+use crate::mode;
 use crate::target::Target;
 
 // Utilized ISUPPORT parameters should have an associated Kind enum variant
 // returned by Operation::kind() and Parameter::kind()
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, strum::IntoStaticStr)]
 pub enum Kind {
+    ACCOUNTEXTBAN,
     AWAYLEN,
     BOUNCER_NETID,
     CASEMAPPING,
@@ -23,16 +27,24 @@ pub enum Kind {
     CHANNELLEN,
     CHANTYPES,
     CHATHISTORY,
+    CLIENTTAGDENY,
+    CLIENTVER,
     CNOTICE,
     CPRIVMSG,
     ELIST,
+    EXTBAN,
+    HOSTLEN,
     KEYLEN,
     KICKLEN,
     KNOCK,
+    LINELEN,
+    MAXCHANNELS,
+    MAXLIST,
     MODES,
     MONITOR,
     MSGREFTYPES,
     NAMELEN,
+    NETWORK,
     NICKLEN,
     PREFIX,
     SAFELIST,
@@ -40,24 +52,74 @@ pub enum Kind {
     TARGMAX,
     TOPICLEN,
     USERIP,
+    USERLEN,
     UTF8ONLY,
+    WATCH,
     WHOX,
 }
 
-#[derive(Debug)]
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        self.into()
+    }
+}
+
+// Ordered alphabetically by name, for stable output
+impl Ord for Kind {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for Kind {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Operation {
     Add(Parameter),
     Remove(String),
 }
 
-pub const UNKNOWN_ISUPPORT_PARAMETER: &str = "unknown ISUPPORT parameter";
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("empty ISUPPORT token not allowed")]
+    EmptyToken,
+    #[error("{parameter}: value required")]
+    MissingValue { parameter: String },
+    #[error(
+        "{parameter}: value required to be a positive integer, got {value:?}"
+    )]
+    InvalidInteger { parameter: String, value: String },
+    #[error("unknown ISUPPORT parameter {0}")]
+    UnknownParameter(String),
+    #[error("{parameter}: {reason}")]
+    Malformed {
+        parameter: String,
+        reason: &'static str,
+    },
+    #[error("ISUPPORT token too long ({0} bytes)")]
+    TooLong(usize),
+}
+
+// Upper bound on a single ISUPPORT token (a server message is limited to 512
+// bytes, excluding tags).  The line has already been read by then, but an
+// oversized token is rejected before it is parsed into (and kept in) the
+// table.
+pub const MAX_TOKEN_LENGTH: usize = 512;
 
 impl FromStr for Operation {
-    type Err = &'static str;
+    type Err = ParseError;
 
     fn from_str(token: &str) -> Result<Self, Self::Err> {
         if token.is_empty() {
-            return Err("empty ISUPPORT token not allowed");
+            return Err(ParseError::EmptyToken);
+        }
+
+        if token.len() > MAX_TOKEN_LENGTH {
+            return Err(ParseError::TooLong(token.len()));
         }
 
         match token.chars().next() {
@@ -66,11 +128,27 @@ impl FromStr for Operation {
                 if let Some((parameter, value)) = token.split_once('=') {
                     match parameter {
                         "ACCEPT" => Ok(Operation::Add(Parameter::ACCEPT(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "ACCOUNTEXTBAN" => {
                             let account_based_extended_ban_masks = value
                                 .split(',')
+                                .filter(|name| {
+                                    let valid = !name.is_empty()
+                                        && name.len()
+                                            <= MAX_ACCOUNT_EXTBAN_NAME_LENGTH
+                                        && name
+                                            .chars()
+                                            .all(|c| c.is_ascii_alphabetic());
+
+                                    if !valid {
+                                        log::warn!(
+                                            "Dropping invalid ACCOUNTEXTBAN entry {name:?}"
+                                        );
+                                    }
+
+                                    valid
+                                })
                                 .map(String::from)
                                 .collect::<Vec<_>>();
 
@@ -79,20 +157,24 @@ impl FromStr for Operation {
                                     account_based_extended_ban_masks,
                                 )))
                             } else {
-                                Err("no valid account-based extended ban masks")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid account-based extended ban masks",
+                                })
                             }
                         }
                         "AWAYLEN" => Ok(Operation::Add(Parameter::AWAYLEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "BOT" => Ok(Operation::Add(Parameter::BOT(
-                            parse_required_letter(value, None)?,
+                            parse_required_letter(parameter, value, None)?,
                         ))),
                         "BOUNCER_NETID" => Ok(Operation::Add(
                             Parameter::BOUNCER_NETID(value.to_owned()),
                         )),
                         "CALLERID" => Ok(Operation::Add(Parameter::CALLERID(
                             parse_required_letter(
+                                parameter,
                                 value,
                                 Some(DEFAULT_CALLER_ID_LETTER),
                             )?,
@@ -110,43 +192,44 @@ impl FromStr for Operation {
                             "rfc7613" => Ok(Operation::Add(
                                 Parameter::CASEMAPPING(CaseMap::RFC7613),
                             )),
-                            _ => Err("unknown casemapping"),
+                            _ => Err(ParseError::Malformed {
+                                parameter: parameter.to_string(),
+                                reason: "unknown casemapping",
+                            }),
                         },
                         "CHANLIMIT" => {
                             let mut channel_limits = vec![];
-
                             value.split(',').for_each(|channel_limit| {
-                                if let Some((prefix, limit)) =
+                                if let Some((prefixes, limit)) =
                                     channel_limit.split_once(':')
+                                    && !prefixes.is_empty()
                                 {
-                                    if limit.is_empty() {
-                                        for c in prefix.chars() {
-                                            // TODO validate after STATUSMSG received
-                                            channel_limits.push(ChannelLimit {
-                                                prefix: c,
-                                                limit: None,
-                                            });
-                                        }
+                                    // TODO validate after STATUSMSG received
+                                    // Some networks use * to signify no limit
+                                    if limit.is_empty() || limit == "*" {
+                                        channel_limits.push(ChannelLimit {
+                                            prefixes: prefixes.to_string(),
+                                            limit: None,
+                                        });
                                     } else if let Ok(limit) =
                                         limit.parse::<u16>()
                                     {
-                                        for c in prefix.chars() {
-                                            // TODO validate after STATUSMSG received
-                                            channel_limits.push(ChannelLimit {
-                                                prefix: c,
-                                                limit: Some(limit),
-                                            });
-                                        }
+                                        channel_limits.push(ChannelLimit {
+                                            prefixes: prefixes.to_string(),
+                                            limit: Some(limit),
+                                        });
                                     }
                                 }
                             });
-
                             if !channel_limits.is_empty() {
                                 Ok(Operation::Add(Parameter::CHANLIMIT(
                                     channel_limits,
                                 )))
                             } else {
-                                Err("no valid channel limits")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid channel limits",
+                                })
                             }
                         }
                         "CHANMODES" => {
@@ -173,12 +256,17 @@ impl FromStr for Operation {
                                     channel_modes,
                                 )))
                             } else {
-                                Err("no valid channel modes")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid channel modes",
+                                })
                             }
                         }
                         "CHANNELLEN" => {
                             Ok(Operation::Add(Parameter::CHANNELLEN(
-                                parse_required_positive_integer(value)?,
+                                parse_required_positive_integer(
+                                    parameter, value,
+                                )?,
                             )))
                         }
                         "CHANTYPES" => {
@@ -194,7 +282,9 @@ impl FromStr for Operation {
                         }
                         "CHATHISTORY" | "draft/CHATHISTORY" => {
                             Ok(Operation::Add(Parameter::CHATHISTORY(
-                                parse_required_positive_integer(value)?,
+                                parse_required_positive_integer(
+                                    parameter, value,
+                                )?,
                             )))
                         }
                         "CLIENTTAGDENY" => {
@@ -229,7 +319,10 @@ impl FromStr for Operation {
                                     client_tag_denials,
                                 )))
                             } else {
-                                Err("no valid client tag denials")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid client tag denials",
+                                })
                             }
                         }
                         "CLIENTVER" => {
@@ -242,14 +335,16 @@ impl FromStr for Operation {
                                 ));
                             }
 
-                            Err(
-                                "value must be a <major>.<minor> version number",
-                            )
+                            Err(ParseError::Malformed {
+                                parameter: parameter.to_string(),
+                                reason: "value must be a <major>.<minor> version number",
+                            })
                         }
                         "CNOTICE" => Ok(Operation::Add(Parameter::CNOTICE)),
                         "CPRIVMSG" => Ok(Operation::Add(Parameter::CPRIVMSG)),
                         "DEAF" => Ok(Operation::Add(Parameter::DEAF(
                             parse_required_letter(
+                                parameter,
                                 value,
                                 Some(DEFAULT_DEAF_LETTER),
                             )?,
@@ -263,20 +358,24 @@ impl FromStr for Operation {
                                         value.to_string(),
                                     )))
                                 } else {
-                                    Err(
-                                        "value required to only contain valid search extensions",
-                                    )
+                                    Err(ParseError::Malformed {
+                                        parameter: parameter.to_string(),
+                                        reason: "value required to only contain valid search extensions",
+                                    })
                                 }
                             } else {
-                                Err("value required")
+                                Err(ParseError::MissingValue {
+                                    parameter: parameter.to_string(),
+                                })
                             }
                         }
                         "ESILENCE" => Ok(Operation::Add(Parameter::ESILENCE(
-                            parse_optional_letters(value)?,
+                            parse_optional_letters(parameter, value)?,
                         ))),
                         "ETRACE" => Ok(Operation::Add(Parameter::ETRACE)),
                         "EXCEPTS" => Ok(Operation::Add(Parameter::EXCEPTS(
                             parse_required_letter(
+                                parameter,
                                 value,
                                 Some(DEFAULT_BAN_EXCEPTION_CHANNEL_LETTER),
                             )?,
@@ -299,42 +398,54 @@ impl FromStr for Operation {
                                             types.to_string(),
                                         )))
                                     } else {
-                                        Err("invalid extended ban prefix(es)")
+                                        Err(ParseError::Malformed {
+                                            parameter: parameter.to_string(),
+                                            reason: "invalid extended ban prefix(es)",
+                                        })
                                     }
                                 } else {
-                                    Err("invalid extended ban type(s)")
+                                    Err(ParseError::Malformed {
+                                        parameter: parameter.to_string(),
+                                        reason: "invalid extended ban type(s)",
+                                    })
                                 }
                             } else {
-                                Err("no valid extended ban masks")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid extended ban masks",
+                                })
                             }
                         }
                         "FNC" => Ok(Operation::Add(Parameter::FNC)),
                         "HOSTLEN" => Ok(Operation::Add(Parameter::HOSTLEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "INVEX" => Ok(Operation::Add(Parameter::INVEX(
                             parse_required_letter(
+                                parameter,
                                 value,
                                 Some(DEFAULT_INVITE_EXCEPTION_LETTER),
                             )?,
                         ))),
                         "KEYLEN" => Ok(Operation::Add(Parameter::KEYLEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "KICKLEN" => Ok(Operation::Add(Parameter::KICKLEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "KNOCK" => Ok(Operation::Add(Parameter::KNOCK)),
                         "LINELEN" => Ok(Operation::Add(Parameter::LINELEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "MAP" => Ok(Operation::Add(Parameter::MAP)),
                         "MAXBANS" => Ok(Operation::Add(Parameter::MAXBANS(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "MAXCHANNELS" => {
                             Ok(Operation::Add(Parameter::MAXCHANNELS(
-                                parse_required_positive_integer(value)?,
+                                parse_required_positive_integer(
+                                    parameter, value,
+                                )?,
                             )))
                         }
                         "MAXLIST" => {
@@ -361,25 +472,30 @@ impl FromStr for Operation {
                                     modes_limits,
                                 )))
                             } else {
-                                Err("no valid modes limits")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid modes limits",
+                                })
                             }
                         }
                         "MAXPARA" => Ok(Operation::Add(Parameter::MAXPARA(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "MAXTARGETS" => {
                             Ok(Operation::Add(Parameter::MAXTARGETS(
-                                parse_optional_positive_integer(value)?,
+                                parse_optional_positive_integer(
+                                    parameter, value,
+                                )?,
                             )))
                         }
                         "METADATA" => Ok(Operation::Add(Parameter::METADATA(
-                            parse_optional_positive_integer(value)?,
+                            parse_optional_positive_integer(parameter, value)?,
                         ))),
                         "MODES" => Ok(Operation::Add(Parameter::MODES(
-                            parse_optional_positive_integer(value)?,
+                            parse_optional_positive_integer(parameter, value)?,
                         ))),
                         "MONITOR" => Ok(Operation::Add(Parameter::MONITOR(
-                            parse_optional_positive_integer(value)?,
+                            parse_optional_positive_integer(parameter, value)?,
                         ))),
                         "MSGREFTYPES" => {
                             let mut message_reference_types = vec![];
@@ -388,13 +504,11 @@ impl FromStr for Operation {
                                 |message_reference_type| {
                                     match message_reference_type {
                                         "msgid" => message_reference_types
-                                            .insert(
-                                                0,
+                                            .push(
                                                 MessageReferenceType::MessageId,
                                             ),
                                         "timestamp" => message_reference_types
-                                            .insert(
-                                                0,
+                                            .push(
                                                 MessageReferenceType::Timestamp,
                                             ),
                                         _ => (),
@@ -407,7 +521,7 @@ impl FromStr for Operation {
                             )))
                         }
                         "NAMELEN" => Ok(Operation::Add(Parameter::NAMELEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "NAMESX" => Ok(Operation::Add(Parameter::NAMESX)),
                         "NETWORK" => Ok(Operation::Add(Parameter::NETWORK(
@@ -415,7 +529,9 @@ impl FromStr for Operation {
                         ))),
                         "NICKLEN" | "MAXNICKLEN" => {
                             Ok(Operation::Add(Parameter::NICKLEN(
-                                parse_required_positive_integer(value)?,
+                                parse_required_positive_integer(
+                                    parameter, value,
+                                )?,
                             )))
                         }
                         "OVERRIDE" => Ok(Operation::Add(Parameter::OVERRIDE)),
@@ -436,7 +552,10 @@ impl FromStr for Operation {
                                     prefix_maps,
                                 )))
                             } else {
-                                Err("unrecognized PREFIX format")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "unrecognized PREFIX format",
+                                })
                             }
                         }
                         "SAFELIST" => Ok(Operation::Add(Parameter::SAFELIST)),
@@ -444,11 +563,11 @@ impl FromStr for Operation {
                             Ok(Operation::Add(Parameter::SECURELIST))
                         }
                         "SILENCE" => Ok(Operation::Add(Parameter::SILENCE(
-                            parse_optional_positive_integer(value)?,
+                            parse_optional_positive_integer(parameter, value)?,
                         ))),
                         "STATUSMSG" => {
                             let chars = value.chars().collect::<Vec<_>>();
-                            // TODO validate that STATUSMSG ⊂ PREFIX after ISUPPORT ends
+                            // Validated against PREFIX in finalize
                             Ok(Operation::Add(Parameter::STATUSMSG(chars)))
                         }
                         "TARGMAX" => {
@@ -491,50 +610,79 @@ impl FromStr for Operation {
                                     command_target_limits,
                                 )))
                             } else {
-                                Err("no valid command target limits")
+                                Err(ParseError::Malformed {
+                                    parameter: parameter.to_string(),
+                                    reason: "no valid command target limits",
+                                })
                             }
                         }
                         "TOPICLEN" => Ok(Operation::Add(Parameter::TOPICLEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "UHNAMES" => Ok(Operation::Add(Parameter::UHNAMES)),
                         "USERIP" => Ok(Operation::Add(Parameter::USERIP)),
                         "USERLEN" => Ok(Operation::Add(Parameter::USERLEN(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "UTF8ONLY" => Ok(Operation::Add(Parameter::UTF8ONLY)),
                         "VLIST" => Ok(Operation::Add(Parameter::VLIST(
-                            parse_required_letters(value)?,
+                            parse_required_letters(parameter, value)?,
                         ))),
                         "WATCH" => Ok(Operation::Add(Parameter::WATCH(
-                            parse_required_positive_integer(value)?,
+                            parse_required_positive_integer(parameter, value)?,
                         ))),
                         "WHOX" => Ok(Operation::Add(Parameter::WHOX)),
-                        _ => Err("unknown ISUPPORT parameter"),
+                        _ => Err(ParseError::UnknownParameter(
+                            parameter.to_string(),
+                        )),
                     }
                 } else {
                     match token {
-                        "ACCEPT" => Err("value required"),
-                        "ACCOUNTEXTBAN" => Err("value(s) required"),
-                        "AWAYLEN" => Err("value required"),
-                        "BOT" => Err("value required"),
+                        "ACCEPT" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "ACCOUNTEXTBAN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "AWAYLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "BOT" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "CALLERID" => Ok(Operation::Add(Parameter::CALLERID(
                             DEFAULT_CALLER_ID_LETTER,
                         ))),
-                        "CASEMAPPING" => Err("value required"),
-                        "CHANLIMIT" => Err("value(s) required"),
-                        "CHANMODES" => Err("value(s) required"),
-                        "CHANNELLEN" => Err("value required"),
+                        "CASEMAPPING" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "CHANLIMIT" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "CHANMODES" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "CHANNELLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "CHANTYPES" => {
                             Ok(Operation::Add(Parameter::CHANTYPES(None)))
                         }
-                        "CHATHISTORY" => Err("value required"),
-                        "CLIENTTAGDENY" => Err("value(s) required"),
-                        "CLIENTVER" => Err("value required"),
+                        "CHATHISTORY" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "CLIENTTAGDENY" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "CLIENTVER" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "DEAF" => Ok(Operation::Add(Parameter::DEAF(
                             DEFAULT_DEAF_LETTER,
                         ))),
-                        "ELIST" => Err("value required"),
+                        "ELIST" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "ESILENCE" => {
                             Ok(Operation::Add(Parameter::ESILENCE(None)))
                         }
@@ -542,21 +690,39 @@ impl FromStr for Operation {
                         "EXCEPTS" => Ok(Operation::Add(Parameter::EXCEPTS(
                             DEFAULT_BAN_EXCEPTION_CHANNEL_LETTER,
                         ))),
-                        "EXTBAN" => Err("value required"),
+                        "EXTBAN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "FNC" => Ok(Operation::Add(Parameter::FNC)),
-                        "HOSTLEN" => Err("value required"),
+                        "HOSTLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "INVEX" => Ok(Operation::Add(Parameter::INVEX(
                             DEFAULT_INVITE_EXCEPTION_LETTER,
                         ))),
-                        "KEYLEN" => Err("value required"),
-                        "KICKLEN" => Err("value required"),
+                        "KEYLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "KICKLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "KNOCK" => Ok(Operation::Add(Parameter::KNOCK)),
-                        "LINELEN" => Err("value required"),
+                        "LINELEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "MAP" => Ok(Operation::Add(Parameter::MAP)),
-                        "MAXBANS" => Err("value required"),
-                        "MAXCHANNELS" => Err("value required"),
-                        "MAXLIST" => Err("value(s) required"),
-                        "MAXPARA" => Err("value required"),
+                        "MAXBANS" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "MAXCHANNELS" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "MAXLIST" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "MAXPARA" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "MAXTARGETS" => {
                             Ok(Operation::Add(Parameter::MAXTARGETS(None)))
                         }
@@ -571,9 +737,17 @@ impl FromStr for Operation {
                             Ok(Operation::Add(Parameter::MSGREFTYPES(vec![])))
                         }
                         "NAMESX" => Ok(Operation::Add(Parameter::NAMESX)),
-                        "NAMELEN" => Err("value required"),
-                        "NETWORK" => Err("value required"),
-                        "NICKLEN" | "MAXNICKLEN" => Err("value required"),
+                        "NAMELEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "NETWORK" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "NICKLEN" | "MAXNICKLEN" => {
+                            Err(ParseError::MissingValue {
+                                parameter: token.to_string(),
+                            })
+                        }
                         "OVERRIDE" => Ok(Operation::Add(Parameter::OVERRIDE)),
                         "PREFIX" => {
                             Ok(Operation::Add(Parameter::PREFIX(vec![])))
@@ -585,19 +759,31 @@ impl FromStr for Operation {
                         "SILENCE" => {
                             Ok(Operation::Add(Parameter::SILENCE(None)))
                         }
-                        "STATUSMSG" => Err("value required"),
+                        "STATUSMSG" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "TARGMAX" => {
                             Ok(Operation::Add(Parameter::TARGMAX(vec![])))
                         }
-                        "TOPICLEN" => Err("value required"),
+                        "TOPICLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "UHNAMES" => Ok(Operation::Add(Parameter::UHNAMES)),
                         "USERIP" => Ok(Operation::Add(Parameter::USERIP)),
-                        "USERLEN" => Err("value required"),
+                        "USERLEN" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "UTF8ONLY" => Ok(Operation::Add(Parameter::UTF8ONLY)),
-                        "VLIST" => Err("value required"),
-                        "WATCH" => Err("value required"),
+                        "VLIST" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
+                        "WATCH" => Err(ParseError::MissingValue {
+                            parameter: token.to_string(),
+                        }),
                         "WHOX" => Ok(Operation::Add(Parameter::WHOX)),
-                        _ => Err(UNKNOWN_ISUPPORT_PARAMETER),
+                        _ => {
+                            Err(ParseError::UnknownParameter(token.to_string()))
+                        }
                     }
                 }
             }
@@ -610,23 +796,32 @@ impl Operation {
         match self {
             Operation::Add(parameter) => parameter.kind(),
             Operation::Remove(parameter) => match parameter.as_ref() {
+                "ACCOUNTEXTBAN" => Some(Kind::ACCOUNTEXTBAN),
                 "AWAYLEN" => Some(Kind::AWAYLEN),
                 "CASEMAPPING" => Some(Kind::CASEMAPPING),
                 "CHANLIMIT" => Some(Kind::CHANLIMIT),
                 "CHANMODES" => Some(Kind::CHANMODES),
                 "CHANNELLEN" => Some(Kind::CHANNELLEN),
                 "CHANTYPES" => Some(Kind::CHANTYPES),
-                "CHATHISTORY" => Some(Kind::CHATHISTORY),
+                "CHATHISTORY" | "draft/CHATHISTORY" => Some(Kind::CHATHISTORY),
+                "CLIENTTAGDENY" => Some(Kind::CLIENTTAGDENY),
+                "CLIENTVER" => Some(Kind::CLIENTVER),
                 "CNOTICE" => Some(Kind::CNOTICE),
                 "CPRIVMSG" => Some(Kind::CPRIVMSG),
                 "ELIST" => Some(Kind::ELIST),
+                "EXTBAN" => Some(Kind::EXTBAN),
+                "HOSTLEN" => Some(Kind::HOSTLEN),
                 "KEYLEN" => Some(Kind::KEYLEN),
                 "KICKLEN" => Some(Kind::KICKLEN),
                 "KNOCK" => Some(Kind::KNOCK),
+                "LINELEN" => Some(Kind::LINELEN),
+                "MAXCHANNELS" => Some(Kind::MAXCHANNELS),
+                "MAXLIST" => Some(Kind::MAXLIST),
                 "MODES" => Some(Kind::MODES),
                 "MONITOR" => Some(Kind::MONITOR),
                 "MSGREFTYPES" => Some(Kind::MSGREFTYPES),
                 "NAMELEN" => Some(Kind::NAMELEN),
+                "NETWORK" => Some(Kind::NETWORK),
                 "NICKLEN" => Some(Kind::NICKLEN),
                 "PREFIX" => Some(Kind::PREFIX),
                 "SAFELIST" => Some(Kind::SAFELIST),
@@ -634,7 +829,9 @@ impl Operation {
                 "TARGMAX" => Some(Kind::TARGMAX),
                 "TOPICLEN" => Some(Kind::TOPICLEN),
                 "USERIP" => Some(Kind::USERIP),
+                "USERLEN" => Some(Kind::USERLEN),
                 "UTF8ONLY" => Some(Kind::UTF8ONLY),
+                "WATCH" => Some(Kind::WATCH),
                 "WHOX" => Some(Kind::WHOX),
                 _ => None,
             },
@@ -642,6 +839,17 @@ impl Operation {
     }
 }
 
+// Re-serializes the operation as an RPL_ISUPPORT token, which parses back into
+// an equivalent operation
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Add(parameter) => write!(f, "{parameter}"),
+            Operation::Remove(parameter) => write!(f, "-{parameter}"),
+        }
+    }
+}
+
 // ISUPPORT Parameter References
 // - https://defs.ircdocs.horse/defs/isupport.html
 // - https://modern.ircdocs.horse/#rplisupport-005
@@ -716,6 +924,7 @@ pub enum Parameter {
 impl Parameter {
     pub fn kind(&self) -> Option<Kind> {
         match self {
+            Parameter::ACCOUNTEXTBAN(_) => Some(Kind::ACCOUNTEXTBAN),
             Parameter::AWAYLEN(_) => Some(Kind::AWAYLEN),
             Parameter::CASEMAPPING(_) => Some(Kind::CASEMAPPING),
             Parameter::CHANLIMIT(_) => Some(Kind::CHANLIMIT),
@@ -723,16 +932,24 @@ impl Parameter {
             Parameter::CHANNELLEN(_) => Some(Kind::CHANNELLEN),
             Parameter::CHANTYPES(_) => Some(Kind::CHANTYPES),
             Parameter::CHATHISTORY(_) => Some(Kind::CHATHISTORY),
+            Parameter::CLIENTTAGDENY(_) => Some(Kind::CLIENTTAGDENY),
+            Parameter::CLIENTVER(_, _) => Some(Kind::CLIENTVER),
             Parameter::CNOTICE => Some(Kind::CNOTICE),
             Parameter::CPRIVMSG => Some(Kind::CPRIVMSG),
             Parameter::ELIST(_) => Some(Kind::ELIST),
+            Parameter::EXTBAN(_, _) => Some(Kind::EXTBAN),
+            Parameter::HOSTLEN(_) => Some(Kind::HOSTLEN),
             Parameter::KEYLEN(_) => Some(Kind::KEYLEN),
             Parameter::KICKLEN(_) => Some(Kind::KICKLEN),
             Parameter::KNOCK => Some(Kind::KNOCK),
+            Parameter::LINELEN(_) => Some(Kind::LINELEN),
+            Parameter::MAXCHANNELS(_) => Some(Kind::MAXCHANNELS),
+            Parameter::MAXLIST(_) => Some(Kind::MAXLIST),
             Parameter::MODES(_) => Some(Kind::MODES),
             Parameter::MONITOR(_) => Some(Kind::MONITOR),
             Parameter::MSGREFTYPES(_) => Some(Kind::MSGREFTYPES),
             Parameter::NAMELEN(_) => Some(Kind::NAMELEN),
+            Parameter::NETWORK(_) => Some(Kind::NETWORK),
             Parameter::NICKLEN(_) => Some(Kind::NICKLEN),
             Parameter::PREFIX(_) => Some(Kind::PREFIX),
             Parameter::SAFELIST => Some(Kind::SAFELIST),
@@ -740,7 +957,9 @@ impl Parameter {
             Parameter::TARGMAX(_) => Some(Kind::TARGMAX),
             Parameter::TOPICLEN(_) => Some(Kind::TOPICLEN),
             Parameter::USERIP => Some(Kind::USERIP),
+            Parameter::USERLEN(_) => Some(Kind::USERLEN),
             Parameter::UTF8ONLY => Some(Kind::UTF8ONLY),
+            Parameter::WATCH(_) => Some(Kind::WATCH),
             Parameter::WHOX => Some(Kind::WHOX),
             Parameter::BOUNCER_NETID(_) => Some(Kind::BOUNCER_NETID),
             _ => None,
@@ -748,8 +967,212 @@ impl Parameter {
     }
 }
 
+impl fmt::Display for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Parameter::ACCEPT(limit) => write!(f, "ACCEPT={limit}"),
+            Parameter::ACCOUNTEXTBAN(masks) => {
+                write!(f, "ACCOUNTEXTBAN={}", masks.join(","))
+            }
+            Parameter::AWAYLEN(len) => write!(f, "AWAYLEN={len}"),
+            Parameter::BOT(letter) => write!(f, "BOT={letter}"),
+            Parameter::BOUNCER_NETID(netid) => {
+                write!(f, "BOUNCER_NETID={netid}")
+            }
+            Parameter::CALLERID(letter) => write!(f, "CALLERID={letter}"),
+            Parameter::CASEMAPPING(casemapping) => {
+                let casemapping = match casemapping {
+                    CaseMap::ASCII => "ascii",
+                    CaseMap::RFC1459 => "rfc1459",
+                    CaseMap::RFC1459_STRICT => "rfc1459-strict",
+                    CaseMap::RFC7613 => "rfc7613",
+                };
+
+                write!(f, "CASEMAPPING={casemapping}")
+            }
+            Parameter::CHANLIMIT(channel_limits) => write!(
+                f,
+                "CHANLIMIT={}",
+                channel_limits
+                    .iter()
+                    .map(|channel_limit| format!(
+                        "{}:{}",
+                        channel_limit.prefixes,
+                        channel_limit
+                            .limit
+                            .as_ref()
+                            .map(ToString::to_string)
+                            .unwrap_or_default()
+                    ))
+                    .join(",")
+            ),
+            Parameter::CHANMODES(mode_kinds) => write!(
+                f,
+                "CHANMODES={}",
+                mode_kinds
+                    .iter()
+                    .map(|mode_kind| &mode_kind.modes)
+                    .join(",")
+            ),
+            Parameter::CHANNELLEN(len) => write!(f, "CHANNELLEN={len}"),
+            Parameter::CHANTYPES(Some(chantypes)) => {
+                write!(f, "CHANTYPES={}", chantypes.iter().collect::<String>())
+            }
+            Parameter::CHANTYPES(None) => write!(f, "CHANTYPES"),
+            Parameter::CHATHISTORY(limit) => write!(f, "CHATHISTORY={limit}"),
+            Parameter::CLIENTTAGDENY(client_only_tags) => write!(
+                f,
+                "CLIENTTAGDENY={}",
+                client_only_tags
+                    .iter()
+                    .map(|client_only_tag| match client_only_tag {
+                        ClientOnlyTags::Allowed(tag) => format!("-{tag}"),
+                        ClientOnlyTags::Denied(tag) => tag.clone(),
+                        ClientOnlyTags::DenyAll => "*".to_string(),
+                    })
+                    .join(",")
+            ),
+            Parameter::CLIENTVER(major, minor) => {
+                write!(f, "CLIENTVER={major}.{minor}")
+            }
+            Parameter::CNOTICE => write!(f, "CNOTICE"),
+            Parameter::CPRIVMSG => write!(f, "CPRIVMSG"),
+            Parameter::DEAF(letter) => write!(f, "DEAF={letter}"),
+            Parameter::ELIST(extensions) => write!(f, "ELIST={extensions}"),
+            Parameter::ESILENCE(Some(flags)) => write!(f, "ESILENCE={flags}"),
+            Parameter::ESILENCE(None) => write!(f, "ESILENCE"),
+            Parameter::ETRACE => write!(f, "ETRACE"),
+            Parameter::EXCEPTS(letter) => write!(f, "EXCEPTS={letter}"),
+            Parameter::EXTBAN(prefix, types) => write!(
+                f,
+                "EXTBAN={},{types}",
+                prefix.map(String::from).unwrap_or_default()
+            ),
+            Parameter::FNC => write!(f, "FNC"),
+            Parameter::HOSTLEN(len) => write!(f, "HOSTLEN={len}"),
+            Parameter::INVEX(letter) => write!(f, "INVEX={letter}"),
+            Parameter::KEYLEN(len) => write!(f, "KEYLEN={len}"),
+            Parameter::KICKLEN(len) => write!(f, "KICKLEN={len}"),
+            Parameter::KNOCK => write!(f, "KNOCK"),
+            Parameter::LINELEN(len) => write!(f, "LINELEN={len}"),
+            Parameter::MAP => write!(f, "MAP"),
+            Parameter::MAXBANS(limit) => write!(f, "MAXBANS={limit}"),
+            Parameter::MAXCHANNELS(limit) => write!(f, "MAXCHANNELS={limit}"),
+            Parameter::MAXLIST(modes_limits) => write!(
+                f,
+                "MAXLIST={}",
+                modes_limits
+                    .iter()
+                    .map(|modes_limit| format!(
+                        "{}:{}",
+                        modes_limit.modes, modes_limit.limit
+                    ))
+                    .join(",")
+            ),
+            Parameter::MAXPARA(limit) => write!(f, "MAXPARA={limit}"),
+            Parameter::MAXTARGETS(limit) => {
+                write_optional(f, "MAXTARGETS", *limit)
+            }
+            Parameter::METADATA(limit) => write_optional(f, "METADATA", *limit),
+            Parameter::MODES(limit) => write_optional(f, "MODES", *limit),
+            Parameter::MONITOR(limit) => write_optional(f, "MONITOR", *limit),
+            Parameter::MSGREFTYPES(message_reference_types) => {
+                if message_reference_types.is_empty() {
+                    write!(f, "MSGREFTYPES")
+                } else {
+                    write!(
+                        f,
+                        "MSGREFTYPES={}",
+                        message_reference_types
+                            .iter()
+                            .map(|message_reference_type| {
+                                match message_reference_type {
+                                    MessageReferenceType::Timestamp => {
+                                        "timestamp"
+                                    }
+                                    MessageReferenceType::MessageId => "msgid",
+                                }
+                            })
+                            .join(",")
+                    )
+                }
+            }
+            Parameter::NAMELEN(len) => write!(f, "NAMELEN={len}"),
+            Parameter::NAMESX => write!(f, "NAMESX"),
+            Parameter::NETWORK(network) => write!(f, "NETWORK={network}"),
+            Parameter::NICKLEN(len) => write!(f, "NICKLEN={len}"),
+            Parameter::OVERRIDE => write!(f, "OVERRIDE"),
+            Parameter::PREFIX(prefix_maps) => {
+                if prefix_maps.is_empty() {
+                    write!(f, "PREFIX")
+                } else {
+                    write!(
+                        f,
+                        "PREFIX=({}){}",
+                        prefix_maps
+                            .iter()
+                            .map(|prefix_map| prefix_map.mode)
+                            .collect::<String>(),
+                        prefix_maps
+                            .iter()
+                            .map(|prefix_map| prefix_map.prefix)
+                            .collect::<String>()
+                    )
+                }
+            }
+            Parameter::SAFELIST => write!(f, "SAFELIST"),
+            Parameter::SECURELIST => write!(f, "SECURELIST"),
+            Parameter::SILENCE(limit) => write_optional(f, "SILENCE", *limit),
+            Parameter::STATUSMSG(prefixes) => {
+                write!(f, "STATUSMSG={}", prefixes.iter().collect::<String>())
+            }
+            Parameter::TARGMAX(command_target_limits) => {
+                if command_target_limits.is_empty() {
+                    write!(f, "TARGMAX")
+                } else {
+                    write!(
+                        f,
+                        "TARGMAX={}",
+                        command_target_limits
+                            .iter()
+                            .map(|command_target_limit| format!(
+                                "{}:{}",
+                                command_target_limit.command,
+                                command_target_limit
+                                    .limit
+                                    .as_ref()
+                                    .map(ToString::to_string)
+                                    .unwrap_or_default()
+                            ))
+                            .join(",")
+                    )
+                }
+            }
+            Parameter::TOPICLEN(len) => write!(f, "TOPICLEN={len}"),
+            Parameter::UHNAMES => write!(f, "UHNAMES"),
+            Parameter::USERIP => write!(f, "USERIP"),
+            Parameter::USERLEN(len) => write!(f, "USERLEN={len}"),
+            Parameter::UTF8ONLY => write!(f, "UTF8ONLY"),
+            Parameter::VLIST(letters) => write!(f, "VLIST={letters}"),
+            Parameter::WATCH(limit) => write!(f, "WATCH={limit}"),
+            Parameter::WHOX => write!(f, "WHOX"),
+        }
+    }
+}
+
+fn write_optional(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    value: Option<u16>,
+) -> fmt::Result {
+    match value {
+        Some(value) => write!(f, "{name}={value}"),
+        None => write!(f, "{name}"),
+    }
+}
+
 #[allow(non_camel_case_types)]
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum CaseMap {
     ASCII,
     RFC1459,
@@ -836,11 +1259,90 @@ impl CaseMap {
             CaseMap::RFC7613 => from_str.to_lowercase(),
         }
     }
+
+    // Prefix match under the casemapping (e.g. for completion), normalizing
+    // only as much of the haystack as the prefix needs
+    pub fn starts_with(&self, haystack: &str, prefix: &str) -> bool {
+        let mut haystack = self.normalized_chars(haystack);
+
+        self.normalized_chars(prefix)
+            .all(|c| haystack.next() == Some(c))
+    }
+
+    pub fn equals(&self, a: &str, b: &str) -> bool {
+        self.normalized_chars(a).eq(self.normalized_chars(b))
+    }
+
+    fn normalized_chars<'a>(
+        &self,
+        from_str: &'a str,
+    ) -> impl Iterator<Item = char> + 'a {
+        let casemap = *self;
+
+        from_str.chars().flat_map(move |c| match casemap {
+            CaseMap::ASCII => Either::Left(iter::once(c.to_ascii_lowercase())),
+            CaseMap::RFC1459 | CaseMap::RFC1459_STRICT => {
+                Either::Left(iter::once(match c {
+                    '[' => '{',
+                    ']' => '}',
+                    '\\' => '|',
+                    '~' if matches!(casemap, CaseMap::RFC1459) => '^',
+                    _ => c.to_ascii_lowercase(),
+                }))
+            }
+            CaseMap::RFC7613 => Either::Right(c.to_lowercase()),
+        })
+    }
+}
+
+// A nick compared often (e.g. our own nick when matching highlights), with its
+// normalized form only recomputed when the nick or casemapping changes
+#[derive(Clone, Debug)]
+pub struct NormalizedNick {
+    raw: String,
+    normalized: String,
+    casemap: CaseMap,
+}
+
+impl NormalizedNick {
+    pub fn new(raw: &str, casemap: CaseMap) -> Self {
+        NormalizedNick {
+            raw: raw.to_string(),
+            normalized: casemap.normalize(raw),
+            casemap,
+        }
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn as_normalized_str(&self) -> &str {
+        &self.normalized
+    }
+
+    // Returns true if the normalized form had to be recomputed
+    pub fn update(&mut self, raw: &str, casemap: CaseMap) -> bool {
+        if self.raw == raw && self.casemap == casemap {
+            return false;
+        }
+
+        *self = NormalizedNick::new(raw, casemap);
+
+        true
+    }
+
+    pub fn matches(&self, other: &str) -> bool {
+        self.casemap
+            .normalized_chars(other)
+            .eq(self.normalized.chars())
+    }
 }
 
+// A limit applies to the combined number of channels across all its prefixes
 #[derive(Clone, Debug)]
 pub struct ChannelLimit {
-    pub prefix: char,
+    pub prefixes: String,
     pub limit: Option<u16>,
 }
 
@@ -869,21 +1371,50 @@ impl fmt::Display for ModeKind {
 pub enum ChatHistorySubcommand {
     Latest(Target, MessageReference, u16),
     Before(Target, MessageReference, u16),
+    After(Target, MessageReference, u16),
     Between(Target, MessageReference, MessageReference, u16),
     Targets(MessageReference, MessageReference, u16),
 }
 
 impl ChatHistorySubcommand {
+    // The target the subcommand requests history for, or None for TARGETS
+    // (which spans all targets, see is_targets)
     pub fn target(&self) -> Option<&str> {
         match self {
             ChatHistorySubcommand::Latest(target, _, _)
             | ChatHistorySubcommand::Before(target, _, _)
+            | ChatHistorySubcommand::After(target, _, _)
             | ChatHistorySubcommand::Between(target, _, _, _) => {
                 Some(target.as_str())
             }
             ChatHistorySubcommand::Targets(_, _, _) => None,
         }
     }
+
+    pub fn is_targets(&self) -> bool {
+        matches!(self, ChatHistorySubcommand::Targets(_, _, _))
+    }
+
+    // Forward paging: continues after the newest loaded message, or requests
+    // the latest messages when none are loaded (or none can be referenced)
+    pub fn after_newest(
+        target: Target,
+        newest: Option<&Message>,
+        isupport: &HashMap<Kind, Parameter>,
+    ) -> Self {
+        let limit = get_chathistory_limit_or_default(isupport);
+
+        let message_reference = newest
+            .map_or(MessageReference::None, |newest| {
+                MessageReference::from_message(newest, isupport)
+            });
+
+        if matches!(message_reference, MessageReference::None) {
+            ChatHistorySubcommand::Latest(target, message_reference, limit)
+        } else {
+            ChatHistorySubcommand::After(target, message_reference, limit)
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -893,6 +1424,51 @@ pub enum ChatHistoryState {
     Ready,
 }
 
+// Each target pages through its history independently, keyed by the
+// casemapping-normalized target
+#[derive(Clone, Debug, Default)]
+pub struct ChatHistoryStates(HashMap<Target, ChatHistoryState>);
+
+impl ChatHistoryStates {
+    pub fn state(&self, target: &Target) -> ChatHistoryState {
+        self.0
+            .get(target)
+            .copied()
+            .unwrap_or(ChatHistoryState::Ready)
+    }
+
+    // Returns false if a request is already pending or history is exhausted
+    pub fn request(&mut self, target: &Target) -> bool {
+        if self.state(target) == ChatHistoryState::Ready {
+            self.0
+                .insert(target.clone(), ChatHistoryState::PendingRequest);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn receive(&mut self, target: &Target, exhausted: bool) {
+        let state = if exhausted {
+            ChatHistoryState::Exhausted
+        } else {
+            ChatHistoryState::Ready
+        };
+
+        self.0.insert(target.clone(), state);
+    }
+
+    pub fn reset(&mut self, target: &Target) {
+        self.0.remove(target);
+    }
+
+    // Re-keys the states when CASEMAPPING changes
+    pub fn renormalize(&mut self, casemapping: CaseMap) {
+        crate::target::renormalize_keys(&mut self.0, casemapping);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ClientOnlyTags {
     Allowed(String),
@@ -906,6 +1482,15 @@ pub struct CommandTargetLimit {
     pub limit: Option<u16>,
 }
 
+impl CommandTargetLimit {
+    // A limit of 0 is treated as unlimited (rather than as disabling the
+    // command), following the common convention among servers; the parsed
+    // value is kept as-is so the parameter is re-emitted unchanged
+    pub fn effective_limit(&self) -> Option<u16> {
+        self.limit.filter(|limit| *limit > 0)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum MessageReference {
     Timestamp(DateTime<Utc>),
@@ -913,6 +1498,54 @@ pub enum MessageReference {
     None,
 }
 
+impl MessageReference {
+    // Reference to the message using the type the server prefers (in
+    // MSGREFTYPES order), or None if the message cannot be referenced
+    pub fn from_message(
+        message: &Message,
+        isupport: &HashMap<Kind, Parameter>,
+    ) -> Self {
+        let message_reference = message
+            .references()
+            .message_reference(get_msgreftypes(isupport));
+
+        if chathistory_supports_reference(isupport, &message_reference) {
+            message_reference
+        } else {
+            MessageReference::None
+        }
+    }
+}
+
+fn get_msgreftypes(
+    isupport: &HashMap<Kind, Parameter>,
+) -> &[MessageReferenceType] {
+    match isupport.get(&Kind::MSGREFTYPES) {
+        Some(Parameter::MSGREFTYPES(types)) => types.as_slice(),
+        _ => &[],
+    }
+}
+
+// Whether CHATHISTORY accepts the reference's type, i.e. it is listed in
+// MSGREFTYPES; without MSGREFTYPES only * (MessageReference::None) is assumed
+// to be accepted
+pub fn chathistory_supports_reference(
+    isupport: &HashMap<Kind, Parameter>,
+    reference: &MessageReference,
+) -> bool {
+    let message_reference_types = get_msgreftypes(isupport);
+
+    match reference {
+        MessageReference::Timestamp(_) => message_reference_types
+            .iter()
+            .any(|kind| matches!(kind, MessageReferenceType::Timestamp)),
+        MessageReference::MessageId(_) => message_reference_types
+            .iter()
+            .any(|kind| matches!(kind, MessageReferenceType::MessageId)),
+        MessageReference::None => true,
+    }
+}
+
 impl fmt::Display for MessageReference {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -959,6 +1592,14 @@ pub struct PrefixMap {
     pub mode: char,
 }
 
+pub const CLIENT_CHATHISTORY_LIMIT: u16 = 500;
+
+// Extended ban names are short tokens (a letter, or a word like "account")
+const MAX_ACCOUNT_EXTBAN_NAME_LENGTH: usize = 16;
+
+// Common server defaults for lengths the specs give no default for
+const DEFAULT_AWAYLEN: u16 = 390;
+
 const DEFAULT_BAN_EXCEPTION_CHANNEL_LETTER: char = 'e';
 
 const DEFAULT_CALLER_ID_LETTER: char = 'g';
@@ -985,10 +1626,28 @@ pub const DEFAULT_CHANMODES: &[ModeKind] = &[
 
 pub const DEFAULT_CHANTYPES: &[char] = proto::DEFAULT_CHANNEL_PREFIXES;
 
+// Reference: https://modern.ircdocs.horse/#channellen-parameter
+const DEFAULT_CHANNELLEN: u16 = 200;
+
 const DEFAULT_DEAF_LETTER: char = 'D';
 
+const DEFAULT_HOSTLEN: u16 = 63;
+
 const DEFAULT_INVITE_EXCEPTION_LETTER: char = 'I';
 
+const DEFAULT_KEYLEN: u16 = 23;
+
+const DEFAULT_KICKLEN: u16 = 390;
+
+// Reference: https://modern.ircdocs.horse/#linelen-parameter
+const DEFAULT_LINELEN: u16 = 512;
+
+const DEFAULT_NICKLEN: u16 = 9;
+
+// Worst case for the `:nick!user@host ` prefix the server prepends when
+// relaying a message, which is not known to (or controlled by) the client
+pub const HOSTMASK_SAFETY_MARGIN: usize = 1 + 64 + 1 + 16 + 1 + 64 + 1;
+
 // Reference: https://modern.ircdocs.horse/#channel-membership-prefixes
 const DEFAULT_PREFIX: &[PrefixMap] = &[
     PrefixMap {
@@ -1013,14 +1672,126 @@ const DEFAULT_PREFIX: &[PrefixMap] = &[
     },
 ];
 
+const DEFAULT_TOPICLEN: u16 = 390;
+
+const DEFAULT_USERLEN: u16 = 10;
+
 const FUZZ_SECONDS: chrono::Duration = chrono::Duration::seconds(5);
 
+const MIN_FUZZ_SECONDS: chrono::Duration = chrono::Duration::seconds(1);
+
+const MAX_FUZZ_SECONDS: chrono::Duration = chrono::Duration::seconds(20);
+
+// Fuzz applied to timestamp references, adapted per target to the density of
+// the previously received page (a full page shrinks the window, a short page
+// grows it)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FuzzWindow(chrono::Duration);
+
+impl Default for FuzzWindow {
+    fn default() -> Self {
+        FuzzWindow(FUZZ_SECONDS)
+    }
+}
+
+impl FuzzWindow {
+    pub fn duration(&self) -> chrono::Duration {
+        self.0
+    }
+
+    pub fn update(&mut self, received: usize, limit: u16) {
+        if received >= limit as usize {
+            self.0 = std::cmp::max(self.0 / 2, MIN_FUZZ_SECONDS);
+        } else {
+            self.widen();
+        }
+    }
+
+    pub fn widen(&mut self) {
+        self.0 = std::cmp::min(self.0 * 2, MAX_FUZZ_SECONDS);
+    }
+}
+
+// Tracks the reference of the last BEFORE request for a target.  Requesting
+// from the same reference again means the previous page did not move the
+// oldest message (e.g. the server counted the reference message against the
+// limit), so paging would stall without a nudge.
+#[derive(Clone, Debug, Default)]
+pub struct PageStall {
+    reference: Option<MessageReference>,
+    count: u16,
+}
+
+impl PageStall {
+    // Raises the limit of a stalled BEFORE request (up to max_limit) and, for a
+    // timestamp reference, widens the fuzz window.  Returns true if the request
+    // was nudged, i.e. false once neither can change any further.
+    pub fn nudge(
+        &mut self,
+        subcommand: &mut ChatHistorySubcommand,
+        fuzz_window: &mut FuzzWindow,
+        max_limit: u16,
+    ) -> bool {
+        let ChatHistorySubcommand::Before(_, message_reference, limit) =
+            subcommand
+        else {
+            return false;
+        };
+
+        if self.reference.as_ref() != Some(message_reference) {
+            self.reference = Some(message_reference.clone());
+            self.count = 0;
+
+            return false;
+        }
+
+        self.count = self.count.saturating_add(1);
+
+        let nudged_limit = std::cmp::max(
+            *limit,
+            std::cmp::min(limit.saturating_add(self.count), max_limit),
+        );
+
+        // Message ids are exact, so only timestamp references are fuzzed
+        let widens =
+            matches!(message_reference, MessageReference::Timestamp(_))
+                && fuzz_window.duration() < MAX_FUZZ_SECONDS;
+
+        if nudged_limit == *limit && !widens {
+            return false;
+        }
+
+        *limit = nudged_limit;
+
+        if widens {
+            fuzz_window.widen();
+        }
+
+        true
+    }
+}
+
+// Whether CHATHISTORY accepts msgid references, in which case message
+// references are exact (see MessageReference::from_message)
+pub fn supports_msgid_references(isupport: &HashMap<Kind, Parameter>) -> bool {
+    get_msgreftypes(isupport)
+        .iter()
+        .any(|kind| matches!(kind, MessageReferenceType::MessageId))
+}
+
+// Only timestamp references from servers without msgid support are fuzzed.
+// Servers supporting msgid references get exact references, which are passed
+// through as-is to avoid overlapping pages.
 pub fn fuzz_start_message_reference(
     message_reference: MessageReference,
+    fuzz_window: FuzzWindow,
+    supports_msgid: bool,
 ) -> MessageReference {
     match message_reference {
-        MessageReference::Timestamp(start_server_time) => {
-            MessageReference::Timestamp(start_server_time - FUZZ_SECONDS)
+        MessageReference::Timestamp(start_server_time) if !supports_msgid => {
+            MessageReference::Timestamp(
+                start_server_time - fuzz_window.duration(),
+            )
         }
         _ => message_reference,
     }
@@ -1028,10 +1799,14 @@ pub fn fuzz_start_message_reference(
 
 pub fn fuzz_end_message_reference(
     message_reference: MessageReference,
+    fuzz_window: FuzzWindow,
+    supports_msgid: bool,
 ) -> MessageReference {
     match message_reference {
-        MessageReference::Timestamp(end_server_time) => {
-            MessageReference::Timestamp(end_server_time + FUZZ_SECONDS)
+        MessageReference::Timestamp(end_server_time) if !supports_msgid => {
+            MessageReference::Timestamp(
+                end_server_time + fuzz_window.duration(),
+            )
         }
         _ => message_reference,
     }
@@ -1040,6 +1815,8 @@ pub fn fuzz_end_message_reference(
 pub fn fuzz_message_reference_range(
     first_message_reference: MessageReference,
     second_message_reference: MessageReference,
+    fuzz_window: FuzzWindow,
+    supports_msgid: bool,
 ) -> (MessageReference, MessageReference) {
     match (
         first_message_reference.clone(),
@@ -1051,13 +1828,29 @@ pub fn fuzz_message_reference_range(
         ) => {
             if start_server_time < end_server_time {
                 (
-                    fuzz_start_message_reference(first_message_reference),
-                    fuzz_end_message_reference(second_message_reference),
+                    fuzz_start_message_reference(
+                        first_message_reference,
+                        fuzz_window,
+                        supports_msgid,
+                    ),
+                    fuzz_end_message_reference(
+                        second_message_reference,
+                        fuzz_window,
+                        supports_msgid,
+                    ),
                 )
             } else {
                 (
-                    fuzz_end_message_reference(first_message_reference),
-                    fuzz_start_message_reference(second_message_reference),
+                    fuzz_end_message_reference(
+                        first_message_reference,
+                        fuzz_window,
+                        supports_msgid,
+                    ),
+                    fuzz_start_message_reference(
+                        second_message_reference,
+                        fuzz_window,
+                        supports_msgid,
+                    ),
                 )
             }
         }
@@ -1077,7 +1870,7 @@ impl WhoToken {
 }
 
 impl FromStr for WhoToken {
-    type Err = &'static str;
+    type Err = ParseError;
 
     fn from_str(token: &str) -> Result<Self, Self::Err> {
         if (1usize..=3usize).contains(&token.chars().count())
@@ -1089,11 +1882,71 @@ impl FromStr for WhoToken {
 
             Ok(WhoToken { digits })
         } else {
-            Err("WHO token must be 1-3 ASCII digits")
+            Err(ParseError::Malformed {
+                parameter: token.to_string(),
+                reason: "WHO token must be 1-3 ASCII digits",
+            })
+        }
+    }
+}
+
+// Features issuing WHOX requests, each allocated the 3-digit tokens starting
+// with its leading digit so replies can be routed back to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhoFeature {
+    Presence,
+    AccountSync,
+    Manual,
+}
+
+impl WhoFeature {
+    fn leading_digit(self) -> char {
+        match self {
+            WhoFeature::Presence => '1',
+            WhoFeature::AccountSync => '2',
+            WhoFeature::Manual => '3',
+        }
+    }
+}
+
+// Cycles through the 100 tokens reserved for each feature
+#[derive(Clone, Debug, Default)]
+pub struct WhoTokenAllocator {
+    next: [u8; 3],
+}
+
+impl WhoTokenAllocator {
+    pub fn allocate(&mut self, feature: WhoFeature) -> WhoToken {
+        let next = &mut self.next[feature as usize];
+        let n = *next;
+
+        *next = (n + 1) % 100;
+
+        WhoToken {
+            digits: [
+                feature.leading_digit(),
+                char::from(b'0' + n / 10),
+                char::from(b'0' + n % 10),
+            ],
         }
     }
 }
 
+// Tokens outside the reserved ranges (e.g. the WHO poll's) have no feature
+pub fn token_feature(token: &WhoToken) -> Option<WhoFeature> {
+    if token.digits.contains(&'\0') {
+        return None;
+    }
+
+    [
+        WhoFeature::Presence,
+        WhoFeature::AccountSync,
+        WhoFeature::Manual,
+    ]
+    .into_iter()
+    .find(|feature| feature.leading_digit() == token.digits[0])
+}
+
 pub enum WhoXPollParameters {
     Default,
     WithAccountName,
@@ -1117,67 +1970,303 @@ impl WhoXPollParameters {
             },
         }
     }
-}
-
-fn parse_optional_letters(value: &str) -> Result<Option<String>, &'static str> {
-    if value.is_empty() {
-        Ok(None)
-    } else if value.chars().all(|c| c.is_ascii_alphabetic()) {
-        Ok(Some(value.to_string()))
-    } else {
-        Err("value required to be letter(s) if specified")
-    }
-}
 
-fn parse_optional_positive_integer(
-    value: &str,
-) -> Result<Option<u16>, &'static str> {
-    if value.is_empty() {
-        Ok(None)
-    } else if let Ok(value) = value.parse::<u16>() {
-        Ok(Some(value))
-    } else {
-        Err("optional value must be a positive integer if specified")
+    pub fn matches_token(&self, token: &WhoToken) -> bool {
+        self.token() == *token
     }
 }
 
-fn parse_required_letter(
-    value: &str,
-    default_value: Option<char>,
-) -> Result<char, &'static str> {
-    if let Some(value) = value.chars().next() {
-        if value.is_ascii_alphabetic() {
-            return Ok(value);
-        }
-    } else if let Some(default_value) = default_value {
-        return Ok(default_value);
-    }
+// Reference: https://ircv3.net/specs/extensions/whox
+const WHOX_FIELDS: &[char] = &[
+    't', 'c', 'u', 'i', 'h', 's', 'n', 'f', 'd', 'l', 'a', 'o', 'r',
+];
 
-    Err("value required to be a letter")
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum WhoxFieldsError {
+    #[error("no WHOX fields requested")]
+    Empty,
+    #[error("unknown WHOX field '{0}'")]
+    UnknownField(char),
+    #[error(transparent)]
+    Mask(#[from] InvalidMask),
 }
 
-fn parse_required_letters(value: &str) -> Result<String, &'static str> {
-    if !value.is_empty() {
-        if value.chars().all(|c| c.is_ascii_alphabetic()) {
-            Ok(value.to_string())
-        } else {
-            Err("value required to be letter(s)")
-        }
-    } else {
-        Err("value required")
-    }
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidMask {
+    #[error("mask user is too long ({len}/{max_len} characters)")]
+    UserTooLong { len: usize, max_len: usize },
+    #[error("mask host is too long ({len}/{max_len} characters)")]
+    HostTooLong { len: usize, max_len: usize },
 }
 
-fn parse_required_positive_integer(value: &str) -> Result<u16, &'static str> {
-    if let Ok(value) = value.parse::<u16>() {
-        Ok(value)
-    } else {
-        Err("value required to be a positive integer")
-    }
-}
+// Validates the user and host portions of a nick!user@host mask against
+// USERLEN and HOSTLEN (when advertised), since servers reject masks exceeding
+// either.  Targets that are not masks (e.g. channels) are not checked.
+pub fn validate_mask(
+    isupport: &HashMap<Kind, Parameter>,
+    mask: &str,
+) -> Result<(), InvalidMask> {
+    let Some((user, host)) = mask.rsplit_once('@') else {
+        return Ok(());
+    };
+
+    let user = user.split_once('!').map_or(user, |(_, user)| user);
+
+    if let Some(Parameter::USERLEN(max_len)) = isupport.get(&Kind::USERLEN)
+        && user.len() > *max_len as usize
+    {
+        return Err(InvalidMask::UserTooLong {
+            len: user.len(),
+            max_len: *max_len as usize,
+        });
+    }
+
+    if let Some(Parameter::HOSTLEN(max_len)) = isupport.get(&Kind::HOSTLEN)
+        && host.len() > *max_len as usize
+    {
+        return Err(InvalidMask::HostTooLong {
+            len: host.len(),
+            max_len: *max_len as usize,
+        });
+    }
+
+    Ok(())
+}
+
+// WHOX does not advertise which fields a server supports, so fields are only
+// validated against those defined by the spec
+pub fn build_whox(
+    isupport: &HashMap<Kind, Parameter>,
+    target: &str,
+    fields: &str,
+    token: Option<WhoToken>,
+) -> Result<proto::Command, WhoxFieldsError> {
+    validate_mask(isupport, target)?;
+
+    if fields.is_empty() {
+        return Err(WhoxFieldsError::Empty);
+    }
+
+    if let Some(field) = fields.chars().find(|c| !WHOX_FIELDS.contains(c)) {
+        return Err(WhoxFieldsError::UnknownField(field));
+    }
+
+    Ok(proto::Command::WHO(
+        target.to_string(),
+        Some(fields.to_string()),
+        token.map(WhoToken::to_owned),
+    ))
+}
+
+// Flags field shared by RPL_WHOREPLY and RPL_WHOSPCRPL, i.e. H (here) or G
+// (gone), followed by * for operators and any membership prefixes
+// Reference: https://modern.ircdocs.horse/#rplwhoreply-352
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WhoFlags {
+    // None when the flags do not start with H or G
+    pub away: Option<bool>,
+    pub oper: bool,
+    pub prefixes: Vec<char>,
+}
+
+impl WhoFlags {
+    // Flags not covered above (e.g. a bot mode flag) are ignored
+    pub fn parse(flags: &str, isupport: &HashMap<Kind, Parameter>) -> Self {
+        let prefix = get_prefix_or_default(isupport);
+
+        let away = match flags.chars().next() {
+            Some('G') => Some(true),
+            Some('H') => Some(false),
+            _ => None,
+        };
+
+        let rest = if away.is_some() { &flags[1..] } else { flags };
+
+        WhoFlags {
+            away,
+            oper: rest.contains('*'),
+            prefixes: rest
+                .chars()
+                .filter(|c| {
+                    prefix.iter().any(|prefix_map| prefix_map.prefix == *c)
+                })
+                .collect(),
+        }
+    }
+}
+
+// Fields of an RPL_WHOSPCRPL reply to a WHOX poll, as requested by the poll
+// parameters matching the reply's token, or of a plain RPL_WHOREPLY (which
+// carries no token or account, but carries user, host, server and realname
+// which WHOX polls don't request)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WhoxRecord {
+    pub token: Option<WhoToken>,
+    pub channel: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub server: Option<String>,
+    pub nick: String,
+    pub flags: WhoFlags,
+    pub account: Option<String>,
+    pub realname: Option<String>,
+}
+
+impl WhoxRecord {
+    // Parses reply arguments (beginning with the client's nick), where an
+    // account of 0 indicates the user is not logged in
+    pub fn parse(
+        args: &[String],
+        isupport: &HashMap<Kind, Parameter>,
+    ) -> Option<Self> {
+        let token = args.get(1)?.parse::<WhoToken>().ok()?;
+
+        let account = if WhoXPollParameters::Default.matches_token(&token) {
+            None
+        } else if WhoXPollParameters::WithAccountName.matches_token(&token) {
+            Some(args.get(5)?)
+                .filter(|account| *account != "0")
+                .cloned()
+        } else {
+            return None;
+        };
+
+        Some(WhoxRecord {
+            token: Some(token),
+            channel: args.get(2)?.clone(),
+            user: None,
+            host: None,
+            server: None,
+            nick: args.get(3)?.clone(),
+            flags: WhoFlags::parse(args.get(4)?, isupport),
+            account,
+            realname: None,
+        })
+    }
+}
+
+// Reference: https://modern.ircdocs.horse/#rplwhoreply-352
+pub fn parse_who_reply(
+    message: &proto::Message,
+    isupport: &HashMap<Kind, Parameter>,
+) -> Option<WhoxRecord> {
+    let proto::Command::Numeric(proto::command::Numeric::RPL_WHOREPLY, args) =
+        &message.command
+    else {
+        return None;
+    };
+
+    // <client> <channel> <username> <host> <server> <nick> <flags> :<hopcount> <realname>
+    let realname = args
+        .get(7)?
+        .split_once(' ')
+        .map(|(_, realname)| realname)
+        .unwrap_or_default();
+
+    Some(WhoxRecord {
+        token: None,
+        channel: args.get(1)?.clone(),
+        user: Some(args.get(2)?.clone()),
+        host: Some(args.get(3)?.clone()),
+        server: Some(args.get(4)?.clone()),
+        nick: args.get(5)?.clone(),
+        flags: WhoFlags::parse(args.get(6)?, isupport),
+        account: None,
+        realname: Some(realname.to_string()),
+    })
+}
+
+fn parse_optional_letters(
+    parameter: &str,
+    value: &str,
+) -> Result<Option<String>, ParseError> {
+    if value.is_empty() {
+        Ok(None)
+    } else if value.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(Some(value.to_string()))
+    } else {
+        Err(ParseError::Malformed {
+            parameter: parameter.to_string(),
+            reason: "value required to be letter(s) if specified",
+        })
+    }
+}
+
+fn parse_optional_positive_integer(
+    parameter: &str,
+    value: &str,
+) -> Result<Option<u16>, ParseError> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        parse_required_positive_integer(parameter, value).map(Some)
+    }
+}
+
+fn parse_required_letter(
+    parameter: &str,
+    value: &str,
+    default_value: Option<char>,
+) -> Result<char, ParseError> {
+    if let Some(value) = value.chars().next() {
+        if value.is_ascii_alphabetic() {
+            return Ok(value);
+        }
+    } else if let Some(default_value) = default_value {
+        return Ok(default_value);
+    } else {
+        return Err(ParseError::MissingValue {
+            parameter: parameter.to_string(),
+        });
+    }
+
+    Err(ParseError::Malformed {
+        parameter: parameter.to_string(),
+        reason: "value required to be a letter",
+    })
+}
+
+fn parse_required_letters(
+    parameter: &str,
+    value: &str,
+) -> Result<String, ParseError> {
+    if !value.is_empty() {
+        if value.chars().all(|c| c.is_ascii_alphabetic()) {
+            Ok(value.to_string())
+        } else {
+            Err(ParseError::Malformed {
+                parameter: parameter.to_string(),
+                reason: "value required to be letter(s)",
+            })
+        }
+    } else {
+        Err(ParseError::MissingValue {
+            parameter: parameter.to_string(),
+        })
+    }
+}
+
+fn parse_required_positive_integer(
+    parameter: &str,
+    value: &str,
+) -> Result<u16, ParseError> {
+    if value.is_empty() {
+        Err(ParseError::MissingValue {
+            parameter: parameter.to_string(),
+        })
+    } else if let Ok(value) = value.parse::<u16>() {
+        Ok(value)
+    } else {
+        Err(ParseError::InvalidInteger {
+            parameter: parameter.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
 
 // Returns the limit directly if found, since we currently treat "no target limit specified"
-// the same as "specifying no limit to the number of targets".
+// the same as "specifying no limit to the number of targets" (or a limit of 0,
+// see CommandTargetLimit::effective_limit).
 pub fn find_target_limit(
     isupport: &HashMap<Kind, Parameter>,
     command: &str,
@@ -1188,7 +2277,8 @@ pub fn find_target_limit(
         target_limits
             .iter()
             .find_map(|target_limit| {
-                (target_limit.command == command).then_some(target_limit.limit)
+                (target_limit.command == command)
+                    .then(|| target_limit.effective_limit())
             })
             .flatten()
     } else {
@@ -1196,12 +2286,40 @@ pub fn find_target_limit(
     }
 }
 
+// TARGMAX table sorted by command name for /targmax, with None signifying
+// unlimited
+pub fn format_targmax(
+    isupport: &HashMap<Kind, Parameter>,
+) -> Vec<(String, Option<u16>)> {
+    let Some(Parameter::TARGMAX(target_limits)) = isupport.get(&Kind::TARGMAX)
+    else {
+        return vec![];
+    };
+
+    target_limits
+        .iter()
+        .map(|target_limit| {
+            (target_limit.command.clone(), target_limit.effective_limit())
+        })
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .collect()
+}
+
 pub fn get_casemapping_or_default(
     isupport: &HashMap<Kind, Parameter>,
 ) -> CaseMap {
     get_casemapping(isupport).unwrap_or_default()
 }
 
+// Whether nick refers to the user (i.e. my_nick) under the server's casemapping
+pub fn is_self(
+    isupport: &HashMap<Kind, Parameter>,
+    nick: &str,
+    my_nick: &str,
+) -> bool {
+    get_casemapping_or_default(isupport).equals(nick, my_nick)
+}
+
 pub fn get_casemapping(isupport: &HashMap<Kind, Parameter>) -> Option<CaseMap> {
     if let Some(Parameter::CASEMAPPING(casemapping)) =
         isupport.get(&Kind::CASEMAPPING)
@@ -1212,6 +2330,47 @@ pub fn get_casemapping(isupport: &HashMap<Kind, Parameter>) -> Option<CaseMap> {
     }
 }
 
+// The (config, server) casemappings when a pinned casemapping contradicts the
+// one the server advertises; a server that does not advertise CASEMAPPING
+// cannot conflict
+pub fn casemapping_conflict(
+    config: CaseMap,
+    isupport: &HashMap<Kind, Parameter>,
+) -> Option<(CaseMap, CaseMap)> {
+    get_casemapping(isupport)
+        .filter(|server| *server != config)
+        .map(|server| (config, server))
+}
+
+// A server limit of zero signifies no limit, in which case the client limit is
+// used
+pub fn get_chathistory_limit_or_default(
+    isupport: &HashMap<Kind, Parameter>,
+) -> u16 {
+    if let Some(Parameter::CHATHISTORY(server_limit)) =
+        isupport.get(&Kind::CHATHISTORY)
+        && *server_limit != 0
+    {
+        return std::cmp::min(*server_limit, CLIENT_CHATHISTORY_LIMIT);
+    }
+
+    CLIENT_CHATHISTORY_LIMIT
+}
+
+pub fn get_clientver(
+    isupport: &HashMap<Kind, Parameter>,
+) -> Option<(u16, u16)> {
+    isupport.get(&Kind::CLIENTVER).and_then(|clientver| {
+        if let Parameter::CLIENTVER(major, minor) = clientver {
+            Some((*major, *minor))
+        } else {
+            log::debug!("Corruption in isupport table.");
+
+            None
+        }
+    })
+}
+
 // https://modern.ircdocs.horse/#chanmodes-parameter
 pub fn get_chanmodes_or_default(
     isupport: &HashMap<Kind, Parameter>,
@@ -1266,6 +2425,270 @@ pub fn get_mode_limit_or_default(
         .unwrap_or(Some(3))
 }
 
+// https://modern.ircdocs.horse/#maxlist-parameter
+// Each entry's limit applies to all of its mode letters (e.g. beI:100)
+pub fn list_mode_limit(
+    isupport: &HashMap<Kind, Parameter>,
+    mode: char,
+) -> Option<u16> {
+    if let Some(Parameter::MAXLIST(modes_limits)) = isupport.get(&Kind::MAXLIST)
+    {
+        modes_limits
+            .iter()
+            .find(|modes_limit| modes_limit.modes.contains(mode))
+            .map(|modes_limit| modes_limit.limit)
+    } else {
+        None
+    }
+}
+
+// https://modern.ircdocs.horse/#channellen-parameter
+// No parameter currently limits channel creation separately, so CHANNELLEN
+// applies whether creating or joining
+pub fn max_channel_len(
+    isupport: &HashMap<Kind, Parameter>,
+    _creating: bool,
+) -> u16 {
+    get_channellen_or_default(isupport)
+}
+
+// Bytes of text that fit in one `<command> <target> :<text>` line (e.g. for
+// formatting::split_message), falling back to the RFC line length until
+// LINELEN is known
+pub fn message_split_len(
+    isupport: &HashMap<Kind, Parameter>,
+    command: &str,
+    target: &str,
+) -> usize {
+    line_budget(isupport, command, target, HOSTMASK_SAFETY_MARGIN)
+}
+
+// Bytes of text left in a line once the `:<source> ` prefix (prefix_len
+// bytes) and the rest of the framing are accounted for
+fn line_budget(
+    isupport: &HashMap<Kind, Parameter>,
+    command: &str,
+    target: &str,
+    prefix_len: usize,
+) -> usize {
+    let linelen = get_linelen_or_default(isupport);
+
+    // Command, target and text are separated by " " and " :", and the line
+    // is terminated by "\r\n"
+    let overhead = prefix_len + command.len() + 1 + target.len() + 2 + 2;
+
+    (linelen as usize).saturating_sub(overhead)
+}
+
+// Splits text into lines that fit in `<command> <target> :<line>` as relayed
+// by the server with a source (i.e. nick!user@host) of source_prefix_len
+// bytes.  Lines are split without breaking characters or formatting codes
+// apart, preferring whitespace in the latter half of a line.
+pub fn split_message(
+    isupport: &HashMap<Kind, Parameter>,
+    command: &str,
+    target: &str,
+    text: &str,
+    source_prefix_len: usize,
+) -> Vec<String> {
+    formatting::split_message_at_whitespace(
+        text,
+        line_budget(isupport, command, target, 1 + source_prefix_len + 1),
+    )
+}
+
+// Length limits for configuring and validating input, with defaults applied
+// for those the server does not advertise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthLimits {
+    pub nicklen: u16,
+    pub channellen: u16,
+    pub topiclen: u16,
+    pub kicklen: u16,
+    pub awaylen: u16,
+    pub keylen: u16,
+    pub userlen: u16,
+    pub hostlen: u16,
+    pub linelen: u16,
+}
+
+pub fn length_limits(isupport: &HashMap<Kind, Parameter>) -> LengthLimits {
+    LengthLimits {
+        nicklen: get_nicklen_or_default(isupport),
+        channellen: max_channel_len(isupport, false),
+        topiclen: get_topiclen_or_default(isupport),
+        kicklen: get_kicklen_or_default(isupport),
+        awaylen: get_awaylen_or_default(isupport),
+        keylen: get_keylen_or_default(isupport),
+        userlen: get_userlen_or_default(isupport),
+        hostlen: get_hostlen_or_default(isupport),
+        linelen: get_linelen_or_default(isupport),
+    }
+}
+
+// Snapshot of the limits input validation needs
+pub type ServerLimits = LengthLimits;
+
+impl LengthLimits {
+    pub fn from_isupport(isupport: &HashMap<Kind, Parameter>) -> Self {
+        length_limits(isupport)
+    }
+}
+
+// https://modern.ircdocs.horse/#nicklen-parameter
+pub fn get_nicklen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::NICKLEN)
+        .and_then(|nicklen| {
+            if let Parameter::NICKLEN(len) = nicklen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_NICKLEN)
+}
+
+// https://modern.ircdocs.horse/#topiclen-parameter
+pub fn get_topiclen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::TOPICLEN)
+        .and_then(|topiclen| {
+            if let Parameter::TOPICLEN(len) = topiclen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_TOPICLEN)
+}
+
+// https://modern.ircdocs.horse/#channellen-parameter
+pub fn get_channellen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::CHANNELLEN)
+        .and_then(|channellen| {
+            if let Parameter::CHANNELLEN(len) = channellen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_CHANNELLEN)
+}
+
+// https://modern.ircdocs.horse/#kicklen-parameter
+pub fn get_kicklen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::KICKLEN)
+        .and_then(|kicklen| {
+            if let Parameter::KICKLEN(len) = kicklen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_KICKLEN)
+}
+
+// https://modern.ircdocs.horse/#awaylen-parameter
+pub fn get_awaylen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::AWAYLEN)
+        .and_then(|awaylen| {
+            if let Parameter::AWAYLEN(len) = awaylen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_AWAYLEN)
+}
+
+pub fn get_keylen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::KEYLEN)
+        .and_then(|keylen| {
+            if let Parameter::KEYLEN(len) = keylen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_KEYLEN)
+}
+
+// https://modern.ircdocs.horse/#userlen-parameter
+pub fn get_userlen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::USERLEN)
+        .and_then(|userlen| {
+            if let Parameter::USERLEN(len) = userlen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_USERLEN)
+}
+
+// https://modern.ircdocs.horse/#hostlen-parameter
+pub fn get_hostlen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::HOSTLEN)
+        .and_then(|hostlen| {
+            if let Parameter::HOSTLEN(len) = hostlen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_HOSTLEN)
+}
+
+pub fn get_linelen_or_default(isupport: &HashMap<Kind, Parameter>) -> u16 {
+    isupport
+        .get(&Kind::LINELEN)
+        .and_then(|linelen| {
+            if let Parameter::LINELEN(len) = linelen {
+                Some(*len)
+            } else {
+                log::debug!("Corruption in isupport table.");
+
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_LINELEN)
+}
+
+// Longest prefix of text within limit bytes that does not split a character,
+// e.g. truncate_to_limit(topic, get_topiclen_or_default(isupport))
+pub fn truncate_to_limit(text: &str, limit: u16) -> &str {
+    let mut end = (limit as usize).min(text.len());
+
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &text[..end]
+}
+
 pub fn get_prefix(isupport: &HashMap<Kind, Parameter>) -> Option<&[PrefixMap]> {
     isupport.get(&Kind::PREFIX).and_then(|prefix| {
         if let Parameter::PREFIX(prefix) = prefix {
@@ -1297,3 +2720,4139 @@ pub fn get_statusmsg_or_default(
         }
     })
 }
+
+// Channel modes a newly created channel likely starts with, i.e. the
+// CHANMODES type D (no argument) modes
+pub fn default_channel_modes(isupport: &HashMap<Kind, Parameter>) -> Vec<char> {
+    get_chanmodes_or_default(isupport)
+        .iter()
+        .filter(|mode_kind| mode_kind.kind == 'D')
+        .flat_map(|mode_kind| mode_kind.modes.chars())
+        .collect()
+}
+
+// CHANMODES type (A to D) listing the mode, where an empty group (e.g. the
+// third group of CHANMODES=beI,k,,imnpst) lists no modes
+pub fn chanmode_group(
+    isupport: &HashMap<Kind, Parameter>,
+    mode: char,
+) -> Option<char> {
+    get_chanmodes_or_default(isupport)
+        .iter()
+        .find(|mode_kind| mode_kind.modes.contains(mode))
+        .map(|mode_kind| mode_kind.kind)
+}
+
+// PREFIX modes always take an argument, otherwise it depends on the CHANMODES
+// type (list modes are only queried without an argument, which is not covered)
+pub fn mode_takes_argument(
+    isupport: &HashMap<Kind, Parameter>,
+    mode: char,
+    adding: bool,
+) -> bool {
+    mode::takes_argument(
+        mode,
+        adding,
+        get_chanmodes_or_default(isupport),
+        get_prefix_or_default(isupport),
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeCategory {
+    Prefix,
+    ListA,
+    SettableB,
+    SettableC,
+    FlagD,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EditableMode {
+    pub mode: char,
+    pub category: ModeCategory,
+    // Whether setting the mode requires an argument
+    pub takes_argument: bool,
+}
+
+// PREFIX modes followed by the CHANMODES groups in order, skipping PREFIX
+// modes a server also (erroneously) lists in CHANMODES
+pub fn all_channel_modes(
+    isupport: &HashMap<Kind, Parameter>,
+) -> Vec<EditableMode> {
+    let prefix = get_prefix_or_default(isupport);
+
+    let prefix_modes = prefix.iter().map(|prefix_map| EditableMode {
+        mode: prefix_map.mode,
+        category: ModeCategory::Prefix,
+        takes_argument: true,
+    });
+
+    let chanmodes = get_chanmodes_or_default(isupport)
+        .iter()
+        .filter_map(|mode_kind| {
+            let category = match mode_kind.kind {
+                'A' => ModeCategory::ListA,
+                'B' => ModeCategory::SettableB,
+                'C' => ModeCategory::SettableC,
+                'D' => ModeCategory::FlagD,
+                _ => return None,
+            };
+
+            Some(mode_kind.modes.chars().map(move |mode| EditableMode {
+                mode,
+                category,
+                takes_argument: category != ModeCategory::FlagD,
+            }))
+        })
+        .flatten()
+        .filter(|editable_mode| {
+            !prefix
+                .iter()
+                .any(|prefix_map| prefix_map.mode == editable_mode.mode)
+        });
+
+    prefix_modes.chain(chanmodes).collect()
+}
+
+// Why a command cannot be built for the server, naming the missing feature or
+// the rejected input
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Unsupported {
+    #[error("not supported by this server (no {})", .0.as_str())]
+    MissingParameter(Kind),
+    #[error("not supported by this server (no {0} capability)")]
+    MissingCapability(&'static str),
+    #[error("{0:?} cannot be sent as a parameter")]
+    InvalidParameter(String),
+    #[error("'{0}' is not a list mode")]
+    NotListMode(char),
+    #[error(transparent)]
+    InvalidNick(#[from] InvalidNick),
+}
+
+fn require_parameter(
+    isupport: &HashMap<Kind, Parameter>,
+    kind: Kind,
+) -> Result<(), Unsupported> {
+    if isupport.contains_key(&kind) {
+        Ok(())
+    } else {
+        Err(Unsupported::MissingParameter(kind))
+    }
+}
+
+// A middle parameter can neither be empty, start with ':' nor contain a space,
+// and no parameter can contain NUL, CR or LF (which would end the line early)
+fn require_middle(parameter: &str) -> Result<(), Unsupported> {
+    if parameter.is_empty()
+        || parameter.starts_with(':')
+        || parameter.contains([' ', '\0', '\r', '\n'])
+    {
+        Err(Unsupported::InvalidParameter(parameter.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn require_trailing(parameter: &str) -> Result<(), Unsupported> {
+    if parameter.contains(['\0', '\r', '\n']) {
+        Err(Unsupported::InvalidParameter(parameter.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn knock(
+    isupport: &HashMap<Kind, Parameter>,
+    channel: &str,
+    message: Option<&str>,
+) -> Result<String, Unsupported> {
+    require_parameter(isupport, Kind::KNOCK)?;
+    require_middle(channel)?;
+    message.map(require_trailing).transpose()?;
+
+    Ok(match message {
+        Some(message) => format!("KNOCK {channel} :{message}"),
+        None => format!("KNOCK {channel}"),
+    })
+}
+
+pub fn userip(
+    isupport: &HashMap<Kind, Parameter>,
+    nick: &str,
+) -> Result<String, Unsupported> {
+    require_parameter(isupport, Kind::USERIP)?;
+    require_middle(nick)?;
+
+    Ok(format!("USERIP {nick}"))
+}
+
+// Message to a user in a shared channel where we are an operator, bypassing
+// the server's target change rate limit
+pub fn cprivmsg(
+    isupport: &HashMap<Kind, Parameter>,
+    nick: &str,
+    channel: &str,
+    text: &str,
+) -> Result<String, Unsupported> {
+    require_parameter(isupport, Kind::CPRIVMSG)?;
+    require_middle(nick)?;
+    require_middle(channel)?;
+    require_trailing(text)?;
+
+    Ok(format!("CPRIVMSG {nick} {channel} :{text}"))
+}
+
+// First of our channels (each with its member nicks) that nick is also in,
+// for use as the channel of a CPRIVMSG
+pub fn shared_channel(
+    my_channels: &[(Target, Vec<String>)],
+    nick: &str,
+    isupport: &HashMap<Kind, Parameter>,
+) -> Option<Target> {
+    let casemapping = get_casemapping_or_default(isupport);
+
+    my_channels
+        .iter()
+        .find(|(_, members)| {
+            members
+                .iter()
+                .any(|member| casemapping.equals(member, nick))
+        })
+        .map(|(channel, _)| channel.clone())
+}
+
+// Query listing the entries of a list mode (CHANMODES type A), e.g. the bans
+// of a channel for +b
+pub fn list_mode_query(
+    isupport: &HashMap<Kind, Parameter>,
+    channel: &str,
+    mode: char,
+) -> Result<String, Unsupported> {
+    if get_chanmodes_or_default(isupport).iter().any(|mode_kind| {
+        mode_kind.kind == 'A' && mode_kind.modes.contains(mode)
+    }) {
+        Ok(format!("MODE {channel} +{mode}"))
+    } else {
+        Err(Unsupported::NotListMode(mode))
+    }
+}
+
+// Pairs are returned in PREFIX order, which is highest privilege first
+pub fn prefix_legend(isupport: &HashMap<Kind, Parameter>) -> Vec<(char, char)> {
+    get_prefix_or_default(isupport)
+        .iter()
+        .map(|prefix_map| (prefix_map.prefix, prefix_map.mode))
+        .collect()
+}
+
+// Prefix characters in PREFIX order, e.g. for stripping them from a nick
+pub fn prefix_chars(isupport: &HashMap<Kind, Parameter>) -> Vec<char> {
+    get_prefix_or_default(isupport)
+        .iter()
+        .map(|prefix_map| prefix_map.prefix)
+        .collect()
+}
+
+// Combines NAMES entries for the same nick (under the server's casemapping),
+// keeping the first spelling and the union of their prefixes in PREFIX order
+pub fn merge_names(
+    isupport: &HashMap<Kind, Parameter>,
+    entries: Vec<(Vec<char>, String)>,
+) -> Vec<(Vec<char>, String)> {
+    use std::collections::hash_map;
+
+    let casemapping = get_casemapping_or_default(isupport);
+    let prefix_chars = prefix_chars(isupport);
+
+    let rank = |prefix: &char| {
+        prefix_chars
+            .iter()
+            .position(|c| c == prefix)
+            .unwrap_or(prefix_chars.len())
+    };
+
+    let mut merged: Vec<(Vec<char>, String)> = vec![];
+    let mut positions = HashMap::new();
+
+    for (prefixes, nick) in entries {
+        match positions.entry(casemapping.normalize(&nick)) {
+            hash_map::Entry::Occupied(entry) => {
+                let (merged_prefixes, _) = &mut merged[*entry.get()];
+
+                for prefix in prefixes {
+                    if !merged_prefixes.contains(&prefix) {
+                        merged_prefixes.push(prefix);
+                    }
+                }
+            }
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(merged.len());
+                merged.push((prefixes.into_iter().unique().collect(), nick));
+            }
+        }
+    }
+
+    for (prefixes, _) in &mut merged {
+        prefixes.sort_by_key(rank);
+    }
+
+    merged
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ChannelNameError {
+    #[error(
+        "channel name must start with one of: {}",
+        .0.iter().collect::<String>()
+    )]
+    InvalidPrefix(Vec<char>),
+    #[error("channel name is longer than {0} bytes")]
+    TooLong(u16),
+    #[error("channel name contains invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+// Without CHANNELLEN the default length applies (see max_channel_len)
+pub fn validate_channel_name(
+    isupport: &HashMap<Kind, Parameter>,
+    name: &str,
+) -> Result<(), ChannelNameError> {
+    let chantypes = get_chantypes_or_default(isupport);
+
+    if !name.starts_with(chantypes) {
+        return Err(ChannelNameError::InvalidPrefix(chantypes.to_vec()));
+    }
+
+    let channellen = get_channellen_or_default(isupport);
+
+    if name.len() > channellen as usize {
+        return Err(ChannelNameError::TooLong(channellen));
+    }
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| *c == ' ' || *c == ',' || c.is_control())
+    {
+        return Err(ChannelNameError::InvalidCharacter(c));
+    }
+
+    Ok(())
+}
+
+// Commands that only make sense for a channel target or only for a nick
+// target; any other command applies to both
+const CHANNEL_COMMANDS: &[&str] = &["KICK", "MODE", "NAMES", "PART", "TOPIC"];
+
+const NICK_COMMANDS: &[&str] = &["WHOIS", "WHOWAS"];
+
+pub fn command_applies(
+    isupport: &HashMap<Kind, Parameter>,
+    command: &str,
+    target: &str,
+) -> bool {
+    let is_channel =
+        proto::is_channel(target, get_chantypes_or_default(isupport));
+
+    if CHANNEL_COMMANDS
+        .iter()
+        .any(|channel_command| command.eq_ignore_ascii_case(channel_command))
+    {
+        is_channel
+    } else if NICK_COMMANDS
+        .iter()
+        .any(|nick_command| command.eq_ignore_ascii_case(nick_command))
+    {
+        !is_channel
+    } else {
+        true
+    }
+}
+
+// The first attempt appends an underscore, later attempts append the attempt
+// number, trimming the base nick so the candidate stays within NICKLEN
+pub fn next_nick_candidate(
+    isupport: &HashMap<Kind, Parameter>,
+    base: &str,
+    attempt: u32,
+) -> String {
+    let suffix = if attempt == 0 {
+        String::from("_")
+    } else {
+        attempt.to_string()
+    };
+
+    // NICKLEN counts bytes, so the base is trimmed on a character boundary
+    let base_len =
+        get_nicklen_or_default(isupport).saturating_sub(suffix.len() as u16);
+
+    format!("{}{suffix}", truncate_to_limit(base, base_len))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidNick {
+    #[error("nick is empty")]
+    Empty,
+    #[error("nick is longer than {0} characters")]
+    TooLong(u16),
+    #[error("nick starts with channel prefix {0:?}")]
+    ChannelPrefix(char),
+    #[error("nick contains invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+// Reference: https://modern.ircdocs.horse/#clients
+pub fn validate_nick(
+    isupport: &HashMap<Kind, Parameter>,
+    nick: &str,
+) -> Result<(), InvalidNick> {
+    let Some(first) = nick.chars().next() else {
+        return Err(InvalidNick::Empty);
+    };
+
+    if get_chantypes_or_default(isupport).contains(&first) {
+        return Err(InvalidNick::ChannelPrefix(first));
+    }
+
+    if matches!(first, '$' | ':') {
+        return Err(InvalidNick::InvalidCharacter(first));
+    }
+
+    if let Some(c) = nick
+        .chars()
+        .find(|c| [' ', ',', '*', '?', '!', '@'].contains(c) || c.is_control())
+    {
+        return Err(InvalidNick::InvalidCharacter(c));
+    }
+
+    let nicklen = get_nicklen_or_default(isupport);
+
+    if nick.chars().count() > nicklen as usize {
+        return Err(InvalidNick::TooLong(nicklen));
+    }
+
+    Ok(())
+}
+
+pub fn nick_change_command(
+    isupport: &HashMap<Kind, Parameter>,
+    new: &str,
+) -> Result<String, Unsupported> {
+    validate_nick(isupport, new)?;
+
+    Ok(format!("NICK {new}"))
+}
+
+// The display form of a query target; the buffer itself is keyed by the
+// casemapped form (see CaseMap::normalize)
+pub fn canonical_query(
+    isupport: &HashMap<Kind, Parameter>,
+    nick: &str,
+) -> Result<String, InvalidNick> {
+    validate_nick(isupport, nick)?;
+
+    Ok(nick.to_string())
+}
+
+// Reference: https://datatracker.ietf.org/doc/html/rfc2811#section-3.2
+pub const DEFAULT_SAFE_CHANNEL_PREFIXES: &[char] = &['!'];
+
+// Which channel prefixes denote safe channels is not advertised by the
+// server, so the caller provides them (DEFAULT_SAFE_CHANNEL_PREFIXES unless
+// configured otherwise)
+pub fn is_safe_channel_prefix(
+    isupport: &HashMap<Kind, Parameter>,
+    safe_prefixes: &[char],
+    prefix: char,
+) -> bool {
+    safe_prefixes.contains(&prefix)
+        && get_chantypes_or_default(isupport).contains(&prefix)
+}
+
+// Safe channels are created by joining the short name with a doubled prefix
+// (e.g. !!channel), after which the server renames the channel to include an
+// assigned ID.  Returns the prefix when JOINing the channel would create one.
+pub fn safe_channel_creation_prefix(
+    isupport: &HashMap<Kind, Parameter>,
+    safe_prefixes: &[char],
+    channel: &str,
+) -> Option<char> {
+    let mut chars = channel.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(first), Some(second))
+            if first == second
+                && is_safe_channel_prefix(isupport, safe_prefixes, first) =>
+        {
+            Some(first)
+        }
+        _ => None,
+    }
+}
+
+// Length of the ID a server assigns to a safe channel, placed between the
+// prefix and the short name
+const SAFE_CHANNEL_ID_LEN: usize = 5;
+
+// When a JOIN for a safe channel (requested as !!name or !name) is answered
+// with the fully-qualified name (!IDname), returns the (old, new) buffer keys
+// so the buffer can be re-keyed to the name assigned by the server.  Only the
+// default safe channel prefixes are recognized, since the server does not
+// advertise them.
+pub fn resolve_safe_channel(
+    requested: &str,
+    joined: &str,
+) -> Option<(String, String)> {
+    let prefix = requested
+        .chars()
+        .next()
+        .filter(|prefix| DEFAULT_SAFE_CHANNEL_PREFIXES.contains(prefix))?;
+
+    let short_name = requested[prefix.len_utf8()..].trim_start_matches(prefix);
+
+    let assigned = joined.strip_prefix(prefix)?;
+
+    let id = assigned.get(..SAFE_CHANNEL_ID_LEN)?;
+    let name = &assigned[SAFE_CHANNEL_ID_LEN..];
+
+    (!short_name.is_empty()
+        && requested != joined
+        && id
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        && name.eq_ignore_ascii_case(short_name))
+    .then(|| (requested.to_string(), joined.to_string()))
+}
+
+// Parameters without a kind (i.e. not tracked by the client) are dropped.
+// Returns the parameter previously in the table, if any
+pub fn apply(
+    isupport: &mut HashMap<Kind, Parameter>,
+    op: Operation,
+) -> Option<Parameter> {
+    let Some(kind) = op.kind() else {
+        log::debug!("Ignoring ISUPPORT operation without a kind: {op:?}");
+
+        return None;
+    };
+
+    match op {
+        Operation::Add(parameter) => isupport.insert(kind, parameter),
+        Operation::Remove(_) => isupport.remove(&kind),
+    }
+}
+
+// Incoming parameters take precedence, while parameters only present in the
+// base table are kept.  A table holds no removals, so a parameter the server
+// has since removed is dropped by applying the removal (see apply) instead.
+pub fn merge(
+    base: &mut HashMap<Kind, Parameter>,
+    incoming: HashMap<Kind, Parameter>,
+) {
+    for parameter in incoming.into_values() {
+        apply(base, Operation::Add(parameter));
+    }
+}
+
+// Reference: https://github.com/ircv3/ircv3-specifications/pull/464/files
+// None signifies that the server does not advertise a client version, which
+// must not be treated as incompatible
+pub fn clientver_compatible(
+    isupport: &HashMap<Kind, Parameter>,
+    client: (u16, u16),
+) -> Option<bool> {
+    get_clientver(isupport).map(|clientver| clientver <= client)
+}
+
+// Only warn when the server advertises a client version newer than ours
+pub fn should_warn_version(
+    isupport: &HashMap<Kind, Parameter>,
+    client: (u16, u16),
+) -> bool {
+    clientver_compatible(isupport, client) == Some(false)
+}
+
+// Capabilities that must be negotiated for an ISUPPORT parameter to be usable
+// by the client
+pub fn required_capabilities(kind: Kind) -> &'static [&'static str] {
+    match kind {
+        Kind::BOUNCER_NETID => &["soju.im/bouncer-networks"],
+        Kind::CHATHISTORY | Kind::MSGREFTYPES => {
+            &["batch", "draft/chathistory", "server-time"]
+        }
+        _ => &[],
+    }
+}
+
+// ISUPPORT table where every parameter is keyed by its own kind, so lookups
+// never encounter a parameter stored under the wrong key
+#[derive(Clone, Debug, Default)]
+pub struct ISupport(HashMap<Kind, Parameter>);
+
+impl ISupport {
+    // Applies an operation, returning the parameter it replaced or removed
+    pub fn insert(&mut self, operation: Operation) -> Option<Parameter> {
+        apply(&mut self.0, operation)
+    }
+
+    pub fn remove(&mut self, parameter: &str) -> Option<Parameter> {
+        self.insert(Operation::Remove(parameter.to_string()))
+    }
+
+    pub fn get(&self, kind: &Kind) -> Option<&Parameter> {
+        self.0.get(kind)
+    }
+
+    pub fn contains(&self, kind: &Kind) -> bool {
+        self.0.contains_key(kind)
+    }
+
+    pub fn as_map(&self) -> &HashMap<Kind, Parameter> {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> HashMap<Kind, Parameter> {
+        self.0
+    }
+
+    pub fn casemapping(&self) -> CaseMap {
+        get_casemapping_or_default(&self.0)
+    }
+
+    pub fn chanmodes(&self) -> &[ModeKind] {
+        get_chanmodes_or_default(&self.0)
+    }
+
+    pub fn chantypes(&self) -> &[char] {
+        get_chantypes_or_default(&self.0)
+    }
+
+    pub fn chathistory_limit(&self) -> u16 {
+        get_chathistory_limit_or_default(&self.0)
+    }
+
+    pub fn nicklen(&self) -> u16 {
+        get_nicklen_or_default(&self.0)
+    }
+
+    pub fn prefix(&self) -> &[PrefixMap] {
+        get_prefix_or_default(&self.0)
+    }
+
+    pub fn statusmsg(&self) -> &[char] {
+        get_statusmsg_or_default(&self.0)
+    }
+
+    pub fn target_limit(&self, command: &str) -> Option<u16> {
+        find_target_limit(&self.0, command)
+    }
+
+    pub fn restore_received(&mut self, received: &HashMap<Kind, Parameter>) {
+        restore_received(&mut self.0, received);
+    }
+
+    pub fn apply_overrides(&mut self, overrides: &[Operation]) {
+        apply_overrides(&mut self.0, overrides);
+    }
+
+    pub fn finalize(&mut self) -> Vec<FinalizeWarning> {
+        finalize(&mut self.0)
+    }
+}
+
+// Read access to the table for the free functions taking the inner map; there
+// is deliberately no DerefMut, so that the invariant holds
+impl std::ops::Deref for ISupport {
+    type Target = HashMap<Kind, Parameter>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+// Entries whose parameter does not match their key are dropped
+impl From<HashMap<Kind, Parameter>> for ISupport {
+    fn from(isupport: HashMap<Kind, Parameter>) -> Self {
+        ISupport(
+            isupport
+                .into_iter()
+                .filter(|(kind, parameter)| {
+                    let valid = parameter.kind().as_ref() == Some(kind);
+
+                    if !valid {
+                        log::debug!("Corruption in isupport table.");
+                    }
+
+                    valid
+                })
+                .collect(),
+        )
+    }
+}
+
+// Negotiated capabilities alongside the ISUPPORT table, where complete marks
+// the end of registration (after which both are final until changed by the
+// server)
+#[derive(Clone, Debug, Default)]
+pub struct ServerFeatures {
+    pub isupport: ISupport,
+    pub caps: HashSet<String>,
+    pub complete: bool,
+    // Parameters advertised via their draft/ spelling
+    pub drafts: HashSet<Kind>,
+    // Warnings from the latest finalize pass
+    pub warnings: Vec<FinalizeWarning>,
+    // Parameters validated by finalize, as received from the server
+    received: HashMap<Kind, Parameter>,
+}
+
+// Records whether an RPL_ISUPPORT token used the draft/ spelling of its
+// parameter (e.g. draft/CHATHISTORY), which the parsed operation does not keep
+pub fn record_draft(
+    drafts: &mut HashSet<Kind>,
+    token: &str,
+    operation: &Operation,
+) {
+    let Some(kind) = operation.kind() else {
+        return;
+    };
+
+    match operation {
+        Operation::Add(_) if token.starts_with("draft/") => {
+            drafts.insert(kind);
+        }
+        _ => {
+            drafts.remove(&kind);
+        }
+    }
+}
+
+// Parameters finalize validates against each other, which may arrive in any
+// order (and across multiple RPL_ISUPPORT lines)
+const FINALIZE_DEPENDENCIES: &[Kind] = &[
+    Kind::CHANMODES,
+    Kind::CHANTYPES,
+    Kind::PREFIX,
+    Kind::STATUSMSG,
+];
+
+impl ServerFeatures {
+    // Applies an RPL_ISUPPORT token, recording whether the parameter used its
+    // draft/ spelling (e.g. draft/CHATHISTORY)
+    pub fn apply_token(&mut self, token: &str) -> Result<(), ParseError> {
+        let operation = token.parse::<Operation>()?;
+
+        let Some(kind) = operation.kind() else {
+            return Ok(());
+        };
+
+        let revalidate = FINALIZE_DEPENDENCIES.contains(&kind);
+
+        record_draft(&mut self.drafts, token, &operation);
+
+        match operation {
+            Operation::Add(parameter) => {
+                if revalidate {
+                    self.received.insert(kind.clone(), parameter.clone());
+                }
+
+                self.isupport.insert(Operation::Add(parameter));
+            }
+            Operation::Remove(parameter) => {
+                self.received.remove(&kind);
+                self.isupport.remove(&parameter);
+            }
+        }
+
+        if revalidate {
+            self.revalidate();
+        }
+
+        Ok(())
+    }
+
+    // Re-runs finalize from the parameters as received, so that a decision
+    // made before a dependency arrived (e.g. STATUSMSG validated against the
+    // default PREFIX) is corrected once it does
+    fn revalidate(&mut self) {
+        self.isupport.restore_received(&self.received);
+
+        self.warnings = self.isupport.finalize();
+    }
+
+    pub fn is_draft(&self, kind: Kind) -> bool {
+        self.drafts.contains(&kind)
+    }
+
+    // The parameter is advertised and all capabilities it depends on have
+    // been acknowledged
+    pub fn supports(&self, kind: Kind) -> bool {
+        required_capabilities(kind.clone())
+            .iter()
+            .all(|cap| self.caps.contains(*cap))
+            && self.isupport.contains_key(&kind)
+    }
+
+    // Like supports, but naming the first missing capability or parameter
+    pub fn require(&self, kind: Kind) -> Result<(), Unsupported> {
+        if let Some(cap) = required_capabilities(kind.clone())
+            .iter()
+            .copied()
+            .find(|cap| !self.caps.contains(*cap))
+        {
+            return Err(Unsupported::MissingCapability(cap));
+        }
+
+        require_parameter(&self.isupport, kind)
+    }
+
+    pub fn supports_chathistory(&self) -> bool {
+        self.supports(Kind::CHATHISTORY)
+    }
+}
+
+// Client features that depend on the server advertising ISUPPORT parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Scrollback,
+    Presence,
+    MassOp,
+}
+
+impl Feature {
+    // Each group of kinds is satisfied by any one kind in the group
+    fn requirements(self) -> &'static [&'static [Kind]] {
+        match self {
+            Feature::Scrollback => &[&[Kind::CHATHISTORY]],
+            Feature::Presence => &[&[Kind::MONITOR, Kind::WATCH]],
+            Feature::MassOp => &[&[Kind::MODES]],
+        }
+    }
+}
+
+// Kinds absent from the table that prevent the feature from being enabled
+// (all kinds of an unsatisfied group are listed), for explaining disabled
+// features to the user
+pub fn missing_requirements(
+    isupport: &HashMap<Kind, Parameter>,
+    feature: Feature,
+) -> Vec<Kind> {
+    feature
+        .requirements()
+        .iter()
+        .filter(|kinds| !kinds.iter().any(|kind| isupport.contains_key(kind)))
+        .flat_map(|kinds| kinds.iter().cloned())
+        .collect()
+}
+
+// The CHANLIMIT entry for channels with the given prefix, where Some(None)
+// signifies no limit.  None when CHANLIMIT is not advertised or does not
+// list the prefix.
+pub fn get_channel_limit(
+    isupport: &HashMap<Kind, Parameter>,
+    prefix: char,
+) -> Option<Option<u16>> {
+    if let Some(Parameter::CHANLIMIT(channel_limits)) =
+        isupport.get(&Kind::CHANLIMIT)
+    {
+        channel_limits
+            .iter()
+            .find(|channel_limit| channel_limit.prefixes.contains(prefix))
+            .map(|channel_limit| channel_limit.limit)
+    } else {
+        None
+    }
+}
+
+// Number of additional channels with the given prefix that can be joined,
+// given the channels already joined, or None when unlimited.  CHANLIMIT is
+// counted across each prefix group, falling back to MAXCHANNELS (counted
+// across all channels) when CHANLIMIT is not advertised.
+pub fn remaining_channel_slots(
+    isupport: &HashMap<Kind, Parameter>,
+    joined: &[String],
+    prefix: char,
+) -> Option<usize> {
+    if let Some(Parameter::CHANLIMIT(channel_limits)) =
+        isupport.get(&Kind::CHANLIMIT)
+    {
+        let channel_limit = channel_limits
+            .iter()
+            .find(|channel_limit| channel_limit.prefixes.contains(prefix))?;
+
+        let limit = channel_limit.limit? as usize;
+
+        let count = joined
+            .iter()
+            .filter(|channel| {
+                channel
+                    .starts_with(|c: char| channel_limit.prefixes.contains(c))
+            })
+            .count();
+
+        Some(limit.saturating_sub(count))
+    } else if let Some(Parameter::MAXCHANNELS(max_channels)) =
+        isupport.get(&Kind::MAXCHANNELS)
+    {
+        Some((*max_channels as usize).saturating_sub(joined.len()))
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TopicError {
+    #[error("topic is {len} bytes, longer than {max_len}")]
+    TooLong { len: usize, max_len: u16 },
+}
+
+// Line breaks (and NUL) would end or corrupt the TOPIC line, so they are
+// removed; formatting codes are kept.  Without TOPICLEN the length of a topic
+// is not limited
+pub fn prepare_topic(
+    isupport: &HashMap<Kind, Parameter>,
+    topic: &str,
+) -> Result<String, TopicError> {
+    let topic = topic
+        .chars()
+        .filter(|c| !matches!(c, '\r' | '\n' | '\0'))
+        .collect::<String>();
+
+    if let Some(Parameter::TOPICLEN(max_len)) = isupport.get(&Kind::TOPICLEN)
+        && topic.len() > *max_len as usize
+    {
+        return Err(TopicError::TooLong {
+            len: topic.len(),
+            max_len: *max_len,
+        });
+    }
+
+    Ok(topic)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum KeyError {
+    #[error("channel key is empty")]
+    Empty,
+    #[error("channel key is longer than {0} bytes")]
+    TooLong(u16),
+    #[error("channel key contains invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+// Without KEYLEN the length of a key is not limited
+pub fn validate_key(
+    isupport: &HashMap<Kind, Parameter>,
+    key: &str,
+) -> Result<(), KeyError> {
+    if key.is_empty() {
+        return Err(KeyError::Empty);
+    }
+
+    if let Some(Parameter::KEYLEN(len)) = isupport.get(&Kind::KEYLEN)
+        && key.len() > *len as usize
+    {
+        return Err(KeyError::TooLong(*len));
+    }
+
+    if let Some(c) = key
+        .chars()
+        .find(|c| *c == ' ' || *c == ',' || c.is_control())
+    {
+        return Err(KeyError::InvalidCharacter(c));
+    }
+
+    Ok(())
+}
+
+// JOIN commands for the channels in order, chunked by the JOIN target limit,
+// with an empty key slot for each keyless channel followed by a keyed one
+pub fn join_command(
+    isupport: &HashMap<Kind, Parameter>,
+    channels: &[(String, Option<String>)],
+) -> Result<Vec<String>, KeyError> {
+    for key in channels.iter().filter_map(|(_, key)| key.as_deref()) {
+        validate_key(isupport, key)?;
+    }
+
+    let chunk_size = find_target_limit(isupport, "JOIN")
+        .map_or(channels.len(), |limit| limit as usize)
+        .max(1);
+
+    Ok(channels
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chanlist = chunk.iter().map(|(channel, _)| channel).join(",");
+
+            let keyed = chunk
+                .iter()
+                .rposition(|(_, key)| key.is_some())
+                .map_or(0, |position| position + 1);
+
+            if keyed == 0 {
+                format!("JOIN {chanlist}")
+            } else {
+                let chankeys = chunk[..keyed]
+                    .iter()
+                    .map(|(_, key)| key.as_deref().unwrap_or_default())
+                    .join(",");
+
+                format!("JOIN {chanlist} {chankeys}")
+            }
+        })
+        .collect())
+}
+
+// Splits channels into those that fit within the channel limits (see
+// remaining_channel_slots) and those the server would reject.  Accepted
+// channels are returned as comma-separated JOIN target lists, chunked by the
+// JOIN target limit.
+pub fn plan_autojoin(
+    isupport: &HashMap<Kind, Parameter>,
+    channels: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let mut accepted = vec![];
+    let mut rejected = vec![];
+
+    for channel in channels {
+        let has_slot = channel.chars().next().is_some_and(|prefix| {
+            remaining_channel_slots(isupport, &accepted, prefix) != Some(0)
+        });
+
+        if has_slot {
+            accepted.push(channel.clone());
+        } else {
+            rejected.push(channel.clone());
+        }
+    }
+
+    let accepted =
+        if let Some(target_limit) = find_target_limit(isupport, "JOIN") {
+            accepted
+                .chunks(target_limit as usize)
+                .map(|chunk| chunk.join(","))
+                .collect()
+        } else if accepted.is_empty() {
+            vec![]
+        } else {
+            vec![accepted.join(",")]
+        };
+
+    (accepted, rejected)
+}
+
+// Parameters the client acts on when the server advertises them (re-keying
+// state for CASEMAPPING, subscribing for MONITOR, checking BOUNCER_NETID).
+// Overrides are written straight into the table and would bypass that, so
+// they are rejected for these parameters.
+const NON_OVERRIDABLE: &[Kind] =
+    &[Kind::BOUNCER_NETID, Kind::CASEMAPPING, Kind::MONITOR];
+
+#[derive(Debug, thiserror::Error)]
+pub enum OverrideError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("{0:?} cannot be overridden")]
+    NotOverridable(Kind),
+}
+
+pub fn parse_override(token: &str) -> Result<Operation, OverrideError> {
+    let operation = token.parse::<Operation>()?;
+
+    if let Some(kind) = operation.kind()
+        && NON_OVERRIDABLE.contains(&kind)
+    {
+        return Err(OverrideError::NotOverridable(kind));
+    }
+
+    Ok(operation)
+}
+
+// Overrides take precedence over server advertised parameters, which take
+// precedence over defaults.  Only overrides from parse_override should be
+// applied, see NON_OVERRIDABLE.
+pub fn apply_overrides(
+    isupport: &mut HashMap<Kind, Parameter>,
+    overrides: &[Operation],
+) {
+    for operation in overrides {
+        apply(isupport, operation.clone());
+    }
+}
+
+// Request the targets with activity between since and now, with since clamped
+// so the window is never inverted
+pub fn targets_since(
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+    isupport: &HashMap<Kind, Parameter>,
+) -> ChatHistorySubcommand {
+    ChatHistorySubcommand::Targets(
+        MessageReference::Timestamp(std::cmp::min(since, now)),
+        MessageReference::Timestamp(now),
+        get_chathistory_limit_or_default(isupport),
+    )
+}
+
+// Pages through a gap in a target's history with BETWEEN requests, advancing
+// the start of each request to the newest message of the previous page
+#[derive(Clone, Debug)]
+pub struct BackfillPlanner {
+    target: Target,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    cursor: DateTime<Utc>,
+    limit: u16,
+    fetched: usize,
+    exhausted: bool,
+}
+
+impl BackfillPlanner {
+    pub fn new(
+        target: Target,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        isupport: &HashMap<Kind, Parameter>,
+    ) -> Self {
+        Self {
+            target,
+            start,
+            end,
+            cursor: start,
+            limit: get_chathistory_limit_or_default(isupport),
+            fetched: 0,
+            exhausted: start >= end,
+        }
+    }
+
+    pub fn next_request(&self) -> Option<ChatHistorySubcommand> {
+        (!self.exhausted).then(|| {
+            ChatHistorySubcommand::Between(
+                self.target.clone(),
+                MessageReference::Timestamp(self.cursor),
+                MessageReference::Timestamp(self.end),
+                self.limit,
+            )
+        })
+    }
+
+    // A short page (or one without messages) means the gap has been filled.  A
+    // full page that ends at the cursor (i.e. more messages share its timestamp
+    // than fit in a page) cannot move it, so paging continues just past the
+    // cursor instead of repeating the same request.
+    pub fn page_received(
+        &mut self,
+        count: usize,
+        newest: Option<DateTime<Utc>>,
+    ) {
+        self.fetched += count;
+
+        match newest {
+            Some(newest) if count >= self.limit as usize => {
+                let cursor = if newest > self.cursor {
+                    newest
+                } else {
+                    self.cursor + chrono::Duration::milliseconds(1)
+                };
+
+                self.cursor = cursor.min(self.end);
+                self.exhausted = self.cursor >= self.end;
+            }
+            _ => {
+                self.cursor = self.end;
+                self.exhausted = true;
+            }
+        }
+    }
+
+    pub fn fetched(&self) -> usize {
+        self.fetched
+    }
+
+    // Fraction (0.0..=1.0) of the gap covered so far, measured by how far
+    // paging has advanced through the gap's time span
+    pub fn progress(&self) -> Option<f32> {
+        if self.exhausted {
+            return Some(1.0);
+        }
+
+        let width = (self.end - self.start).num_milliseconds();
+        let covered = (self.cursor - self.start).num_milliseconds();
+
+        (width > 0).then(|| (covered as f32 / width as f32).clamp(0.0, 1.0))
+    }
+
+    // Total number of messages in the gap, extrapolated from the messages
+    // fetched over the portion of the gap covered so far
+    pub fn estimated_total(&self) -> Option<usize> {
+        self.progress()
+            .filter(|progress| *progress > 0.0)
+            .map(|progress| (self.fetched as f32 / progress).round() as usize)
+    }
+}
+
+// Upper bound on the messages a full backfill of the gap will fetch at the
+// given message rate, counted in whole pages of the CHATHISTORY limit
+pub fn estimated_backfill_messages(
+    isupport: &HashMap<Kind, Parameter>,
+    gap: chrono::Duration,
+    avg_rate_per_min: f32,
+) -> u32 {
+    let limit = u32::from(get_chathistory_limit_or_default(isupport));
+
+    let minutes = gap.num_milliseconds().max(0) as f32 / 60_000.0;
+    let messages = (minutes * avg_rate_per_min.max(0.0)).ceil() as u32;
+
+    messages.div_ceil(limit).saturating_mul(limit)
+}
+
+// Ban mask matching the given account, using the first ACCOUNTEXTBAN name that
+// the server's EXTBAN supports (named extbans are assumed to be supported,
+// since EXTBAN only lists single letter types)
+// Reference: https://ircv3.net/specs/extensions/account-extban
+pub fn account_extban(
+    isupport: &HashMap<Kind, Parameter>,
+    account: &str,
+) -> Option<String> {
+    let Some(Parameter::EXTBAN(prefix, types)) = isupport.get(&Kind::EXTBAN)
+    else {
+        return None;
+    };
+
+    let Some(Parameter::ACCOUNTEXTBAN(names)) =
+        isupport.get(&Kind::ACCOUNTEXTBAN)
+    else {
+        return None;
+    };
+
+    names
+        .iter()
+        .find(|name| name.len() > 1 || types.contains(name.as_str()))
+        .map(|name| {
+            format!("{}{name}:{account}", prefix.iter().collect::<String>())
+        })
+}
+
+// Maximum length of the tag data a client may send (excluding the leading '@'
+// and trailing space), beyond which servers reject or truncate the line
+// Reference: https://ircv3.net/specs/extensions/message-tags#size-limit
+pub const CLIENT_TAG_DATA_LIMIT: usize = 4094;
+
+// Length of the tags as sent, i.e. with values escaped and joined by ';'
+fn tag_data_len(tags: &[(String, Option<String>)]) -> usize {
+    let len = tags
+        .iter()
+        .map(|(key, value)| match value.as_deref() {
+            Some(value) if !value.is_empty() => {
+                key.len() + 1 + escaped_tag_value_len(value)
+            }
+            _ => key.len(),
+        })
+        .sum::<usize>();
+
+    len + tags.len().saturating_sub(1)
+}
+
+fn escaped_tag_value_len(value: &str) -> usize {
+    value
+        .chars()
+        .map(|c| match c {
+            '\\' | ';' | ' ' | '\r' | '\n' => 2,
+            _ => c.len_utf8(),
+        })
+        .sum()
+}
+
+pub fn tags_within_limit(tags: &[(String, Option<String>)]) -> bool {
+    tag_data_len(tags) <= CLIENT_TAG_DATA_LIMIT
+}
+
+// Whether CLIENTTAGDENY permits sending the client-only tag (given with or
+// without its '+' prefix)
+pub fn client_tag_allowed(
+    isupport: &HashMap<Kind, Parameter>,
+    tag: &str,
+) -> bool {
+    let Some(Parameter::CLIENTTAGDENY(client_only_tags)) =
+        isupport.get(&Kind::CLIENTTAGDENY)
+    else {
+        return true;
+    };
+
+    let tag = tag.strip_prefix('+').unwrap_or(tag);
+
+    let deny_all = client_only_tags.iter().any(|client_only_tag| {
+        matches!(client_only_tag, ClientOnlyTags::DenyAll)
+    });
+
+    if deny_all {
+        client_only_tags
+            .iter()
+            .any(|client_only_tag| match client_only_tag {
+                ClientOnlyTags::Allowed(allowed) => allowed == tag,
+                _ => false,
+            })
+    } else {
+        !client_only_tags
+            .iter()
+            .any(|client_only_tag| match client_only_tag {
+                ClientOnlyTags::Denied(denied) => denied == tag,
+                _ => false,
+            })
+    }
+}
+
+// Drops client-only tags denied by CLIENTTAGDENY, then drops client-only tags
+// from the end (lowest priority first) until the tags fit within the limit
+pub fn fit_client_tags(
+    isupport: &HashMap<Kind, Parameter>,
+    tags: Vec<(String, Option<String>)>,
+) -> Vec<(String, Option<String>)> {
+    let mut tags = tags
+        .into_iter()
+        .filter(|(key, _)| {
+            !key.starts_with('+') || client_tag_allowed(isupport, key)
+        })
+        .collect::<Vec<_>>();
+
+    while !tags_within_limit(&tags) {
+        let Some(index) =
+            tags.iter().rposition(|(key, _)| key.starts_with('+'))
+        else {
+            break;
+        };
+
+        tags.remove(index);
+    }
+
+    tags
+}
+
+// Maximum number of parameter tokens sent in a single RPL_ISUPPORT line
+// Reference: https://modern.ircdocs.horse/#rplisupport-005
+const ISUPPORT_TOKENS_PER_LINE: usize = 13;
+
+// Emits the table as RPL_ISUPPORT lines (as a server would send them), with
+// parameters ordered by Kind for stable output
+pub fn emit_isupport_lines(
+    table: &HashMap<Kind, Parameter>,
+    server: &str,
+    nick: &str,
+) -> Vec<String> {
+    let mut parameters = table.iter().collect::<Vec<_>>();
+
+    parameters.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    parameters
+        .chunks(ISUPPORT_TOKENS_PER_LINE)
+        .map(|parameters| {
+            format!(
+                ":{server} 005 {nick} {} :are supported by this server",
+                parameters
+                    .iter()
+                    .map(|(_, parameter)| parameter.to_string())
+                    .join(" ")
+            )
+        })
+        .collect()
+}
+
+// Parameters shown (in order) by capabilities_summary with their values
+const SUMMARY_PARAMETERS: &[Kind] =
+    &[Kind::NICKLEN, Kind::TOPICLEN, Kind::CHANMODES];
+
+// Parameters shown (in order) by capabilities_summary as a feature name only
+const SUMMARY_FEATURES: &[(Kind, &str)] = &[
+    (Kind::CHATHISTORY, "history"),
+    (Kind::MONITOR, "monitor"),
+    (Kind::WHOX, "whox"),
+    (Kind::UTF8ONLY, "utf8only"),
+];
+
+// Parameters which are unlikely to change between connections to a server
+const STABLE_KINDS: &[Kind] = &[
+    Kind::CASEMAPPING,
+    Kind::CHANMODES,
+    Kind::NETWORK,
+    Kind::NICKLEN,
+    Kind::PREFIX,
+];
+
+// Baseline restored on reconnect until the server re-advertises its
+// parameters, dropping those that may differ (e.g. MONITOR or CHATHISTORY)
+pub fn stable_subset(
+    table: &HashMap<Kind, Parameter>,
+) -> HashMap<Kind, Parameter> {
+    table
+        .iter()
+        .filter(|(kind, _)| STABLE_KINDS.contains(kind))
+        .map(|(kind, parameter)| (kind.clone(), parameter.clone()))
+        .collect()
+}
+
+// Parameters advertised before a reconnect that the server no longer
+// advertises (e.g. CHATHISTORY), so the loss of a feature can be surfaced
+pub fn regressions(
+    old: &HashMap<Kind, Parameter>,
+    new: &HashMap<Kind, Parameter>,
+) -> Vec<Kind> {
+    old.keys()
+        .filter(|kind| !new.contains_key(kind))
+        .cloned()
+        .sorted()
+        .collect()
+}
+
+// Hash of the parameters rendered as tokens in Kind order, so it is stable
+// across runs and independent of the order parameters were advertised in
+// (e.g. for caching, or recognizing a network's servers share a feature set)
+pub fn fingerprint(isupport: &HashMap<Kind, Parameter>) -> u64 {
+    let canonical = isupport
+        .iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, parameter)| parameter.to_string())
+        .join(" ");
+
+    seahash::hash(canonical.as_bytes())
+}
+
+// Condensed, human-facing summary of the most relevant parameters (e.g. for
+// a status bar), omitting any the server did not advertise
+pub fn capabilities_summary(isupport: &HashMap<Kind, Parameter>) -> String {
+    SUMMARY_PARAMETERS
+        .iter()
+        .filter_map(|kind| isupport.get(kind))
+        .map(|parameter| parameter.to_string().replacen('=', " ", 1))
+        .chain(
+            SUMMARY_FEATURES
+                .iter()
+                .filter(|(kind, _)| isupport.contains_key(kind))
+                .map(|(_, name)| (*name).to_string()),
+        )
+        .join(" · ")
+}
+
+// Number of parameters the server advertised (e.g. for a badge)
+pub fn feature_count(isupport: &HashMap<Kind, Parameter>) -> usize {
+    isupport.len()
+}
+
+pub fn has(isupport: &HashMap<Kind, Parameter>, kind: Kind) -> bool {
+    isupport.contains_key(&kind)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FinalizeWarning {
+    #[error(
+        "CHANTYPES and STATUSMSG overlap ({}), overlapping characters are only treated as STATUSMSG when followed by a CHANTYPES character",
+        .0.iter().collect::<String>()
+    )]
+    ChantypesStatusmsgOverlap(Vec<char>),
+    #[error(
+        "CHANMODES lists PREFIX modes ({}), which are removed from CHANMODES",
+        .0.iter().collect::<String>()
+    )]
+    ChanmodesPrefixOverlap(Vec<char>),
+    #[error(
+        "STATUSMSG lists characters not in PREFIX ({}), which are removed from STATUSMSG",
+        .0.iter().collect::<String>()
+    )]
+    StatusmsgNotInPrefix(Vec<char>),
+}
+
+// Resets the parameters finalize validates to their values as received, so
+// that finalize can be re-run whenever one of them changes
+pub fn restore_received(
+    isupport: &mut HashMap<Kind, Parameter>,
+    received: &HashMap<Kind, Parameter>,
+) {
+    for kind in FINALIZE_DEPENDENCIES {
+        if let Some(parameter) = received.get(kind) {
+            isupport.insert(kind.clone(), parameter.clone());
+        }
+    }
+}
+
+// Consistency checks for parameters which depend on each other, and so can
+// only be made once they have been received.  Warnings describe each
+// inconsistency found and how it is resolved.
+pub fn finalize(
+    isupport: &mut HashMap<Kind, Parameter>,
+) -> Vec<FinalizeWarning> {
+    let mut warnings = vec![];
+
+    // STATUSMSG may only contain membership prefixes
+    let prefixes = get_prefix_or_default(isupport)
+        .iter()
+        .map(|prefix_map| prefix_map.prefix)
+        .collect::<Vec<_>>();
+
+    if let Some(Parameter::STATUSMSG(statusmsg)) =
+        isupport.get_mut(&Kind::STATUSMSG)
+    {
+        let unknown = statusmsg
+            .iter()
+            .filter(|c| !prefixes.contains(c))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if !unknown.is_empty() {
+            statusmsg.retain(|c| prefixes.contains(c));
+
+            warnings.push(FinalizeWarning::StatusmsgNotInPrefix(unknown));
+        }
+    }
+
+    // Resolved when parsing targets (see proto::parse_channel_from_target)
+    let chantypes = get_chantypes_or_default(isupport);
+    let overlap = get_statusmsg_or_default(isupport)
+        .iter()
+        .filter(|c| chantypes.contains(c))
+        .copied()
+        .collect::<Vec<_>>();
+
+    if !overlap.is_empty() {
+        warnings.push(FinalizeWarning::ChantypesStatusmsgOverlap(overlap));
+    }
+
+    // PREFIX modes are authoritative, so remove them from any CHANMODES type
+    let prefix_modes = get_prefix_or_default(isupport)
+        .iter()
+        .map(|prefix_map| prefix_map.mode)
+        .collect::<Vec<_>>();
+
+    if let Some(Parameter::CHANMODES(chanmodes)) =
+        isupport.get_mut(&Kind::CHANMODES)
+    {
+        let mut overlap = vec![];
+
+        for chanmode in chanmodes.iter_mut() {
+            if chanmode.modes.contains(prefix_modes.as_slice()) {
+                overlap.extend(
+                    chanmode.modes.chars().filter(|c| prefix_modes.contains(c)),
+                );
+
+                chanmode.modes = Cow::Owned(
+                    chanmode
+                        .modes
+                        .chars()
+                        .filter(|c| !prefix_modes.contains(c))
+                        .collect(),
+                );
+            }
+        }
+
+        if !overlap.is_empty() {
+            warnings.push(FinalizeWarning::ChanmodesPrefixOverlap(overlap));
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn isupport(tokens: &[&str]) -> HashMap<Kind, Parameter> {
+        tokens
+            .iter()
+            .filter_map(|token| match token.parse::<Operation>() {
+                Ok(Operation::Add(parameter)) => {
+                    parameter.kind().map(|kind| (kind, parameter))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prefix_legend_default() {
+        let isupport = HashMap::new();
+
+        assert_eq!(
+            prefix_legend(&isupport),
+            vec![('~', 'q'), ('&', 'a'), ('@', 'o'), ('%', 'h'), ('+', 'v')]
+        );
+    }
+
+    #[test]
+    fn prefix_legend_custom() {
+        let isupport = isupport(&["PREFIX=(ov)@+"]);
+
+        assert_eq!(prefix_legend(&isupport), vec![('@', 'o'), ('+', 'v')]);
+    }
+
+    #[test]
+    fn prefix_chars_default() {
+        let isupport = HashMap::new();
+
+        assert_eq!(prefix_chars(&isupport), vec!['~', '&', '@', '%', '+']);
+    }
+
+    #[test]
+    fn prefix_chars_custom() {
+        let isupport = isupport(&["PREFIX=(Yov)!@+"]);
+
+        assert_eq!(prefix_chars(&isupport), vec!['!', '@', '+']);
+    }
+
+    #[test]
+    fn command_applies_to_target() {
+        let isupport = isupport(&["CHANTYPES=#"]);
+
+        assert!(command_applies(&isupport, "TOPIC", "#halloy"));
+        assert!(!command_applies(&isupport, "topic", "casperstorm"));
+        assert!(command_applies(&isupport, "WHOIS", "casperstorm"));
+        assert!(!command_applies(&isupport, "WHOIS", "#halloy"));
+        assert!(command_applies(&isupport, "PRIVMSG", "#halloy"));
+        assert!(command_applies(&isupport, "PRIVMSG", "casperstorm"));
+
+        // & is not a channel type here, so &halloy is a nick
+        assert!(!command_applies(&isupport, "KICK", "&halloy"));
+    }
+
+    #[test]
+    fn fuzz_window_shrinks_after_full_page() {
+        let mut fuzz_window = FuzzWindow::default();
+
+        fuzz_window.update(100, 100);
+        assert!(fuzz_window.duration() < FUZZ_SECONDS);
+
+        for _ in 0..10 {
+            fuzz_window.update(100, 100);
+        }
+        assert_eq!(fuzz_window.duration(), MIN_FUZZ_SECONDS);
+    }
+
+    #[test]
+    fn fuzz_window_grows_after_short_page() {
+        let mut fuzz_window = FuzzWindow::default();
+
+        fuzz_window.update(10, 100);
+        assert!(fuzz_window.duration() > FUZZ_SECONDS);
+
+        for _ in 0..10 {
+            fuzz_window.update(10, 100);
+        }
+        assert_eq!(fuzz_window.duration(), MAX_FUZZ_SECONDS);
+    }
+
+    #[test]
+    fn fuzz_range_uses_window() {
+        let start = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        let end = DateTime::from_timestamp(1_000_100, 0).unwrap();
+
+        let mut fuzz_window = FuzzWindow::default();
+        fuzz_window.update(100, 100);
+
+        assert_eq!(
+            fuzz_message_reference_range(
+                MessageReference::Timestamp(start),
+                MessageReference::Timestamp(end),
+                fuzz_window,
+                false,
+            ),
+            (
+                MessageReference::Timestamp(start - fuzz_window.duration()),
+                MessageReference::Timestamp(end + fuzz_window.duration()),
+            )
+        );
+    }
+
+    #[test]
+    fn next_nick_candidate_within_nicklen() {
+        let isupport = isupport(&["NICKLEN=9"]);
+
+        assert_eq!(next_nick_candidate(&isupport, "halloy", 0), "halloy_");
+        assert_eq!(next_nick_candidate(&isupport, "halloy", 2), "halloy2");
+        assert_eq!(next_nick_candidate(&isupport, "halloyirc", 0), "halloyir_");
+        assert_eq!(
+            next_nick_candidate(&isupport, "halloyirc", 12),
+            "halloyi12"
+        );
+    }
+
+    #[test]
+    fn next_nick_candidate_default_nicklen() {
+        let isupport = HashMap::new();
+
+        let candidate = next_nick_candidate(&isupport, "averylongnick", 3);
+
+        assert_eq!(candidate, "averylon3");
+        assert_eq!(candidate.len(), DEFAULT_NICKLEN as usize);
+    }
+
+    #[test]
+    fn next_nick_candidate_multibyte() {
+        let isupport = isupport(&["NICKLEN=10"]);
+
+        // 9 bytes remain for the base, which would split the fifth ñ
+        let candidate = next_nick_candidate(&isupport, "ñññññ", 0);
+
+        assert_eq!(candidate, "ññññ_");
+        assert!(candidate.len() <= 10);
+
+        assert_eq!(next_nick_candidate(&isupport, "ñññññ", 7), "ññññ7");
+    }
+
+    #[test]
+    fn safe_channel_prefix() {
+        let isupport = isupport(&["CHANTYPES=#!"]);
+        let safe = DEFAULT_SAFE_CHANNEL_PREFIXES;
+
+        assert!(is_safe_channel_prefix(&isupport, safe, '!'));
+        assert!(!is_safe_channel_prefix(&isupport, safe, '#'));
+
+        assert_eq!(
+            safe_channel_creation_prefix(&isupport, safe, "!!halloy"),
+            Some('!')
+        );
+        assert_eq!(
+            safe_channel_creation_prefix(&isupport, safe, "!ABCDEhalloy"),
+            None
+        );
+        assert_eq!(
+            safe_channel_creation_prefix(&isupport, safe, "##halloy"),
+            None
+        );
+    }
+
+    #[test]
+    fn safe_channel_prefix_configured() {
+        let isupport = isupport(&["CHANTYPES=#!+"]);
+        let safe = &['+'];
+
+        assert!(is_safe_channel_prefix(&isupport, safe, '+'));
+        assert!(!is_safe_channel_prefix(&isupport, safe, '!'));
+        assert_eq!(
+            safe_channel_creation_prefix(&isupport, safe, "++halloy"),
+            Some('+')
+        );
+        assert_eq!(
+            safe_channel_creation_prefix(&isupport, safe, "!!halloy"),
+            None
+        );
+    }
+
+    #[test]
+    fn safe_channel_prefix_not_in_chantypes() {
+        let isupport = HashMap::new();
+        let safe = DEFAULT_SAFE_CHANNEL_PREFIXES;
+
+        assert!(!is_safe_channel_prefix(&isupport, safe, '!'));
+        assert_eq!(
+            safe_channel_creation_prefix(&isupport, safe, "!!halloy"),
+            None
+        );
+    }
+
+    #[test]
+    fn merge_prefers_incoming() {
+        let mut base = isupport(&["NICKLEN=16", "CHANTYPES=#", "WHOX"]);
+
+        let incoming = isupport(&["NICKLEN=30", "CHANTYPES=#&"]);
+
+        merge(&mut base, incoming);
+
+        assert_eq!(get_nicklen_or_default(&base), 30);
+        assert_eq!(get_chantypes_or_default(&base), &['#', '&']);
+        assert!(base.contains_key(&Kind::WHOX));
+        assert_eq!(base.len(), 3);
+    }
+
+    #[test]
+    fn should_warn_version_fails_open() {
+        let client = (3, 2);
+
+        let absent = HashMap::new();
+        assert_eq!(clientver_compatible(&absent, client), None);
+        assert!(!should_warn_version(&absent, client));
+
+        let equal = isupport(&["CLIENTVER=3.2"]);
+        assert_eq!(clientver_compatible(&equal, client), Some(true));
+        assert!(!should_warn_version(&equal, client));
+
+        let older = isupport(&["CLIENTVER=3.1"]);
+        assert_eq!(clientver_compatible(&older, client), Some(true));
+        assert!(!should_warn_version(&older, client));
+
+        let newer = isupport(&["CLIENTVER=4.0"]);
+        assert_eq!(clientver_compatible(&newer, client), Some(false));
+        assert!(should_warn_version(&newer, client));
+    }
+
+    #[test]
+    fn operation_parse_errors() {
+        assert_eq!(
+            "".parse::<Operation>().unwrap_err(),
+            ParseError::EmptyToken
+        );
+        assert_eq!(
+            "EXAMPLE=1".parse::<Operation>().unwrap_err(),
+            ParseError::UnknownParameter("EXAMPLE".to_string())
+        );
+        assert_eq!(
+            "EXAMPLE".parse::<Operation>().unwrap_err(),
+            ParseError::UnknownParameter("EXAMPLE".to_string())
+        );
+        assert_eq!(
+            "CASEMAPPING=unknown".parse::<Operation>().unwrap_err(),
+            ParseError::Malformed {
+                parameter: "CASEMAPPING".to_string(),
+                reason: "unknown casemapping",
+            }
+        );
+    }
+
+    #[test]
+    fn operation_parse_errors_missing_value() {
+        for token in ["NICKLEN", "CHANLIMIT", "ELIST=", "VLIST=", "BOT="] {
+            let parameter = token.trim_end_matches('=').to_string();
+
+            assert_eq!(
+                token.parse::<Operation>().unwrap_err(),
+                ParseError::MissingValue { parameter },
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn operation_parse_errors_invalid_integer() {
+        assert_eq!(
+            "NICKLEN=abc".parse::<Operation>().unwrap_err(),
+            ParseError::InvalidInteger {
+                parameter: "NICKLEN".to_string(),
+                value: "abc".to_string(),
+            }
+        );
+        assert_eq!(
+            "MONITOR=-1".parse::<Operation>().unwrap_err(),
+            ParseError::InvalidInteger {
+                parameter: "MONITOR".to_string(),
+                value: "-1".to_string(),
+            }
+        );
+        assert_eq!(
+            "NICKLEN=abc".parse::<Operation>().unwrap_err().to_string(),
+            "NICKLEN: value required to be a positive integer, got \"abc\""
+        );
+    }
+
+    #[test]
+    fn who_token_parse_error() {
+        assert_eq!(
+            "1234".parse::<WhoToken>(),
+            Err(ParseError::Malformed {
+                parameter: "1234".to_string(),
+                reason: "WHO token must be 1-3 ASCII digits",
+            })
+        );
+    }
+
+    #[test]
+    fn required_capabilities_for_kind() {
+        assert_eq!(
+            required_capabilities(Kind::CHATHISTORY),
+            &["batch", "draft/chathistory", "server-time"]
+        );
+        assert!(required_capabilities(Kind::MONITOR).is_empty());
+    }
+
+    #[test]
+    fn plan_autojoin_exceeding_chanlimit() {
+        let isupport = isupport(&["CHANLIMIT=#:2,&:", "TARGMAX=JOIN:2"]);
+
+        let channels = ["#a", "&b", "#c", "#d", "&e", "#f"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        let (accepted, rejected) = plan_autojoin(&isupport, &channels);
+
+        assert_eq!(accepted, vec!["#a,&b", "#c,&e"]);
+        assert_eq!(rejected, vec!["#d", "#f"]);
+    }
+
+    #[test]
+    fn plan_autojoin_maxchannels() {
+        let isupport = isupport(&["MAXCHANNELS=2"]);
+
+        let channels = ["#a", "&b", "#c"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        let (accepted, rejected) = plan_autojoin(&isupport, &channels);
+
+        assert_eq!(accepted, vec!["#a,&b"]);
+        assert_eq!(rejected, vec!["#c"]);
+    }
+
+    #[test]
+    fn overrides_persist_across_server_update() {
+        let overrides = ["NICKLEN=30", "-UTF8ONLY"]
+            .into_iter()
+            .map(|token| token.parse::<Operation>().unwrap())
+            .collect::<Vec<_>>();
+
+        let update = isupport(&["NICKLEN=9", "UTF8ONLY"]);
+
+        let mut isupport = isupport(&["NICKLEN=16", "UTF8ONLY"]);
+        apply_overrides(&mut isupport, &overrides);
+
+        assert_eq!(get_nicklen_or_default(&isupport), 30);
+        assert!(!isupport.contains_key(&Kind::UTF8ONLY));
+
+        merge(&mut isupport, update);
+        apply_overrides(&mut isupport, &overrides);
+
+        assert_eq!(get_nicklen_or_default(&isupport), 30);
+        assert!(!isupport.contains_key(&Kind::UTF8ONLY));
+    }
+
+    #[test]
+    fn overrides_with_side_effects_rejected() {
+        assert!(matches!(
+            parse_override("NICKLEN=30"),
+            Ok(Operation::Add(Parameter::NICKLEN(30)))
+        ));
+        assert!(matches!(
+            parse_override("CASEMAPPING=ascii"),
+            Err(OverrideError::NotOverridable(Kind::CASEMAPPING))
+        ));
+        assert!(matches!(
+            parse_override("-MONITOR"),
+            Err(OverrideError::NotOverridable(Kind::MONITOR))
+        ));
+        assert!(matches!(
+            parse_override("NICKLEN=abc"),
+            Err(OverrideError::Parse(ParseError::InvalidInteger { .. }))
+        ));
+    }
+
+    #[test]
+    fn targets_since_references_and_limit() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let since = now - chrono::Duration::hours(24);
+
+        assert_eq!(
+            targets_since(since, now, &isupport(&["CHATHISTORY=100"])),
+            ChatHistorySubcommand::Targets(
+                MessageReference::Timestamp(since),
+                MessageReference::Timestamp(now),
+                100,
+            )
+        );
+        assert_eq!(
+            targets_since(since, now, &isupport(&["CHATHISTORY=1000"])),
+            ChatHistorySubcommand::Targets(
+                MessageReference::Timestamp(since),
+                MessageReference::Timestamp(now),
+                CLIENT_CHATHISTORY_LIMIT,
+            )
+        );
+        assert_eq!(
+            targets_since(
+                now + chrono::Duration::hours(1),
+                now,
+                &HashMap::new()
+            ),
+            ChatHistorySubcommand::Targets(
+                MessageReference::Timestamp(now),
+                MessageReference::Timestamp(now),
+                CLIENT_CHATHISTORY_LIMIT,
+            )
+        );
+    }
+
+    #[test]
+    fn finalize_chantypes_statusmsg_overlap() {
+        let mut isupport = isupport(&["CHANTYPES=#+", "STATUSMSG=@+"]);
+
+        assert_eq!(
+            finalize(&mut isupport),
+            vec![FinalizeWarning::ChantypesStatusmsgOverlap(vec!['+'])]
+        );
+
+        let mut isupport = isupport(&["CHANTYPES=#", "STATUSMSG=@+"]);
+
+        assert!(finalize(&mut isupport).is_empty());
+    }
+
+    #[test]
+    fn finalize_statusmsg_not_in_prefix() {
+        let mut isupport = isupport(&["PREFIX=(ov)@+", "STATUSMSG=~@+"]);
+
+        assert_eq!(
+            finalize(&mut isupport),
+            vec![FinalizeWarning::StatusmsgNotInPrefix(vec!['~'])]
+        );
+        assert_eq!(get_statusmsg_or_default(&isupport), &['@', '+']);
+        assert!(finalize(&mut isupport).is_empty());
+    }
+
+    #[test]
+    fn server_features_revalidate_in_any_order() {
+        let tokens = [
+            "CHANTYPES=#",
+            "CHANMODES=beIq,k,l,imnpst",
+            "STATUSMSG=~@+",
+            "PREFIX=(ov)@+",
+        ];
+
+        let mut forward = ServerFeatures::default();
+        let mut reversed = ServerFeatures::default();
+
+        for token in tokens {
+            forward.apply_token(token).unwrap();
+        }
+        for token in tokens.iter().rev() {
+            reversed.apply_token(token).unwrap();
+        }
+
+        for features in [&forward, &reversed] {
+            // The default PREFIX accepted ~ and claimed q, until the
+            // advertised PREFIX arrived
+            assert_eq!(features.isupport.statusmsg(), &['@', '+']);
+            assert_eq!(
+                features.isupport[&Kind::CHANMODES].to_string(),
+                "CHANMODES=beIq,k,l,imnpst"
+            );
+            assert_eq!(
+                features.warnings,
+                vec![FinalizeWarning::StatusmsgNotInPrefix(vec!['~'])]
+            );
+        }
+
+        // Removing PREFIX reverts to validating against the default
+        forward.apply_token("-PREFIX").unwrap();
+
+        assert_eq!(forward.isupport.statusmsg(), &['~', '@', '+']);
+        assert_eq!(
+            forward.warnings,
+            vec![FinalizeWarning::ChanmodesPrefixOverlap(vec!['q'])]
+        );
+    }
+
+    #[test]
+    fn kinds_sort_alphabetically() {
+        let mut kinds = vec![
+            Kind::WHOX,
+            Kind::CHANTYPES,
+            Kind::AWAYLEN,
+            Kind::NICKLEN,
+            Kind::BOUNCER_NETID,
+            Kind::CHANLIMIT,
+        ];
+
+        kinds.sort();
+
+        assert_eq!(
+            kinds.iter().map(Kind::as_str).collect::<Vec<_>>(),
+            vec![
+                "AWAYLEN",
+                "BOUNCER_NETID",
+                "CHANLIMIT",
+                "CHANTYPES",
+                "NICKLEN",
+                "WHOX",
+            ]
+        );
+    }
+
+    #[test]
+    fn emitted_lines_round_trip() {
+        let table = isupport(&[
+            "AWAYLEN=200",
+            "CASEMAPPING=rfc1459",
+            "CHANLIMIT=#:25,&:",
+            "CHANMODES=beI,k,l,imnpst",
+            "CHANNELLEN=64",
+            "CHANTYPES=#&",
+            "CHATHISTORY=100",
+            "CLIENTVER=3.0",
+            "CNOTICE",
+            "CPRIVMSG",
+            "ELIST=CMNTU",
+            "KEYLEN=32",
+            "KICKLEN=255",
+            "KNOCK",
+            "MAXCHANNELS=50",
+            "MODES",
+            "MONITOR=100",
+            "MSGREFTYPES=msgid,timestamp",
+            "NAMELEN=128",
+            "NICKLEN=30",
+            "PREFIX=(ov)@+",
+            "SAFELIST",
+            "STATUSMSG=@+",
+            // An empty table, which is emitted without a value
+            "TARGMAX",
+            "TOPICLEN=390",
+            "USERIP",
+            "UTF8ONLY",
+            "WHOX",
+        ]);
+
+        let lines = emit_isupport_lines(&table, "irc.example.org", "halloy");
+
+        assert_eq!(lines.len(), 3);
+
+        let mut parsed = HashMap::new();
+
+        for line in &lines {
+            let message =
+                proto::parse::message(&format!("{line}\r\n")).unwrap();
+
+            let proto::Command::Numeric(
+                proto::command::Numeric::RPL_ISUPPORT,
+                args,
+            ) = message.command
+            else {
+                panic!("expected RPL_ISUPPORT, got {line}");
+            };
+
+            assert_eq!(args.first().map(String::as_str), Some("halloy"));
+            assert!(args.len() - 2 <= ISUPPORT_TOKENS_PER_LINE);
+
+            for arg in &args[1..args.len() - 1] {
+                if let Ok(Operation::Add(parameter)) = arg.parse::<Operation>()
+                    && let Some(kind) = parameter.kind()
+                {
+                    parsed.insert(kind, parameter);
+                }
+            }
+        }
+
+        assert_eq!(parsed.len(), table.len());
+
+        for (kind, parameter) in &table {
+            assert_eq!(
+                parsed.get(kind).map(ToString::to_string),
+                Some(parameter.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn chanlimit_grouped_prefixes() {
+        let isupport = isupport(&["CHANLIMIT=#&:3,+:"]);
+
+        let Some(Parameter::CHANLIMIT(channel_limits)) =
+            isupport.get(&Kind::CHANLIMIT)
+        else {
+            panic!("expected CHANLIMIT");
+        };
+
+        assert_eq!(channel_limits.len(), 2);
+        assert_eq!(channel_limits[0].prefixes, "#&");
+        assert_eq!(channel_limits[0].limit, Some(3));
+        assert_eq!(channel_limits[1].prefixes, "+");
+        assert_eq!(channel_limits[1].limit, None);
+
+        let joined = ["#a", "&b"].map(String::from);
+
+        assert_eq!(remaining_channel_slots(&isupport, &joined, '#'), Some(1));
+        assert_eq!(remaining_channel_slots(&isupport, &joined, '&'), Some(1));
+        assert_eq!(remaining_channel_slots(&isupport, &joined, '+'), None);
+
+        let joined = ["#a", "&b", "&c", "+d"].map(String::from);
+
+        assert_eq!(remaining_channel_slots(&isupport, &joined, '#'), Some(0));
+        assert_eq!(remaining_channel_slots(&isupport, &joined, '&'), Some(0));
+    }
+
+    #[test]
+    fn get_channel_limit_by_prefix() {
+        let isupport = isupport(&["CHANLIMIT=#:10,&:"]);
+
+        assert_eq!(get_channel_limit(&isupport, '#'), Some(Some(10)));
+        assert_eq!(get_channel_limit(&isupport, '&'), Some(None));
+        assert_eq!(get_channel_limit(&isupport, '+'), None);
+
+        assert_eq!(get_channel_limit(&HashMap::new(), '#'), None);
+    }
+
+    #[test]
+    fn plan_autojoin_grouped_chanlimit() {
+        let isupport = isupport(&["CHANLIMIT=#&:3"]);
+
+        let channels = ["#a", "&b", "#c", "&d", "#e"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        let (accepted, rejected) = plan_autojoin(&isupport, &channels);
+
+        assert_eq!(accepted, vec!["#a,&b,#c"]);
+        assert_eq!(rejected, vec!["&d", "#e"]);
+    }
+
+    #[test]
+    fn chathistory_targets_has_no_target() {
+        let now = Utc::now();
+
+        let targets = targets_since(
+            now - chrono::Duration::hours(1),
+            now,
+            &HashMap::new(),
+        );
+
+        assert!(targets.is_targets());
+        assert_eq!(targets.target(), None);
+
+        let latest = ChatHistorySubcommand::Latest(
+            Target::parse("#halloy", &['#'], &[], CaseMap::default()),
+            MessageReference::None,
+            100,
+        );
+
+        assert!(!latest.is_targets());
+        assert_eq!(latest.target(), Some("#halloy"));
+    }
+
+    #[test]
+    fn list_mode_limit_grouped_modes() {
+        let isupport = isupport(&["MAXLIST=beI:100,q:50"]);
+
+        assert_eq!(list_mode_limit(&isupport, 'b'), Some(100));
+        assert_eq!(list_mode_limit(&isupport, 'e'), Some(100));
+        assert_eq!(list_mode_limit(&isupport, 'I'), Some(100));
+        assert_eq!(list_mode_limit(&isupport, 'q'), Some(50));
+        assert_eq!(list_mode_limit(&isupport, 'k'), None);
+        assert_eq!(list_mode_limit(&HashMap::new(), 'b'), None);
+    }
+
+    #[test]
+    fn default_channel_modes_from_chanmodes() {
+        assert_eq!(
+            default_channel_modes(&HashMap::new()),
+            vec!['i', 'm', 's', 't', 'n']
+        );
+
+        let isupport = isupport(&["CHANMODES=beI,k,l,CMnt"]);
+
+        assert_eq!(default_channel_modes(&isupport), vec!['C', 'M', 'n', 't']);
+    }
+
+    #[test]
+    fn reject_oversized_token() {
+        let network = format!("NETWORK={}", "a".repeat(MAX_TOKEN_LENGTH));
+
+        assert_eq!(
+            network.parse::<Operation>().unwrap_err(),
+            ParseError::TooLong(network.len())
+        );
+
+        let chanmodes = format!("CHANMODES=b,k,l,{}", "n".repeat(1024));
+
+        assert_eq!(
+            chanmodes.parse::<Operation>().unwrap_err(),
+            ParseError::TooLong(chanmodes.len())
+        );
+
+        let network = format!("NETWORK={}", "a".repeat(64));
+
+        assert!(network.parse::<Operation>().is_ok());
+    }
+
+    #[test]
+    fn server_features_delegates() {
+        let mut features = ServerFeatures {
+            isupport: isupport(&[
+                "CHATHISTORY=100",
+                "NICKLEN=16",
+                "STATUSMSG=@",
+            ])
+            .into(),
+            caps: HashSet::from([
+                "batch".to_string(),
+                "server-time".to_string(),
+            ]),
+            complete: true,
+            ..ServerFeatures::default()
+        };
+
+        assert_eq!(features.isupport.nicklen(), 16);
+        assert_eq!(features.isupport.chathistory_limit(), 100);
+        assert_eq!(features.isupport.statusmsg(), &['@']);
+        assert!(!features.supports_chathistory());
+
+        features.caps.insert("draft/chathistory".to_string());
+
+        assert!(features.supports_chathistory());
+        assert!(!features.supports(Kind::WHOX));
+
+        let features = ServerFeatures::default();
+
+        assert_eq!(features.isupport.nicklen(), DEFAULT_NICKLEN);
+        assert!(!features.supports_chathistory());
+        assert!(!features.complete);
+    }
+
+    #[test]
+    fn whox_poll_token_matching() {
+        let default = "9".parse::<WhoToken>().unwrap();
+        let with_account_name = "99".parse::<WhoToken>().unwrap();
+        let other = "999".parse::<WhoToken>().unwrap();
+
+        assert!(WhoXPollParameters::Default.matches_token(&default));
+        assert!(!WhoXPollParameters::Default.matches_token(&with_account_name));
+        assert!(!WhoXPollParameters::Default.matches_token(&other));
+
+        assert!(
+            WhoXPollParameters::WithAccountName
+                .matches_token(&with_account_name)
+        );
+        assert!(!WhoXPollParameters::WithAccountName.matches_token(&default));
+        assert!(!WhoXPollParameters::WithAccountName.matches_token(&other));
+    }
+
+    #[test]
+    fn resolve_safe_channel_rename() {
+        assert_eq!(
+            resolve_safe_channel("!!halloy", "!AB12Challoy"),
+            Some(("!!halloy".to_string(), "!AB12Challoy".to_string()))
+        );
+        assert_eq!(
+            resolve_safe_channel("!halloy", "!AB12CHalloy"),
+            Some(("!halloy".to_string(), "!AB12CHalloy".to_string()))
+        );
+        assert_eq!(resolve_safe_channel("!AB12Challoy", "!AB12Challoy"), None);
+        assert_eq!(resolve_safe_channel("!!halloy", "!AB12Cother"), None);
+        assert_eq!(resolve_safe_channel("#halloy", "#AB12Challoy"), None);
+        assert_eq!(resolve_safe_channel("!!halloy", "!ab12challoy"), None);
+        assert_eq!(resolve_safe_channel("++halloy", "+AB12Challoy"), None);
+    }
+
+    #[test]
+    fn whox_record_parse() {
+        let isupport = HashMap::new();
+        let args = |args: &[&str]| {
+            args.iter().map(ToString::to_string).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            WhoxRecord::parse(
+                &args(&["me", "99", "#halloy", "dan", "G", "dan_"]),
+                &isupport
+            ),
+            Some(WhoxRecord {
+                token: Some("99".parse().unwrap()),
+                channel: "#halloy".to_string(),
+                user: None,
+                host: None,
+                server: None,
+                nick: "dan".to_string(),
+                flags: WhoFlags {
+                    away: Some(true),
+                    oper: false,
+                    prefixes: vec![],
+                },
+                account: Some("dan_".to_string()),
+                realname: None,
+            })
+        );
+        assert_eq!(
+            WhoxRecord::parse(
+                &args(&["me", "99", "#halloy", "dan", "H", "0"]),
+                &isupport
+            )
+            .and_then(|record| record.account),
+            None
+        );
+        assert_eq!(
+            WhoxRecord::parse(
+                &args(&["me", "9", "#halloy", "dan", "H"]),
+                &isupport
+            )
+            .map(|record| (record.nick, record.account)),
+            Some(("dan".to_string(), None))
+        );
+        assert_eq!(
+            WhoxRecord::parse(
+                &args(&["me", "1", "#halloy", "dan", "H"]),
+                &isupport
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_requirements_for_features() {
+        let isupport = isupport(&["CHATHISTORY=100", "WATCH=128"]);
+
+        assert!(
+            missing_requirements(&isupport, Feature::Scrollback).is_empty()
+        );
+        assert!(missing_requirements(&isupport, Feature::Presence).is_empty());
+        assert_eq!(
+            missing_requirements(&isupport, Feature::MassOp),
+            vec![Kind::MODES]
+        );
+
+        let isupport = isupport(&["MODES=4"]);
+
+        assert_eq!(
+            missing_requirements(&isupport, Feature::Presence),
+            vec![Kind::MONITOR, Kind::WATCH]
+        );
+        assert_eq!(
+            missing_requirements(&isupport, Feature::Scrollback),
+            vec![Kind::CHATHISTORY]
+        );
+        assert!(missing_requirements(&isupport, Feature::MassOp).is_empty());
+    }
+
+    #[test]
+    fn finalize_chanmodes_prefix_overlap() {
+        let mut isupport =
+            isupport(&["PREFIX=(ov)@+", "CHANMODES=beI,k,l,imnot"]);
+
+        assert_eq!(
+            finalize(&mut isupport),
+            vec![FinalizeWarning::ChanmodesPrefixOverlap(vec!['o'])]
+        );
+        assert_eq!(
+            get_chanmodes_or_default(&isupport)
+                .iter()
+                .map(|chanmode| chanmode.modes.as_ref())
+                .collect::<Vec<_>>(),
+            vec!["beI", "k", "l", "imnt"]
+        );
+        assert!(finalize(&mut isupport).is_empty());
+    }
+
+    #[test]
+    fn backfill_progress_is_monotonic() {
+        let end = Utc::now();
+        let start = end - chrono::Duration::hours(4);
+
+        let mut backfill = BackfillPlanner::new(
+            Target::parse("#halloy", &['#'], &[], CaseMap::default()),
+            start,
+            end,
+            &isupport(&["CHATHISTORY=100"]),
+        );
+
+        assert_eq!(backfill.progress(), Some(0.0));
+        assert_eq!(backfill.estimated_total(), None);
+
+        let mut progress = 0.0;
+
+        for hour in 1..4 {
+            assert!(backfill.next_request().is_some());
+
+            backfill.page_received(
+                100,
+                Some(start + chrono::Duration::hours(hour)),
+            );
+
+            let next = backfill.progress().unwrap();
+
+            assert!(next > progress);
+            assert!(next < 1.0);
+
+            progress = next;
+        }
+
+        assert_eq!(backfill.estimated_total(), Some(400));
+
+        backfill.page_received(20, Some(end));
+
+        assert_eq!(backfill.progress(), Some(1.0));
+        assert_eq!(backfill.fetched(), 320);
+        assert!(backfill.next_request().is_none());
+    }
+
+    #[test]
+    fn backfill_advances_past_full_page_at_cursor() {
+        let start = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        let end = start + chrono::Duration::seconds(1);
+
+        let mut backfill = BackfillPlanner::new(
+            Target::parse("#halloy", &['#'], &[], CaseMap::default()),
+            start,
+            end,
+            &isupport(&["CHATHISTORY=100"]),
+        );
+
+        // Every message of the page has the cursor's timestamp
+        backfill.page_received(100, Some(start));
+
+        assert!(matches!(
+            backfill.next_request(),
+            Some(ChatHistorySubcommand::Between(
+                _,
+                MessageReference::Timestamp(cursor),
+                _,
+                100,
+            )) if cursor > start
+        ));
+
+        // Paging stops once the cursor can't advance within the gap
+        for _ in 0..1_000 {
+            backfill.page_received(100, Some(start));
+        }
+
+        assert!(backfill.next_request().is_none());
+        assert_eq!(backfill.progress(), Some(1.0));
+    }
+
+    #[test]
+    fn capabilities_summary_line() {
+        let table = isupport(&[
+            "WHOX",
+            "MONITOR=100",
+            "CHATHISTORY=100",
+            "CHANMODES=beI,k,l,imnpst",
+            "TOPICLEN=390",
+            "NICKLEN=30",
+            "CHANTYPES=#",
+        ]);
+
+        assert_eq!(
+            capabilities_summary(&table),
+            "NICKLEN 30 · TOPICLEN 390 · CHANMODES beI,k,l,imnpst · history · monitor · whox"
+        );
+
+        let table = isupport(&["TOPICLEN=390", "MONITOR"]);
+
+        assert_eq!(capabilities_summary(&table), "TOPICLEN 390 · monitor");
+
+        assert_eq!(capabilities_summary(&HashMap::new()), "");
+    }
+
+    #[test]
+    fn apply_operations() {
+        let mut table = HashMap::new();
+
+        apply(&mut table, "PREFIX=(ov)@+".parse().unwrap());
+        assert!(table.contains_key(&Kind::PREFIX));
+
+        apply(&mut table, "-PREFIX".parse().unwrap());
+        assert!(!table.contains_key(&Kind::PREFIX));
+
+        apply(&mut table, "NICKLEN=30".parse().unwrap());
+        apply(&mut table, "NICKLEN=16".parse().unwrap());
+        assert_eq!(get_nicklen_or_default(&table), 16);
+
+        // Removing an unknown parameter leaves the table untouched
+        apply(&mut table, "-EXAMPLE".parse().unwrap());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn feature_count_matches_entries() {
+        assert_eq!(feature_count(&HashMap::new()), 0);
+
+        let table = isupport(&["WHOX", "MONITOR=100", "NICKLEN=30"]);
+
+        assert_eq!(feature_count(&table), 3);
+        assert!(has(&table, Kind::WHOX));
+        assert!(!has(&table, Kind::TOPICLEN));
+
+        // Repeated parameters replace the earlier value
+        let table = isupport(&["NICKLEN=30", "NICKLEN=16"]);
+
+        assert_eq!(feature_count(&table), 1);
+    }
+
+    #[test]
+    fn account_extban_masks() {
+        // UnrealIRCd
+        let table =
+            isupport(&["EXTBAN=~,GOSTacijmnpqrt", "ACCOUNTEXTBAN=account,a"]);
+
+        assert_eq!(
+            account_extban(&table, "dan"),
+            Some("~account:dan".to_string())
+        );
+
+        // InspIRCd (no prefix)
+        let table =
+            isupport(&["EXTBAN=,ACNOQRSTUacjmnprswz", "ACCOUNTEXTBAN=R"]);
+
+        assert_eq!(account_extban(&table, "dan"), Some("R:dan".to_string()));
+
+        // Letters not advertised by EXTBAN are skipped
+        let table = isupport(&["EXTBAN=$,acjorxz", "ACCOUNTEXTBAN=R,a"]);
+
+        assert_eq!(account_extban(&table, "dan"), Some("$a:dan".to_string()));
+
+        let table = isupport(&["ACCOUNTEXTBAN=account,a"]);
+
+        assert_eq!(account_extban(&table, "dan"), None);
+    }
+
+    #[test]
+    fn account_extban_invalid_entries_dropped() {
+        assert!(matches!(
+            "ACCOUNTEXTBAN=~a,R,acc0unt,,account".parse::<Operation>(),
+            Ok(Operation::Add(Parameter::ACCOUNTEXTBAN(names)))
+                if names == ["R", "account"]
+        ));
+
+        assert!(matches!(
+            "ACCOUNTEXTBAN=~a,$R".parse::<Operation>(),
+            Err(ParseError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn whox_fields_validated() {
+        let isupport = HashMap::new();
+
+        assert!(matches!(
+            build_whox(
+                &isupport,
+                "#halloy",
+                WhoXPollParameters::WithAccountName.fields(),
+                Some(WhoXPollParameters::WithAccountName.token()),
+            ),
+            Ok(proto::Command::WHO(target, Some(fields), Some(token)))
+                if target == "#halloy" && fields == "tcnfa" && token == "99"
+        ));
+
+        assert!(matches!(
+            build_whox(&isupport, "#halloy", "cuhsnfdlaor", None),
+            Ok(proto::Command::WHO(_, Some(_), None))
+        ));
+
+        assert_eq!(
+            build_whox(&isupport, "#halloy", "tcnxa", None).unwrap_err(),
+            WhoxFieldsError::UnknownField('x')
+        );
+        assert_eq!(
+            build_whox(&isupport, "#halloy", "", None).unwrap_err(),
+            WhoxFieldsError::Empty
+        );
+    }
+
+    #[test]
+    fn whox_mask_validated() {
+        let isupport = isupport(&["USERLEN=4", "HOSTLEN=16"]);
+
+        assert!(matches!(
+            build_whox(&isupport, "*!*@example.com", "tcnf", None),
+            Ok(proto::Command::WHO(..))
+        ));
+
+        let error = build_whox(
+            &isupport,
+            "*!*@very.long.host.example.com",
+            "tcnf",
+            None,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            WhoxFieldsError::Mask(InvalidMask::HostTooLong {
+                len: 26,
+                max_len: 16,
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "mask host is too long (26/16 characters)"
+        );
+
+        assert_eq!(
+            validate_mask(&isupport, "*!~username@host"),
+            Err(InvalidMask::UserTooLong { len: 9, max_len: 4 })
+        );
+        assert_eq!(validate_mask(&isupport, "#halloy"), Ok(()));
+        assert_eq!(
+            validate_mask(&HashMap::new(), "*!*@very.long.host.example.com"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn after_newest_paging() {
+        let target = Target::parse(
+            "#halloy",
+            DEFAULT_CHANTYPES,
+            &[],
+            CaseMap::default(),
+        );
+
+        let table =
+            isupport(&["CHATHISTORY=100", "MSGREFTYPES=msgid,timestamp"]);
+
+        assert_eq!(
+            ChatHistorySubcommand::after_newest(target.clone(), None, &table),
+            ChatHistorySubcommand::Latest(
+                target.clone(),
+                MessageReference::None,
+                100
+            )
+        );
+
+        let mut newest = Message::sent(
+            crate::message::Target::Server {
+                source: crate::message::Source::Server(None),
+            },
+            crate::message::plain("hello".to_string()),
+        );
+
+        assert_eq!(
+            ChatHistorySubcommand::after_newest(
+                target.clone(),
+                Some(&newest),
+                &table
+            ),
+            ChatHistorySubcommand::After(
+                target.clone(),
+                MessageReference::Timestamp(newest.server_time),
+                100
+            )
+        );
+
+        newest.id = Some("abc".to_string());
+
+        assert_eq!(
+            ChatHistorySubcommand::after_newest(
+                target.clone(),
+                Some(&newest),
+                &table
+            ),
+            ChatHistorySubcommand::After(
+                target.clone(),
+                MessageReference::MessageId("abc".to_string()),
+                100
+            )
+        );
+
+        // Without MSGREFTYPES no message can be referenced
+        assert_eq!(
+            ChatHistorySubcommand::after_newest(
+                target.clone(),
+                Some(&newest),
+                &HashMap::new()
+            ),
+            ChatHistorySubcommand::Latest(
+                target,
+                MessageReference::None,
+                CLIENT_CHATHISTORY_LIMIT
+            )
+        );
+    }
+
+    #[test]
+    fn casemap_starts_with() {
+        assert!(CaseMap::ASCII.starts_with("Alice", "al"));
+        assert!(CaseMap::ASCII.starts_with("alice", "ALI"));
+        assert!(CaseMap::ASCII.starts_with("alice", ""));
+        assert!(!CaseMap::ASCII.starts_with("alice", "alicea"));
+        assert!(!CaseMap::ASCII.starts_with("[dan]", "{dan"));
+        assert!(!CaseMap::ASCII.starts_with("Émile", "é"));
+
+        assert!(CaseMap::RFC1459.starts_with("[Dan]", "{dan}"));
+        assert!(CaseMap::RFC1459.starts_with("{dan}", "[DAN]"));
+        assert!(CaseMap::RFC1459.starts_with("a\\b", "A|"));
+        assert!(CaseMap::RFC1459.starts_with("~dan", "^"));
+
+        assert!(CaseMap::RFC1459_STRICT.starts_with("[Dan]", "{d"));
+        assert!(CaseMap::RFC1459_STRICT.starts_with("a\\b", "A|"));
+        assert!(!CaseMap::RFC1459_STRICT.starts_with("~dan", "^"));
+
+        assert!(CaseMap::RFC7613.starts_with("Émile", "é"));
+        assert!(CaseMap::RFC7613.starts_with("ÉMILE", "émi"));
+        assert!(!CaseMap::RFC7613.starts_with("[dan]", "{"));
+
+        // Consistent with normalize
+        for casemap in [
+            CaseMap::ASCII,
+            CaseMap::RFC1459,
+            CaseMap::RFC1459_STRICT,
+            CaseMap::RFC7613,
+        ] {
+            for (haystack, prefix) in [
+                ("[Alice]~", "{ALICE}^"),
+                ("Émile\\", "éMILE|"),
+                ("ab", "AB"),
+            ] {
+                assert_eq!(
+                    casemap.starts_with(haystack, prefix),
+                    casemap
+                        .normalize(haystack)
+                        .starts_with(&casemap.normalize(prefix)),
+                    "{casemap:?} {haystack} {prefix}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn is_self_under_casemapping() {
+        let table = isupport(&["CASEMAPPING=ascii"]);
+
+        assert!(is_self(&table, "Dan", "dan"));
+        assert!(is_self(&table, "DAN", "dAn"));
+        assert!(!is_self(&table, "dan", "danny"));
+        assert!(!is_self(&table, "[dan]", "{dan}"));
+
+        let table = isupport(&["CASEMAPPING=rfc1459"]);
+
+        assert!(is_self(&table, "[Dan]", "{dan}"));
+        assert!(is_self(&table, "Dan^", "dan~"));
+
+        // Defaults to rfc7613
+        assert!(is_self(&HashMap::new(), "Émile", "émile"));
+        assert!(!is_self(&HashMap::new(), "[dan]", "{dan}"));
+    }
+
+    #[test]
+    fn message_reference_from_message_matches() {
+        let mut message = Message::sent(
+            crate::message::Target::Server {
+                source: crate::message::Source::Server(None),
+            },
+            crate::message::plain("hello".to_string()),
+        );
+        message.id = Some("abc".to_string());
+
+        let table = isupport(&["MSGREFTYPES=msgid,timestamp"]);
+        let message_reference =
+            MessageReference::from_message(&message, &table);
+
+        assert_eq!(
+            message_reference,
+            MessageReference::MessageId("abc".to_string())
+        );
+        assert!(message_reference == message);
+
+        // The server's preferred type comes first
+        let table = isupport(&["MSGREFTYPES=timestamp,msgid"]);
+
+        assert_eq!(
+            MessageReference::from_message(&message, &table),
+            MessageReference::Timestamp(message.server_time)
+        );
+
+        let table = isupport(&["MSGREFTYPES=timestamp"]);
+        let message_reference =
+            MessageReference::from_message(&message, &table);
+
+        assert_eq!(
+            message_reference,
+            MessageReference::Timestamp(message.server_time)
+        );
+        assert!(message_reference == message);
+
+        // Nothing to reference the message by, so it cannot match
+        let message_reference =
+            MessageReference::from_message(&message, &HashMap::new());
+
+        assert_eq!(message_reference, MessageReference::None);
+        assert!(message_reference != message);
+    }
+
+    #[test]
+    fn client_tags_limit() {
+        let tag = |key: &str, value: Option<String>| (key.to_string(), value);
+
+        // "+a=" plus value, leaving room for ";+b" (3 bytes)
+        let value = "x".repeat(CLIENT_TAG_DATA_LIMIT - 3 - 3);
+
+        let tags = vec![tag("+a", Some(value.clone())), tag("+b", None)];
+        assert!(tags_within_limit(&tags));
+
+        let tags = vec![tag("+a", Some(value.clone())), tag("+bc", None)];
+        assert!(!tags_within_limit(&tags));
+
+        // Escaped values count at their escaped length
+        let tags = vec![
+            tag("+a", Some(format!("{} ", &value[1..]))),
+            tag("+b", None),
+        ];
+        assert!(!tags_within_limit(&tags));
+
+        // Lowest priority (last) client-only tags are dropped first, while
+        // other tags are kept
+        let value = "x".repeat(CLIENT_TAG_DATA_LIMIT - 12);
+
+        let tags = vec![
+            tag("+a", Some(value.clone())),
+            tag("msgid", None),
+            tag("+typing", Some("active".to_string())),
+        ];
+
+        assert_eq!(
+            fit_client_tags(&HashMap::new(), tags),
+            vec![tag("+a", Some(value)), tag("msgid", None)]
+        );
+    }
+
+    #[test]
+    fn client_tags_deny() {
+        let tags = || {
+            vec![
+                ("+typing".to_string(), Some("active".to_string())),
+                ("+draft/react".to_string(), Some("👍".to_string())),
+                ("label".to_string(), Some("1".to_string())),
+            ]
+        };
+
+        let table = isupport(&["CLIENTTAGDENY=*,-typing"]);
+
+        assert!(client_tag_allowed(&table, "+typing"));
+        assert!(!client_tag_allowed(&table, "+draft/react"));
+        assert_eq!(
+            fit_client_tags(&table, tags())
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["+typing", "label"]
+        );
+
+        let table = isupport(&["CLIENTTAGDENY=typing"]);
+
+        assert_eq!(
+            fit_client_tags(&table, tags())
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["+draft/react", "label"]
+        );
+
+        assert_eq!(fit_client_tags(&HashMap::new(), tags()), tags());
+    }
+
+    #[test]
+    fn chathistory_states_per_target() {
+        let casemapping = CaseMap::default();
+        let halloy = Target::parse("#halloy", &['#'], &[], casemapping);
+        let rust = Target::parse("#rust", &['#'], &[], casemapping);
+
+        let mut states = ChatHistoryStates::default();
+
+        assert!(states.request(&halloy));
+        assert!(!states.request(&halloy));
+        assert_eq!(states.state(&halloy), ChatHistoryState::PendingRequest);
+        assert_eq!(states.state(&rust), ChatHistoryState::Ready);
+
+        states.receive(&halloy, true);
+
+        assert_eq!(states.state(&halloy), ChatHistoryState::Exhausted);
+        assert!(!states.request(&halloy));
+        assert!(states.request(&rust));
+
+        states.receive(&rust, false);
+
+        assert_eq!(states.state(&rust), ChatHistoryState::Ready);
+
+        states.reset(&halloy);
+
+        assert_eq!(states.state(&halloy), ChatHistoryState::Ready);
+    }
+
+    #[test]
+    fn chathistory_states_casemapped_key() {
+        let casemapping = CaseMap::RFC1459;
+        let mut states = ChatHistoryStates::default();
+
+        states.receive(
+            &Target::parse("#Halloy[1]", &['#'], &[], casemapping),
+            true,
+        );
+
+        assert_eq!(
+            states.state(&Target::parse(
+                "#halloy{1}",
+                &['#'],
+                &[],
+                casemapping
+            )),
+            ChatHistoryState::Exhausted
+        );
+    }
+
+    #[test]
+    fn page_stall_nudges_repeated_reference() {
+        let target = Target::parse("#halloy", &['#'], &[], CaseMap::default());
+        let oldest = MessageReference::MessageId("oldest".to_string());
+
+        let mut page_stall = PageStall::default();
+        let mut fuzz_window = FuzzWindow::default();
+
+        let mut subcommand =
+            ChatHistorySubcommand::Before(target.clone(), oldest.clone(), 100);
+
+        assert!(!page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+        assert_eq!(fuzz_window, FuzzWindow::default());
+
+        // The page came back without moving the oldest message
+        let mut subcommand =
+            ChatHistorySubcommand::Before(target.clone(), oldest.clone(), 100);
+
+        assert!(page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+        assert!(matches!(
+            subcommand,
+            ChatHistorySubcommand::Before(_, _, 101)
+        ));
+        // Message ids are not fuzzed, so the window is left alone
+        assert_eq!(fuzz_window, FuzzWindow::default());
+
+        // Nothing is left to change at the maximum limit
+        let mut subcommand =
+            ChatHistorySubcommand::Before(target.clone(), oldest, 500);
+
+        assert!(!page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+        assert!(matches!(
+            subcommand,
+            ChatHistorySubcommand::Before(_, _, 500)
+        ));
+
+        // Paging resumes once the oldest message moves
+        let mut subcommand = ChatHistorySubcommand::Before(
+            target,
+            MessageReference::MessageId("older".to_string()),
+            100,
+        );
+
+        assert!(!page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+        assert!(matches!(
+            subcommand,
+            ChatHistorySubcommand::Before(_, _, 100)
+        ));
+    }
+
+    #[test]
+    fn page_stall_widens_timestamp_fuzz() {
+        let target = Target::parse("#halloy", &['#'], &[], CaseMap::default());
+        let oldest = MessageReference::Timestamp(
+            DateTime::from_timestamp(1_000_000, 0).unwrap(),
+        );
+
+        let mut page_stall = PageStall::default();
+        let mut fuzz_window = FuzzWindow::default();
+
+        let mut subcommand =
+            ChatHistorySubcommand::Before(target.clone(), oldest.clone(), 500);
+
+        assert!(!page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+
+        // At the maximum limit only the fuzz window can still change
+        while fuzz_window.duration() < MAX_FUZZ_SECONDS {
+            let mut subcommand = ChatHistorySubcommand::Before(
+                target.clone(),
+                oldest.clone(),
+                500,
+            );
+
+            assert!(page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+        }
+
+        let mut subcommand = ChatHistorySubcommand::Before(target, oldest, 500);
+
+        assert!(!page_stall.nudge(&mut subcommand, &mut fuzz_window, 500));
+        assert_eq!(fuzz_window.duration(), MAX_FUZZ_SECONDS);
+    }
+
+    #[test]
+    fn normalized_nick_recomputes_on_change() {
+        let mut nick = NormalizedNick::new("Foo[m]", CaseMap::RFC1459);
+
+        assert_eq!(nick.as_normalized_str(), "foo{m}");
+        assert!(nick.matches("FOO{M}"));
+        assert!(!nick.matches("foo"));
+
+        assert!(!nick.update("Foo[m]", CaseMap::RFC1459));
+        assert_eq!(nick.as_normalized_str(), "foo{m}");
+
+        assert!(nick.update("Foo[m]", CaseMap::ASCII));
+        assert_eq!(nick.as_normalized_str(), "foo[m]");
+        assert!(!nick.matches("foo{m}"));
+
+        assert!(!nick.update("Foo[m]", CaseMap::ASCII));
+
+        assert!(nick.update("Bar", CaseMap::ASCII));
+        assert_eq!(nick.raw(), "Bar");
+        assert!(nick.matches("bAR"));
+    }
+
+    #[test]
+    fn list_mode_query_list_modes_only() {
+        let isupport = isupport(&["CHANMODES=beIq,k,fl,imnpst"]);
+
+        assert_eq!(
+            list_mode_query(&isupport, "#halloy", 'b'),
+            Ok("MODE #halloy +b".to_string())
+        );
+        assert_eq!(
+            list_mode_query(&isupport, "#halloy", 'q'),
+            Ok("MODE #halloy +q".to_string())
+        );
+        assert_eq!(
+            list_mode_query(&isupport, "#halloy", 'k'),
+            Err(Unsupported::NotListMode('k'))
+        );
+        assert_eq!(
+            list_mode_query(&HashMap::new(), "#halloy", 'q'),
+            Err(Unsupported::NotListMode('q'))
+        );
+    }
+
+    #[test]
+    fn who_flags_parse() {
+        let isupport = isupport(&["PREFIX=(ohv)@%+"]);
+
+        assert_eq!(
+            WhoFlags::parse("G*@+", &isupport),
+            WhoFlags {
+                away: Some(true),
+                oper: true,
+                prefixes: vec!['@', '+'],
+            }
+        );
+        assert_eq!(
+            WhoFlags::parse("H%B", &isupport),
+            WhoFlags {
+                away: Some(false),
+                oper: false,
+                prefixes: vec!['%'],
+            }
+        );
+        // ~ is not a prefix on this server
+        assert_eq!(
+            WhoFlags::parse("H~", &isupport),
+            WhoFlags {
+                away: Some(false),
+                oper: false,
+                prefixes: vec![],
+            }
+        );
+        assert_eq!(WhoFlags::parse("", &isupport), WhoFlags::default());
+    }
+
+    #[test]
+    fn stable_subset_drops_volatile() {
+        let table = isupport(&[
+            "CASEMAPPING=ascii",
+            "CHANMODES=b,k,l,imnst",
+            "CHATHISTORY=100",
+            "MONITOR=100",
+            "NETWORK=Libera.Chat",
+            "NICKLEN=16",
+            "PREFIX=(ov)@+",
+            "WHOX",
+        ]);
+
+        let stable = stable_subset(&table);
+
+        assert_eq!(
+            stable.keys().sorted().collect::<Vec<_>>(),
+            vec![
+                &Kind::CASEMAPPING,
+                &Kind::CHANMODES,
+                &Kind::NETWORK,
+                &Kind::NICKLEN,
+                &Kind::PREFIX,
+            ]
+        );
+        assert_eq!(get_nicklen_or_default(&stable), 16);
+        assert!(stable_subset(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn unsupported_on_bare_server() {
+        let bare = HashMap::new();
+
+        assert_eq!(
+            knock(&bare, "#halloy", None),
+            Err(Unsupported::MissingParameter(Kind::KNOCK))
+        );
+        assert_eq!(
+            userip(&bare, "dan"),
+            Err(Unsupported::MissingParameter(Kind::USERIP))
+        );
+        assert_eq!(
+            cprivmsg(&bare, "dan", "#halloy", "hi"),
+            Err(Unsupported::MissingParameter(Kind::CPRIVMSG))
+        );
+        assert_eq!(
+            Unsupported::MissingParameter(Kind::KNOCK).to_string(),
+            "not supported by this server (no KNOCK)"
+        );
+
+        let table = isupport(&["KNOCK", "CPRIVMSG"]);
+
+        assert_eq!(
+            knock(&table, "#halloy", Some("let me in")),
+            Ok("KNOCK #halloy :let me in".to_string())
+        );
+        assert_eq!(
+            cprivmsg(&table, "dan", "#halloy", "hi"),
+            Ok("CPRIVMSG dan #halloy :hi".to_string())
+        );
+
+        // Parameters cannot smuggle in another command
+        assert_eq!(
+            knock(&table, "#halloy", Some("hi\r\nQUIT")),
+            Err(Unsupported::InvalidParameter("hi\r\nQUIT".to_string()))
+        );
+        assert_eq!(
+            cprivmsg(&table, "dan #halloy", "#halloy", "hi"),
+            Err(Unsupported::InvalidParameter("dan #halloy".to_string()))
+        );
+        assert_eq!(
+            cprivmsg(&table, "dan", "#halloy", "hi\nthere"),
+            Err(Unsupported::InvalidParameter("hi\nthere".to_string()))
+        );
+
+        let features = ServerFeatures {
+            isupport: isupport(&["CHATHISTORY=100"]).into(),
+            ..ServerFeatures::default()
+        };
+
+        assert_eq!(
+            features.require(Kind::CHATHISTORY),
+            Err(Unsupported::MissingCapability("batch"))
+        );
+        assert_eq!(
+            ServerFeatures::default().require(Kind::WHOX),
+            Err(Unsupported::MissingParameter(Kind::WHOX))
+        );
+    }
+
+    #[test]
+    fn chanmodes_empty_groups() {
+        for token in ["CHANMODES=beI,,,imnpst", "CHANMODES=beI,k,,imnpst"] {
+            assert!(token.parse::<Operation>().is_ok());
+        }
+
+        let table = isupport(&["CHANMODES=beI,k,,imnpst"]);
+
+        assert_eq!(get_chanmodes_or_default(&table).len(), 4);
+        assert_eq!(chanmode_group(&table, 'b'), Some('A'));
+        assert_eq!(chanmode_group(&table, 'k'), Some('B'));
+        assert_eq!(chanmode_group(&table, 'l'), None);
+        assert_eq!(chanmode_group(&table, 'n'), Some('D'));
+
+        assert!(mode_takes_argument(&table, 'b', false));
+        assert!(mode_takes_argument(&table, 'k', false));
+        assert!(!mode_takes_argument(&table, 'l', true));
+        assert!(!mode_takes_argument(&table, 'n', true));
+        assert!(mode_takes_argument(&table, 'o', false));
+
+        let table = isupport(&["CHANMODES=beI,,,imnpst"]);
+
+        assert_eq!(chanmode_group(&table, 'I'), Some('A'));
+        assert_eq!(chanmode_group(&table, 'k'), None);
+        assert_eq!(chanmode_group(&table, 's'), Some('D'));
+        assert!(!mode_takes_argument(&table, 'k', true));
+
+        // Defaults still apply without CHANMODES
+        assert!(mode_takes_argument(&HashMap::new(), 'l', true));
+        assert!(!mode_takes_argument(&HashMap::new(), 'l', false));
+    }
+
+    #[test]
+    fn all_channel_modes_categories() {
+        let table = isupport(&["PREFIX=(ov)@+", "CHANMODES=beI,k,lo,imnst"]);
+
+        let modes = all_channel_modes(&table);
+
+        let category = |mode: char| {
+            modes
+                .iter()
+                .find(|editable_mode| editable_mode.mode == mode)
+                .map(|editable_mode| {
+                    (editable_mode.category, editable_mode.takes_argument)
+                })
+        };
+
+        assert_eq!(category('o'), Some((ModeCategory::Prefix, true)));
+        assert_eq!(category('v'), Some((ModeCategory::Prefix, true)));
+        assert_eq!(category('b'), Some((ModeCategory::ListA, true)));
+        assert_eq!(category('I'), Some((ModeCategory::ListA, true)));
+        assert_eq!(category('k'), Some((ModeCategory::SettableB, true)));
+        assert_eq!(category('l'), Some((ModeCategory::SettableC, true)));
+        assert_eq!(category('t'), Some((ModeCategory::FlagD, false)));
+        assert_eq!(category('q'), None);
+
+        // o is only listed once, as a PREFIX mode
+        assert_eq!(
+            modes
+                .iter()
+                .map(|editable_mode| editable_mode.mode)
+                .join(""),
+            "ovbeIklimnst"
+        );
+    }
+
+    #[test]
+    fn fuzz_skips_msgid_references() {
+        let mut first = Message::sent(
+            crate::message::Target::Server {
+                source: crate::message::Source::Server(None),
+            },
+            crate::message::plain("first".to_string()),
+        );
+        first.id = Some("first".to_string());
+
+        let mut last = first.clone();
+        last.id = Some("last".to_string());
+        last.server_time = first.server_time + chrono::Duration::minutes(1);
+
+        let fuzz_window = FuzzWindow::default();
+
+        let msgid_capable = isupport(&["MSGREFTYPES=msgid,timestamp"]);
+
+        assert_eq!(
+            fuzz_message_reference_range(
+                MessageReference::from_message(&first, &msgid_capable),
+                MessageReference::from_message(&last, &msgid_capable),
+                fuzz_window,
+                supports_msgid_references(&msgid_capable),
+            ),
+            (
+                MessageReference::MessageId("first".to_string()),
+                MessageReference::MessageId("last".to_string()),
+            )
+        );
+        assert_eq!(
+            fuzz_end_message_reference(
+                MessageReference::from_message(&first, &msgid_capable),
+                fuzz_window,
+                supports_msgid_references(&msgid_capable),
+            ),
+            MessageReference::MessageId("first".to_string())
+        );
+
+        // Messages without a msgid are referenced exactly by timestamp
+        first.id = None;
+
+        assert_eq!(
+            fuzz_start_message_reference(
+                MessageReference::from_message(&first, &msgid_capable),
+                fuzz_window,
+                supports_msgid_references(&msgid_capable),
+            ),
+            MessageReference::Timestamp(first.server_time)
+        );
+
+        let timestamp_only = isupport(&["MSGREFTYPES=timestamp"]);
+
+        assert_eq!(
+            fuzz_message_reference_range(
+                MessageReference::from_message(&first, &timestamp_only),
+                MessageReference::from_message(&last, &timestamp_only),
+                fuzz_window,
+                supports_msgid_references(&timestamp_only),
+            ),
+            (
+                MessageReference::Timestamp(
+                    first.server_time - fuzz_window.duration()
+                ),
+                MessageReference::Timestamp(
+                    last.server_time + fuzz_window.duration()
+                ),
+            )
+        );
+        assert_eq!(
+            fuzz_end_message_reference(
+                MessageReference::from_message(&first, &timestamp_only),
+                fuzz_window,
+                supports_msgid_references(&timestamp_only),
+            ),
+            MessageReference::Timestamp(
+                first.server_time + fuzz_window.duration()
+            )
+        );
+    }
+
+    #[test]
+    fn validate_channel_name_rules() {
+        let table = isupport(&["CHANTYPES=#", "CHANNELLEN=8"]);
+
+        assert_eq!(validate_channel_name(&table, "#halloy"), Ok(()));
+        assert_eq!(
+            validate_channel_name(&table, "&halloy"),
+            Err(ChannelNameError::InvalidPrefix(vec!['#']))
+        );
+        assert_eq!(
+            validate_channel_name(&table, "#halloy-dev"),
+            Err(ChannelNameError::TooLong(8))
+        );
+        assert_eq!(
+            validate_channel_name(&table, "#a,#b"),
+            Err(ChannelNameError::InvalidCharacter(','))
+        );
+        assert_eq!(
+            validate_channel_name(&table, "#a\u{7}"),
+            Err(ChannelNameError::InvalidCharacter('\u{7}'))
+        );
+        assert_eq!(
+            ChannelNameError::InvalidPrefix(vec!['#', '&']).to_string(),
+            "channel name must start with one of: #&"
+        );
+
+        // Without CHANNELLEN the default length applies
+        assert_eq!(
+            validate_channel_name(
+                &HashMap::new(),
+                &format!("&{}", "a".repeat(DEFAULT_CHANNELLEN as usize - 1))
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate_channel_name(
+                &HashMap::new(),
+                &format!("&{}", "a".repeat(300))
+            ),
+            Err(ChannelNameError::TooLong(DEFAULT_CHANNELLEN))
+        );
+    }
+
+    #[test]
+    fn format_targmax_sorted() {
+        let table =
+            isupport(&["TARGMAX=PRIVMSG:4,NOTICE:4,JOIN:,KICK:1,WHOIS:1"]);
+
+        assert_eq!(
+            format_targmax(&table),
+            vec![
+                ("JOIN".to_string(), None),
+                ("KICK".to_string(), Some(1)),
+                ("NOTICE".to_string(), Some(4)),
+                ("PRIVMSG".to_string(), Some(4)),
+                ("WHOIS".to_string(), Some(1)),
+            ]
+        );
+        assert!(format_targmax(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn fingerprint_is_order_independent() {
+        let tokens = [
+            "CASEMAPPING=rfc1459",
+            "CHANTYPES=#&",
+            "NICKLEN=30",
+            "PREFIX=(ov)@+",
+            "WHOX",
+        ];
+        let reversed = tokens.iter().rev().copied().collect::<Vec<_>>();
+
+        assert_eq!(
+            fingerprint(&isupport(&tokens)),
+            fingerprint(&isupport(&reversed))
+        );
+
+        let changed = isupport(&[
+            "CASEMAPPING=rfc1459",
+            "CHANTYPES=#&",
+            "NICKLEN=31",
+            "PREFIX=(ov)@+",
+            "WHOX",
+        ]);
+
+        assert_ne!(fingerprint(&isupport(&tokens)), fingerprint(&changed));
+    }
+
+    #[test]
+    fn regressions_lists_dropped_parameters() {
+        let old = isupport(&["CHATHISTORY=100", "NICKLEN=16", "WHOX"]);
+        let new = isupport(&["NICKLEN=30", "MONITOR=100"]);
+
+        assert_eq!(
+            regressions(&old, &new),
+            vec![Kind::CHATHISTORY, Kind::WHOX]
+        );
+        assert!(regressions(&new, &new).is_empty());
+        assert!(regressions(&HashMap::new(), &new).is_empty());
+    }
+
+    #[test]
+    fn parse_who_reply_plain() {
+        let message = proto::parse::message(
+            ":irc.example.org 352 me #halloy ~dan example.org irc.example.org dan H*@ :0 Dan\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_who_reply(&message, &HashMap::new()),
+            Some(WhoxRecord {
+                token: None,
+                channel: "#halloy".to_string(),
+                user: Some("~dan".to_string()),
+                host: Some("example.org".to_string()),
+                server: Some("irc.example.org".to_string()),
+                nick: "dan".to_string(),
+                flags: WhoFlags {
+                    away: Some(false),
+                    oper: true,
+                    prefixes: vec!['@'],
+                },
+                account: None,
+                realname: Some("Dan".to_string()),
+            })
+        );
+
+        // Hopcount is stripped, the rest of the trailing parameter is kept
+        let message = proto::parse::message(
+            ":irc.example.org 352 me * dan 192.0.2.1 hub.example.org Dan G :3 Dan the IRC user\r\n",
+        )
+        .unwrap();
+        let record = parse_who_reply(&message, &HashMap::new()).unwrap();
+
+        assert_eq!(record.channel, "*");
+        assert_eq!(record.user.as_deref(), Some("dan"));
+        assert_eq!(record.host.as_deref(), Some("192.0.2.1"));
+        assert_eq!(record.server.as_deref(), Some("hub.example.org"));
+        assert_eq!(record.nick, "Dan");
+        assert_eq!(record.flags.away, Some(true));
+        assert_eq!(record.realname.as_deref(), Some("Dan the IRC user"));
+
+        let message = proto::parse::message(
+            ":irc.example.org 352 me #halloy dan example.org irc.example.org dan H :0\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_who_reply(&message, &HashMap::new())
+                .and_then(|record| record.realname),
+            Some(String::new())
+        );
+
+        let message =
+            proto::parse::message(":irc.example.org 315 me #halloy :End\r\n")
+                .unwrap();
+
+        assert_eq!(parse_who_reply(&message, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn nick_change_command_validates() {
+        let isupport = isupport(&["NICKLEN=9", "CHANTYPES=#"]);
+
+        assert_eq!(
+            nick_change_command(&isupport, "halloy"),
+            Ok("NICK halloy".to_string())
+        );
+        assert_eq!(
+            nick_change_command(&isupport, "halloy_irc"),
+            Err(Unsupported::InvalidNick(InvalidNick::TooLong(9)))
+        );
+        assert_eq!(
+            nick_change_command(&isupport, "#halloy"),
+            Err(Unsupported::InvalidNick(InvalidNick::ChannelPrefix('#')))
+        );
+        assert_eq!(
+            nick_change_command(&isupport, "hal loy"),
+            Err(Unsupported::InvalidNick(InvalidNick::InvalidCharacter(' ')))
+        );
+
+        // Without NICKLEN the default applies
+        assert_eq!(
+            nick_change_command(&HashMap::new(), "halloy_irc"),
+            Err(Unsupported::InvalidNick(InvalidNick::TooLong(
+                DEFAULT_NICKLEN
+            )))
+        );
+    }
+
+    #[test]
+    fn canonical_query_validates() {
+        let isupport = isupport(&["NICKLEN=9", "CHANTYPES=#&"]);
+
+        assert_eq!(
+            canonical_query(&isupport, "Halloy[m]"),
+            Ok("Halloy[m]".to_string())
+        );
+        assert_eq!(
+            canonical_query(&isupport, "halloy_irc"),
+            Err(InvalidNick::TooLong(9))
+        );
+        assert_eq!(
+            canonical_query(&isupport, "&halloy"),
+            Err(InvalidNick::ChannelPrefix('&'))
+        );
+        assert_eq!(
+            canonical_query(&isupport, "hal loy"),
+            Err(InvalidNick::InvalidCharacter(' '))
+        );
+        assert_eq!(canonical_query(&isupport, ""), Err(InvalidNick::Empty));
+        assert_eq!(
+            canonical_query(&HashMap::new(), "halloy_irc"),
+            Err(InvalidNick::TooLong(DEFAULT_NICKLEN))
+        );
+    }
+
+    #[test]
+    fn chathistory_supports_reference_types() {
+        let msgid = MessageReference::MessageId("abc".to_string());
+        let timestamp = MessageReference::Timestamp(DateTime::UNIX_EPOCH);
+
+        let timestamp_only =
+            isupport(&["CHATHISTORY=100", "MSGREFTYPES=timestamp"]);
+
+        assert!(!chathistory_supports_reference(&timestamp_only, &msgid));
+        assert!(chathistory_supports_reference(&timestamp_only, &timestamp));
+        assert!(chathistory_supports_reference(
+            &timestamp_only,
+            &MessageReference::None
+        ));
+
+        let both =
+            isupport(&["CHATHISTORY=100", "MSGREFTYPES=msgid,timestamp"]);
+
+        assert!(chathistory_supports_reference(&both, &msgid));
+        assert!(chathistory_supports_reference(&both, &timestamp));
+
+        let absent = isupport(&["CHATHISTORY=100"]);
+
+        assert!(!chathistory_supports_reference(&absent, &msgid));
+        assert!(!chathistory_supports_reference(&absent, &timestamp));
+        assert!(chathistory_supports_reference(
+            &absent,
+            &MessageReference::None
+        ));
+    }
+
+    #[test]
+    fn length_accessors() {
+        let table = isupport(&["NICKLEN=30", "TOPICLEN=307", "CHANNELLEN=64"]);
+
+        assert_eq!(get_nicklen_or_default(&table), 30);
+        assert_eq!(get_topiclen_or_default(&table), 307);
+        assert_eq!(get_channellen_or_default(&table), 64);
+
+        let absent = HashMap::new();
+
+        assert_eq!(get_nicklen_or_default(&absent), 9);
+        assert_eq!(get_topiclen_or_default(&absent), 390);
+        assert_eq!(get_channellen_or_default(&absent), 200);
+
+        let corrupt = HashMap::from([
+            (Kind::NICKLEN, Parameter::TOPICLEN(30)),
+            (Kind::TOPICLEN, Parameter::CHANNELLEN(307)),
+            (Kind::CHANNELLEN, Parameter::NICKLEN(64)),
+        ]);
+
+        assert_eq!(get_nicklen_or_default(&corrupt), 9);
+        assert_eq!(get_topiclen_or_default(&corrupt), 390);
+        assert_eq!(get_channellen_or_default(&corrupt), 200);
+    }
+
+    #[test]
+    fn truncate_to_limit_on_char_boundary() {
+        assert_eq!(truncate_to_limit("halloy irc", 6), "halloy");
+
+        // 🦀 occupies bytes 3..7
+        assert_eq!(truncate_to_limit("hi 🦀!", 5), "hi ");
+        assert_eq!(truncate_to_limit("hi 🦀!", 7), "hi 🦀");
+
+        assert_eq!(truncate_to_limit("halloy", 390), "halloy");
+        assert_eq!(truncate_to_limit("", 0), "");
+    }
+
+    #[test]
+    fn max_channel_len_creating_and_joining() {
+        let isupport = isupport(&["CHANNELLEN=32"]);
+
+        assert_eq!(max_channel_len(&isupport, true), 32);
+        assert_eq!(max_channel_len(&isupport, false), 32);
+
+        assert_eq!(max_channel_len(&HashMap::new(), true), DEFAULT_CHANNELLEN);
+        assert_eq!(max_channel_len(&HashMap::new(), false), DEFAULT_CHANNELLEN);
+    }
+
+    #[test]
+    fn split_message_at_whitespace() {
+        let text = "halloy ".repeat(143);
+        let text = text.trim_end();
+        assert_eq!(text.chars().count(), 1000);
+
+        let lines =
+            split_message(&HashMap::new(), "PRIVMSG", "#halloy", text, 14);
+        let budget = 512 - ":nick!user@host PRIVMSG #halloy :\r\n".len();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= budget));
+        assert!(
+            lines
+                .iter()
+                .all(|line| line.starts_with("halloy")
+                    && line.ends_with("halloy"))
+        );
+        assert_eq!(lines.join(" "), text);
+
+        // A shorter LINELEN results in more lines
+        let lines = split_message(
+            &isupport(&["LINELEN=256"]),
+            "PRIVMSG",
+            "#halloy",
+            text,
+            14,
+        );
+
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|line| line.len() <= 256 - 35));
+        assert_eq!(lines.join(" "), text);
+    }
+
+    #[test]
+    fn split_message_multibyte() {
+        let text = "🦀".repeat(300);
+
+        let lines =
+            split_message(&HashMap::new(), "PRIVMSG", "#halloy", &text, 14);
+        let budget = 512 - ":nick!user@host PRIVMSG #halloy :\r\n".len();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= budget));
+        assert!(lines.iter().all(|line| line.chars().all(|c| c == '🦀')));
+        assert_eq!(lines.concat(), text);
+    }
+
+    #[test]
+    fn split_message_color_on_boundary() {
+        let budget = 512 - ":nick!user@host PRIVMSG #halloy :\r\n".len();
+
+        // The color code would straddle the end of the first line
+        let text = format!("{}\u{3}04,02red text", "a".repeat(budget - 5));
+        let lines =
+            split_message(&HashMap::new(), "PRIVMSG", "#halloy", &text, 14);
+
+        assert_eq!(
+            lines,
+            vec!["a".repeat(budget - 5), "\u{3}04,02red text".to_string()]
+        );
+    }
+
+    #[test]
+    fn message_split_len_before_isupport() {
+        let text = "halloy ".repeat(200);
+
+        let max_bytes =
+            message_split_len(&HashMap::new(), "PRIVMSG", "#halloy");
+        let lines = crate::message::formatting::split_message(&text, max_bytes);
+
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|line| {
+            HOSTMASK_SAFETY_MARGIN
+                + format!("PRIVMSG #halloy :{line}\r\n").len()
+                <= DEFAULT_LINELEN as usize
+        }));
+        assert_eq!(lines.concat(), text);
+
+        let isupport = isupport(&["LINELEN=1024"]);
+
+        assert_eq!(
+            message_split_len(&isupport, "PRIVMSG", "#halloy"),
+            max_bytes + 512
+        );
+    }
+
+    #[test]
+    fn casemapping_conflict_with_config() {
+        let isupport = isupport(&["CASEMAPPING=rfc1459"]);
+
+        assert_eq!(casemapping_conflict(CaseMap::RFC1459, &isupport), None);
+        assert_eq!(
+            casemapping_conflict(CaseMap::ASCII, &isupport),
+            Some((CaseMap::ASCII, CaseMap::RFC1459))
+        );
+        assert_eq!(casemapping_conflict(CaseMap::ASCII, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn estimated_backfill_messages_in_pages() {
+        let isupport = isupport(&["CHATHISTORY=100"]);
+
+        // 90 minutes at 5 messages a minute is 450 messages, or 5 pages
+        assert_eq!(
+            estimated_backfill_messages(
+                &isupport,
+                chrono::Duration::minutes(90),
+                5.0
+            ),
+            500
+        );
+        assert_eq!(
+            estimated_backfill_messages(
+                &isupport,
+                chrono::Duration::minutes(20),
+                5.0
+            ),
+            100
+        );
+        assert_eq!(
+            estimated_backfill_messages(
+                &isupport,
+                chrono::Duration::minutes(90),
+                0.0
+            ),
+            0
+        );
+        assert_eq!(
+            estimated_backfill_messages(
+                &isupport,
+                chrono::Duration::minutes(-5),
+                5.0
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn targmax_zero_is_unlimited() {
+        let token = "TARGMAX=JOIN:0,PRIVMSG:4,WHOIS:";
+
+        let table = isupport(&[token]);
+
+        assert_eq!(find_target_limit(&table, "JOIN"), None);
+        assert_eq!(find_target_limit(&table, "PRIVMSG"), Some(4));
+        assert_eq!(find_target_limit(&table, "WHOIS"), None);
+        assert_eq!(
+            format_targmax(&table),
+            vec![
+                ("JOIN".to_string(), None),
+                ("PRIVMSG".to_string(), Some(4)),
+                ("WHOIS".to_string(), None),
+            ]
+        );
+
+        // The 0 limit is kept when the parameter is re-emitted
+        assert_eq!(table[&Kind::TARGMAX].to_string(), token);
+    }
+
+    #[test]
+    fn server_features_draft_spelling() {
+        let mut features = ServerFeatures::default();
+
+        features.apply_token("draft/CHATHISTORY=100").unwrap();
+
+        assert!(features.is_draft(Kind::CHATHISTORY));
+        assert_eq!(features.isupport.chathistory_limit(), 100);
+
+        features.apply_token("CHATHISTORY=50").unwrap();
+
+        assert!(!features.is_draft(Kind::CHATHISTORY));
+        assert_eq!(features.isupport.chathistory_limit(), 50);
+
+        features.apply_token("draft/CHATHISTORY=100").unwrap();
+        features.apply_token("-draft/CHATHISTORY").unwrap();
+
+        assert!(!features.is_draft(Kind::CHATHISTORY));
+        assert!(!features.isupport.contains_key(&Kind::CHATHISTORY));
+
+        features.apply_token("NICKLEN=16").unwrap();
+
+        assert!(!features.is_draft(Kind::NICKLEN));
+    }
+
+    #[test]
+    fn server_limits_with_defaults() {
+        let isupport =
+            isupport(&["NICKLEN=30", "TOPICLEN=307", "LINELEN=4096"]);
+
+        assert_eq!(
+            ServerLimits::from_isupport(&isupport),
+            ServerLimits {
+                nicklen: 30,
+                topiclen: 307,
+                channellen: DEFAULT_CHANNELLEN,
+                kicklen: DEFAULT_KICKLEN,
+                awaylen: DEFAULT_AWAYLEN,
+                userlen: DEFAULT_USERLEN,
+                hostlen: DEFAULT_HOSTLEN,
+                keylen: DEFAULT_KEYLEN,
+                linelen: 4096,
+            }
+        );
+        assert_eq!(
+            ServerLimits::from_isupport(&HashMap::new()).linelen,
+            DEFAULT_LINELEN
+        );
+    }
+
+    #[test]
+    fn length_limits_with_defaults() {
+        let isupport = isupport(&[
+            "NICKLEN=30",
+            "CHANNELLEN=64",
+            "TOPICLEN=307",
+            "KICKLEN=255",
+            "USERLEN=12",
+        ]);
+
+        assert_eq!(
+            length_limits(&isupport),
+            LengthLimits {
+                nicklen: 30,
+                channellen: 64,
+                topiclen: 307,
+                kicklen: 255,
+                awaylen: DEFAULT_AWAYLEN,
+                keylen: DEFAULT_KEYLEN,
+                userlen: 12,
+                hostlen: DEFAULT_HOSTLEN,
+                linelen: DEFAULT_LINELEN,
+            }
+        );
+        assert_eq!(length_limits(&HashMap::new()).nicklen, DEFAULT_NICKLEN);
+
+        // A parameter stored under the wrong kind is not used
+        let corrupted = HashMap::from([(Kind::KICKLEN, Parameter::AWAYLEN(5))]);
+
+        assert_eq!(length_limits(&corrupted).kicklen, DEFAULT_KICKLEN);
+    }
+
+    #[test]
+    fn shared_channel_by_casemapping() {
+        let isupport = isupport(&["CASEMAPPING=rfc1459"]);
+        let casemapping = get_casemapping_or_default(&isupport);
+
+        let halloy = Target::parse("#halloy", &['#'], &[], casemapping);
+        let rust = Target::parse("#rust", &['#'], &[], casemapping);
+
+        let my_channels = vec![
+            (halloy.clone(), vec!["me".to_string(), "dan".to_string()]),
+            (
+                rust.clone(),
+                vec!["me".to_string(), "Casper[m]".to_string()],
+            ),
+        ];
+
+        assert_eq!(
+            shared_channel(&my_channels, "casper{M}", &isupport),
+            Some(rust)
+        );
+        assert_eq!(
+            shared_channel(&my_channels, "DAN", &isupport),
+            Some(halloy)
+        );
+        assert_eq!(
+            shared_channel(&my_channels, "andymandias", &isupport),
+            None
+        );
+        assert_eq!(shared_channel(&[], "dan", &isupport), None);
+    }
+
+    #[test]
+    fn merge_names_unions_prefixes() {
+        let isupport = isupport(&["PREFIX=(qov)~@+", "CASEMAPPING=rfc1459"]);
+
+        let entries = vec![
+            (vec!['+'], "dan".to_string()),
+            (vec![], "casper".to_string()),
+            (vec!['@'], "DAN".to_string()),
+            (vec!['~', '+'], "Dan".to_string()),
+            (vec!['+'], "andy[m]".to_string()),
+            (vec!['+'], "andy{m}".to_string()),
+        ];
+
+        assert_eq!(
+            merge_names(&isupport, entries),
+            vec![
+                (vec!['~', '@', '+'], "dan".to_string()),
+                (vec![], "casper".to_string()),
+                (vec!['+'], "andy[m]".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn join_command_aligns_keys() {
+        let isupport = isupport(&["TARGMAX=JOIN:3", "KEYLEN=8"]);
+
+        let channel = |name: &str, key: Option<&str>| {
+            (name.to_string(), key.map(String::from))
+        };
+
+        assert_eq!(
+            join_command(
+                &isupport,
+                &[
+                    channel("#a", Some("key_a")),
+                    channel("#b", None),
+                    channel("#c", Some("key_c")),
+                    channel("#d", None),
+                    channel("#e", Some("key_e")),
+                    channel("#f", None),
+                    channel("#g", None),
+                ]
+            ),
+            Ok(vec![
+                "JOIN #a,#b,#c key_a,,key_c".to_string(),
+                "JOIN #d,#e,#f ,key_e".to_string(),
+                "JOIN #g".to_string(),
+            ])
+        );
+        assert_eq!(
+            join_command(&isupport, &[channel("#a", Some("too_long_key"))]),
+            Err(KeyError::TooLong(8))
+        );
+        assert_eq!(
+            join_command(&isupport, &[channel("#a", Some("a,b"))]),
+            Err(KeyError::InvalidCharacter(','))
+        );
+        assert_eq!(join_command(&isupport, &[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn who_token_feature_round_trip() {
+        let mut allocator = WhoTokenAllocator::default();
+
+        let presence = allocator.allocate(WhoFeature::Presence);
+        let account_sync = allocator.allocate(WhoFeature::AccountSync);
+        let manual = allocator.allocate(WhoFeature::Manual);
+
+        assert_eq!(presence.to_owned(), "100");
+        assert_eq!(account_sync.to_owned(), "200");
+        assert_eq!(manual.to_owned(), "300");
+        assert_eq!(allocator.allocate(WhoFeature::Presence).to_owned(), "101");
+
+        for token in [presence, account_sync, manual] {
+            assert_eq!(token.to_owned().parse::<WhoToken>(), Ok(token));
+        }
+
+        assert_eq!(token_feature(&presence), Some(WhoFeature::Presence));
+        assert_eq!(token_feature(&account_sync), Some(WhoFeature::AccountSync));
+        assert_eq!(token_feature(&manual), Some(WhoFeature::Manual));
+
+        // Tokens wrap within the feature's range
+        for _ in 0..98 {
+            allocator.allocate(WhoFeature::Presence);
+        }
+        assert_eq!(allocator.allocate(WhoFeature::Presence).to_owned(), "100");
+
+        assert_eq!(token_feature(&WhoXPollParameters::Default.token()), None);
+        assert_eq!(token_feature(&"1".parse().unwrap()), None);
+        assert_eq!(token_feature(&"999".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn prepare_topic_cleans_and_limits() {
+        let isupport = isupport(&["TOPICLEN=16"]);
+
+        assert_eq!(
+            prepare_topic(&isupport, "halloy\r\nQUIT"),
+            Ok("halloyQUIT".to_string())
+        );
+        assert_eq!(
+            prepare_topic(&isupport, "\u{2}halloy\u{2} irc"),
+            Ok("\u{2}halloy\u{2} irc".to_string())
+        );
+        assert_eq!(
+            prepare_topic(&isupport, "an irc client written in rust"),
+            Err(TopicError::TooLong {
+                len: 29,
+                max_len: 16
+            })
+        );
+
+        // Multi-byte characters count by bytes
+        assert_eq!(
+            prepare_topic(&isupport, "ü".repeat(9).as_str()),
+            Err(TopicError::TooLong {
+                len: 18,
+                max_len: 16
+            })
+        );
+        assert!(prepare_topic(&HashMap::new(), &"a".repeat(1000)).is_ok());
+    }
+
+    #[test]
+    fn operation_display_round_trip() {
+        let line = ":irc.example.org 005 halloy AWAYLEN=200 CASEMAPPING=rfc1459 CHANLIMIT=#:25,&: CHANMODES=beI,k,l,imnpst CHANNELLEN=64 CHANTYPES=#& CLIENTTAGDENY=*,-draft/react ELIST=CMNTU EXCEPTS=e EXTBAN=~,a INVEX=I MAXLIST=beI:100 MODES=4 MONITOR MSGREFTYPES=msgid,timestamp NETWORK=Libera.Chat NICKLEN=30 PREFIX=(qaohv)~&@%+ STATUSMSG=@+ TARGMAX=JOIN:,PRIVMSG:4 TARGMAX WHOX -KNOCK :are supported by this server\r\n";
+
+        let message = proto::parse::message(line).unwrap();
+
+        let proto::Command::Numeric(
+            proto::command::Numeric::RPL_ISUPPORT,
+            args,
+        ) = message.command
+        else {
+            panic!("expected RPL_ISUPPORT");
+        };
+
+        let tokens = &args[1..args.len() - 1];
+
+        assert_eq!(tokens.len(), 23);
+
+        for token in tokens {
+            let operation = token.parse::<Operation>().unwrap();
+
+            assert_eq!(&operation.to_string(), token);
+
+            let reparsed = operation.to_string().parse::<Operation>().unwrap();
+
+            assert_eq!(reparsed.kind(), operation.kind());
+            assert_eq!(reparsed.to_string(), operation.to_string());
+        }
+
+        assert_eq!(Operation::Remove("WHOX".to_string()).to_string(), "-WHOX");
+    }
+
+    #[test]
+    fn chanlimit_wildcard_is_unlimited() {
+        let joined = vec!["#halloy".to_string(), "#rust".to_string()];
+
+        for token in ["CHANLIMIT=#:", "CHANLIMIT=#:*"] {
+            let isupport = isupport(&[token]);
+
+            assert_eq!(remaining_channel_slots(&isupport, &joined, '#'), None);
+            assert_eq!(isupport[&Kind::CHANLIMIT].to_string(), "CHANLIMIT=#:");
+        }
+
+        let isupport = isupport(&["CHANLIMIT=#:10"]);
+
+        assert_eq!(remaining_channel_slots(&isupport, &joined, '#'), Some(8));
+    }
+
+    #[test]
+    fn isupport_applies_operations() {
+        let mut isupport = ISupport::default();
+
+        for token in [
+            "CASEMAPPING=ascii",
+            "NICKLEN=30",
+            "TARGMAX=PRIVMSG:4",
+            "PREFIX=(ov)@+",
+            "NICKLEN=16",
+            "-TARGMAX",
+        ] {
+            isupport.insert(token.parse::<Operation>().unwrap());
+        }
+
+        assert_eq!(isupport.casemapping(), CaseMap::ASCII);
+        assert_eq!(isupport.nicklen(), 16);
+        assert_eq!(isupport.target_limit("PRIVMSG"), None);
+        assert!(!isupport.contains(&Kind::TARGMAX));
+        assert_eq!(
+            isupport
+                .prefix()
+                .iter()
+                .map(|p| p.prefix)
+                .collect::<Vec<_>>(),
+            vec!['@', '+']
+        );
+
+        assert!(isupport.remove("PREFIX").is_some());
+        assert!(isupport.remove("PREFIX").is_none());
+        assert!(!isupport.contains(&Kind::PREFIX));
+        assert_eq!(isupport.as_map().len(), 2);
+    }
+
+    #[test]
+    fn isupport_from_map_drops_mismatched_entries() {
+        let mut map = isupport(&["NICKLEN=30", "CASEMAPPING=ascii"]);
+        map.insert(Kind::CHANTYPES, Parameter::NICKLEN(9));
+
+        let isupport = ISupport::from(map);
+
+        assert!(!isupport.contains(&Kind::CHANTYPES));
+        assert_eq!(isupport.chantypes(), proto::DEFAULT_CHANNEL_PREFIXES);
+        assert_eq!(isupport.nicklen(), 30);
+        assert_eq!(isupport.casemapping(), CaseMap::ASCII);
+    }
+}
@@ -188,6 +188,271 @@ pub fn parse(
     Some(fragments)
 }
 
+/// Splits `text` into lines of at most `max_bytes` bytes without breaking
+/// formatting codes apart.  Formatting active at a split is reset at the end
+/// of the line and re-emitted at the start of the next, so each line renders
+/// independently.
+pub fn split_message(text: &str, max_bytes: usize) -> Vec<String> {
+    split(text, max_bytes, false)
+}
+
+/// Like [`split_message`], but lines are split at whitespace in their latter
+/// half when possible, in which case the whitespace is dropped.
+pub fn split_message_at_whitespace(
+    text: &str,
+    max_bytes: usize,
+) -> Vec<String> {
+    split(text, max_bytes, true)
+}
+
+fn split(text: &str, max_bytes: usize, at_whitespace: bool) -> Vec<String> {
+    let reset_len = Modifier::Reset.char().len_utf8();
+
+    let mut lines = vec![];
+    let mut line = String::new();
+    let mut line_has_text = false;
+    let mut active = ActiveFormatting::default();
+    // Formatting as of the last text in the line, and where the codes that
+    // follow it begin (these are dropped on a split, since the active
+    // formatting is re-emitted at the start of the next line)
+    let mut settled = ActiveFormatting::default();
+    let mut trailing = 0;
+    // Last whitespace in the latter half of the line
+    let mut whitespace: Option<Break> = None;
+
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let code = Code::parse(rest);
+        let len = code.as_ref().map_or(c.len_utf8(), |(_, len)| *len);
+        let (unit, remaining) = rest.split_at(len);
+        rest = remaining;
+
+        let mut next = active.clone();
+        if let Some((code, _)) = code {
+            next.apply(code);
+        }
+
+        // Room is kept for a reset whenever formatting would be active
+        let reserved = if next.is_empty() { 0 } else { reset_len };
+
+        let is_whitespace =
+            at_whitespace && code.is_none() && c.is_whitespace();
+
+        if line_has_text && line.len() + unit.len() + reserved > max_bytes {
+            // Prefer splitting at earlier whitespace, re-processing the text
+            // that follows it, unless this is whitespace itself
+            let earlier = if is_whitespace {
+                None
+            } else {
+                whitespace.take()
+            };
+            let resplit = earlier.is_some();
+
+            // A code at the split is folded into the re-emitted formatting
+            let point = earlier.unwrap_or_else(|| Break {
+                trailing,
+                settled: settled.clone(),
+                active: if code.is_some() {
+                    next.clone()
+                } else {
+                    active.clone()
+                },
+                rest,
+            });
+
+            line.truncate(point.trailing);
+
+            if !point.settled.is_empty() {
+                line.push(Modifier::Reset.char());
+            }
+
+            lines.push(mem::take(&mut line));
+
+            active = point.active;
+            rest = point.rest;
+            line = active.codes();
+            line_has_text = false;
+            settled = active.clone();
+            trailing = line.len();
+            whitespace = None;
+
+            // Whitespace at a split is dropped
+            if resplit || code.is_some() || is_whitespace {
+                continue;
+            }
+        }
+
+        if is_whitespace && line_has_text && line.len() >= max_bytes / 2 {
+            whitespace = Some(Break {
+                trailing,
+                settled: settled.clone(),
+                active: next.clone(),
+                rest,
+            });
+        }
+
+        line.push_str(unit);
+        active = next;
+
+        if code.is_none() {
+            line_has_text = true;
+            settled = active.clone();
+            trailing = line.len();
+        }
+    }
+
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+// Where a line is split: the length of the line up to the split, the
+// formatting as of its last text and as of the split, and the text following
+// the split
+struct Break<'a> {
+    trailing: usize,
+    settled: ActiveFormatting,
+    active: ActiveFormatting,
+    rest: &'a str,
+}
+
+#[derive(Clone, Copy)]
+enum Code<'a> {
+    Toggle(Modifier),
+    Reset,
+    Color {
+        modifier: Modifier,
+        fg: Option<&'a str>,
+        bg: Option<&'a str>,
+    },
+}
+
+impl<'a> Code<'a> {
+    // Formatting code at the start of text (if any), with its length in bytes
+    fn parse(text: &'a str) -> Option<(Self, usize)> {
+        let modifier = Modifier::try_from(text.chars().next()?).ok()?;
+
+        let (digits, is_digit): (usize, fn(&u8) -> bool) = match modifier {
+            Modifier::Reset => return Some((Code::Reset, 1)),
+            Modifier::Color => (2, u8::is_ascii_digit),
+            Modifier::HexColor => (6, u8::is_ascii_hexdigit),
+            _ => return Some((Code::Toggle(modifier), 1)),
+        };
+
+        // Colors are a fixed number of hex digits, or 1-2 digits
+        let component = |start: usize| {
+            let len = text.as_bytes()[start..]
+                .iter()
+                .take(digits)
+                .take_while(|byte| is_digit(byte))
+                .count();
+
+            let valid = match modifier {
+                Modifier::HexColor => len == digits,
+                _ => len > 0,
+            };
+
+            valid.then(|| &text[start..start + len])
+        };
+
+        let Some(fg) = component(1) else {
+            return Some((
+                Code::Color {
+                    modifier,
+                    fg: None,
+                    bg: None,
+                },
+                1,
+            ));
+        };
+
+        let mut len = 1 + fg.len();
+
+        let bg = if text.as_bytes().get(len) == Some(&b',') {
+            component(len + 1)
+        } else {
+            None
+        };
+
+        if let Some(bg) = bg {
+            len += 1 + bg.len();
+        }
+
+        Some((
+            Code::Color {
+                modifier,
+                fg: Some(fg),
+                bg,
+            },
+            len,
+        ))
+    }
+}
+
+#[derive(Clone, Default)]
+struct ActiveFormatting {
+    toggles: Vec<Modifier>,
+    // Color code kind with its foreground and (optional) background
+    color: Option<(Modifier, String, Option<String>)>,
+}
+
+impl ActiveFormatting {
+    fn is_empty(&self) -> bool {
+        self.toggles.is_empty() && self.color.is_none()
+    }
+
+    fn apply(&mut self, code: Code) {
+        match code {
+            Code::Reset => *self = Self::default(),
+            Code::Toggle(modifier) => {
+                if let Some(index) =
+                    self.toggles.iter().position(|toggle| *toggle == modifier)
+                {
+                    self.toggles.remove(index);
+                } else {
+                    self.toggles.push(modifier);
+                }
+            }
+            Code::Color { fg: None, .. } => self.color = None,
+            Code::Color {
+                modifier,
+                fg: Some(fg),
+                bg,
+            } => {
+                // A color without background keeps the current background
+                let bg = bg.map(String::from).or_else(|| {
+                    self.color
+                        .take()
+                        .filter(|(current, _, _)| *current == modifier)
+                        .and_then(|(_, _, bg)| bg)
+                });
+
+                self.color = Some((modifier, fg.to_string(), bg));
+            }
+        }
+    }
+
+    fn codes(&self) -> String {
+        let mut codes =
+            self.toggles.iter().map(Modifier::char).collect::<String>();
+
+        if let Some((modifier, fg, bg)) = &self.color {
+            codes.push(modifier.char());
+            codes.push_str(fg);
+
+            if let Some(bg) = bg {
+                codes.push(',');
+                codes.push_str(bg);
+            }
+        }
+
+        codes
+    }
+}
+
 #[derive(
     Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize,
 )]
@@ -705,3 +970,91 @@ impl Color {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{split_message, split_message_at_whitespace};
+
+    #[test]
+    fn split_message_plain() {
+        assert_eq!(
+            split_message("hello there friend", 8),
+            vec!["hello th", "ere frie", "nd"]
+        );
+        assert_eq!(split_message("", 8), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_message_color_across_boundary() {
+        let lines = split_message("\u{3}04,02red on blue text\u{f} plain", 16);
+
+        assert_eq!(
+            lines,
+            vec![
+                "\u{3}04,02red on bl\u{f}",
+                "\u{3}04,02ue text\u{f} p",
+                "lain",
+            ]
+        );
+        assert!(lines.iter().all(|line| line.len() <= 16));
+    }
+
+    #[test]
+    fn split_message_keeps_codes_whole() {
+        // The color code is not split from its digits
+        assert_eq!(
+            split_message("abcde\u{3}12blue", 8),
+            vec!["abcde", "\u{3}12blue"]
+        );
+
+        // Toggles and a foreground-only color (keeping the background) are
+        // re-emitted at the start of each continuation line, and codes at a
+        // split are not left dangling at the end of a line
+        assert_eq!(
+            split_message("\u{2}bold\u{3}04,02red\u{3}05text", 12),
+            vec![
+                "\u{2}bold\u{f}",
+                "\u{2}\u{3}04,02red\u{f}",
+                "\u{2}\u{3}05,02text",
+            ]
+        );
+    }
+
+    #[test]
+    fn split_message_at_whitespace_plain() {
+        assert_eq!(
+            split_message_at_whitespace("hello there friend", 8),
+            vec!["hello", "there", "friend"]
+        );
+        assert_eq!(
+            split_message_at_whitespace("abcdefghij", 4),
+            vec!["abcd", "efgh", "ij"]
+        );
+
+        // Whitespace early in a line would leave it mostly empty
+        assert_eq!(
+            split_message_at_whitespace("a bcdefghij", 8),
+            vec!["a bcdefg", "hij"]
+        );
+    }
+
+    #[test]
+    fn split_message_at_whitespace_keeps_formatting() {
+        let lines = split_message_at_whitespace(
+            "\u{3}04,02red on blue text\u{f} plain",
+            16,
+        );
+
+        assert_eq!(
+            lines,
+            vec!["\u{3}04,02red on\u{f}", "\u{3}04,02blue text\u{f}", "plain"]
+        );
+        assert!(lines.iter().all(|line| line.len() <= 16));
+
+        // Formatting at the whitespace carries over to the next line
+        assert_eq!(
+            split_message_at_whitespace("abcd\u{2}efgh\u{2} ijkl", 12),
+            vec!["abcd\u{2}efgh\u{f}", "ijkl"]
+        );
+    }
+}
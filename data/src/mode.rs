@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt;
 
 use irc::proto;
@@ -282,6 +283,59 @@ impl Parser for User {
     }
 }
 
+impl Parser for char {
+    fn from_char(c: char) -> Self {
+        c
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelSettings {
+    pub key: Option<String>,
+    pub limit: Option<u32>,
+    pub flags: Vec<char>,
+}
+
+// Flags are the CHANMODES group D (no argument) modes, kept in the order they
+// were set
+pub fn extract_channel_settings(
+    isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    changes: &[Mode<char>],
+) -> ChannelSettings {
+    let chanmodes = isupport::get_chanmodes_or_default(isupport);
+
+    let is_flag = |mode: char| {
+        chanmodes.iter().any(|chanmode| {
+            chanmode.kind == 'D' && chanmode.modes.contains(mode)
+        })
+    };
+
+    let mut settings = ChannelSettings::default();
+
+    for change in changes {
+        match change {
+            Mode::Add('k', key) => settings.key.clone_from(key),
+            Mode::Remove('k', _) => settings.key = None,
+            Mode::Add('l', limit) => {
+                settings.limit =
+                    limit.as_deref().and_then(|limit| limit.parse().ok());
+            }
+            Mode::Remove('l', _) => settings.limit = None,
+            Mode::Add(mode, _) if is_flag(*mode) => {
+                if !settings.flags.contains(mode) {
+                    settings.flags.push(*mode);
+                }
+            }
+            Mode::Remove(mode, _) if is_flag(*mode) => {
+                settings.flags.retain(|flag| flag != mode);
+            }
+            _ => (),
+        }
+    }
+
+    settings
+}
+
 enum ModeSet<'a> {
     Plus(&'a str),
     Minus(&'a str),
@@ -350,35 +404,50 @@ where
     parsed
 }
 
+// Whether the mode takes an argument when added or removed (see takes_arg)
+pub(crate) fn takes_argument(
+    mode: char,
+    adding: bool,
+    chanmodes: &[isupport::ModeKind],
+    prefix: &[isupport::PrefixMap],
+) -> bool {
+    let mode_set = if adding {
+        ModeSet::Plus("")
+    } else {
+        ModeSet::Minus("")
+    };
+
+    takes_arg(mode, &mode_set, chanmodes, prefix)
+}
+
+// PREFIX modes always take an argument, even if (erroneously) also listed in
+// CHANMODES
 fn takes_arg(
     mode: char,
     mode_set: &ModeSet,
     chanmodes: &[isupport::ModeKind],
     prefix: &[isupport::PrefixMap],
 ) -> bool {
-    let known = if let Some(kind) = chanmodes.iter().find_map(|chanmode| {
-        if chanmode.modes.chars().any(|m| m == mode) {
-            Some(chanmode.kind)
-        } else {
-            None
-        }
-    }) {
-        match kind {
+    if prefix.iter().any(|prefix_map| prefix_map.mode == mode) {
+        return true;
+    }
+
+    let known = chanmodes
+        .iter()
+        .find_map(|chanmode| {
+            if chanmode.modes.chars().any(|m| m == mode) {
+                Some(chanmode.kind)
+            } else {
+                None
+            }
+        })
+        .and_then(|kind| match kind {
             'A' => Some(!matches!(mode_set, ModeSet::None(_))),
             'B' => Some(true),
             'C' => Some(matches!(mode_set, ModeSet::Plus(_))),
             'D' => Some(false),
             _ => None,
-        }
-    } else {
-        prefix.iter().find_map(|prefix_map| {
-            if prefix_map.mode == mode {
-                Some(true)
-            } else {
-                None
-            }
-        })
-    };
+        });
 
     known.unwrap_or(false)
 }
@@ -418,4 +487,70 @@ mod test {
             assert_eq!(modes, expected);
         }
     }
+
+    #[test]
+    fn channel_settings() {
+        let isupport = HashMap::<isupport::Kind, isupport::Parameter>::new();
+
+        let changes = parse::<char>(
+            "+ntkl",
+            &["secret".into(), "50".into()],
+            isupport::get_chanmodes_or_default(&isupport),
+            isupport::get_prefix_or_default(&isupport),
+        );
+
+        assert_eq!(
+            extract_channel_settings(&isupport, &changes),
+            ChannelSettings {
+                key: Some("secret".into()),
+                limit: Some(50),
+                flags: vec!['n', 't'],
+            }
+        );
+
+        let changes = parse::<char>(
+            "+m-kt",
+            &["secret".into()],
+            isupport::get_chanmodes_or_default(&isupport),
+            isupport::get_prefix_or_default(&isupport),
+        );
+
+        assert_eq!(
+            extract_channel_settings(&isupport, &changes),
+            ChannelSettings {
+                key: None,
+                limit: None,
+                flags: vec!['m'],
+            }
+        );
+    }
+
+    #[test]
+    fn prefix_mode_listed_in_chanmodes() {
+        let chanmodes = [
+            isupport::ModeKind {
+                kind: 'A',
+                modes: "b".into(),
+            },
+            isupport::ModeKind {
+                kind: 'D',
+                modes: "ont".into(),
+            },
+        ];
+        let prefix = [isupport::PrefixMap {
+            prefix: '@',
+            mode: 'o',
+        }];
+
+        let modes = parse::<char>("+ont", &["dan".into()], &chanmodes, &prefix);
+
+        assert_eq!(
+            modes,
+            vec![
+                Mode::Add('@', Some("dan".into())),
+                Mode::Add('n', None),
+                Mode::Add('t', None),
+            ]
+        );
+    }
 }
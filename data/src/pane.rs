@@ -1,8 +1,12 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Deserialize, Serialize};
 
-use crate::Buffer;
+use crate::buffer::Upstream;
+use crate::target::{self, TargetKind};
+use crate::{Buffer, isupport};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum Pane {
     Split {
         axis: Axis,
@@ -16,8 +20,1386 @@ pub enum Pane {
     Empty,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Axis {
     Horizontal,
     Vertical,
 }
+
+// Child of a Split taken at each step of a path from the root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    A,
+    B,
+}
+
+// Matches the spacing between panes in the pane grid
+pub const SPACING: u16 = 4;
+
+// Layouts with more nested splits than this are assumed to be corrupt
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PaneError {
+    #[error("split with two empty panes")]
+    EmptySplit,
+    #[error("split ratio {0} outside of (0, 1)")]
+    InvalidRatio(f32),
+    #[error("too many nested splits ({0})")]
+    TooDeep(usize),
+    #[error("no pane at path")]
+    InvalidPath,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    // Horizontal splits stack a above b, vertical splits place a left of b,
+    // with the ratio applied to the space remaining after SPACING
+    fn split(self, axis: Axis, ratio: f32) -> (Rect, Rect) {
+        match axis {
+            Axis::Horizontal => {
+                let available = self.height.saturating_sub(SPACING);
+                let a_height = ((f32::from(available) * ratio).round() as u16)
+                    .min(available);
+
+                (
+                    Rect {
+                        height: a_height,
+                        ..self
+                    },
+                    Rect {
+                        y: self.y.saturating_add(a_height + SPACING),
+                        height: available - a_height,
+                        ..self
+                    },
+                )
+            }
+            Axis::Vertical => {
+                let available = self.width.saturating_sub(SPACING);
+                let a_width = ((f32::from(available) * ratio).round() as u16)
+                    .min(available);
+
+                (
+                    Rect {
+                        width: a_width,
+                        ..self
+                    },
+                    Rect {
+                        x: self.x.saturating_add(a_width + SPACING),
+                        width: available - a_width,
+                        ..self
+                    },
+                )
+            }
+        }
+    }
+
+    fn fits(self, (min_width, min_height): (u16, u16)) -> bool {
+        self.width >= min_width && self.height >= min_height
+    }
+}
+
+// Buffers placed by Pane::layout_rects, along with those left out of the
+// layout by collapsed splits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Layout {
+    pub rects: Vec<(Buffer, Rect)>,
+    pub hidden: Vec<Buffer>,
+}
+
+// Where a newly opened buffer is placed, relative to the pane at the path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenTarget {
+    FillEmpty(Vec<Direction>),
+    SplitLeaf(Vec<Direction>),
+    ReplaceCurrent(Vec<Direction>),
+}
+
+// Pre-order description of a Pane tree, where each Split is followed by the
+// ops for its a and b children
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaneOp {
+    Split(Axis, f32),
+    Buffer(Buffer),
+    Empty,
+}
+
+impl Pane {
+    // True when the tree contains no buffers (i.e. nothing to focus)
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Pane::Split { a, b, .. } => a.is_empty() && b.is_empty(),
+            Pane::Buffer { .. } => false,
+            Pane::Empty => true,
+        }
+    }
+
+    // Path to the first buffer in pre-order, for use as a focus fallback
+    pub fn first_buffer(&self) -> Option<Vec<Direction>> {
+        match self {
+            Pane::Split { a, b, .. } => {
+                let (direction, mut path) =
+                    a.first_buffer().map(|path| (Direction::A, path)).or_else(
+                        || b.first_buffer().map(|path| (Direction::B, path)),
+                    )?;
+
+                path.insert(0, direction);
+
+                Some(path)
+            }
+            Pane::Buffer { .. } => Some(vec![]),
+            Pane::Empty => None,
+        }
+    }
+
+    pub fn buffers(&self) -> impl Iterator<Item = &Buffer> {
+        self.iter_paths().map(|(_, buffer)| buffer)
+    }
+
+    pub fn shares_buffer(&self, other: &Pane) -> bool {
+        let buffers = self.buffers().collect::<HashSet<_>>();
+
+        other.buffers().any(|buffer| buffers.contains(buffer))
+    }
+
+    // Buffers in pre-order, each with the path to its leaf
+    pub fn iter_paths(
+        &self,
+    ) -> impl Iterator<Item = (Vec<Direction>, &Buffer)> {
+        let mut paths = vec![];
+
+        self.push_paths(&mut vec![], &mut paths);
+
+        paths.into_iter()
+    }
+
+    fn push_paths<'a>(
+        &'a self,
+        path: &mut Vec<Direction>,
+        paths: &mut Vec<(Vec<Direction>, &'a Buffer)>,
+    ) {
+        match self {
+            Pane::Split { a, b, .. } => {
+                path.push(Direction::A);
+                a.push_paths(path, paths);
+                path.pop();
+
+                path.push(Direction::B);
+                b.push_paths(path, paths);
+                path.pop();
+            }
+            Pane::Buffer { buffer } => paths.push((path.clone(), buffer)),
+            Pane::Empty => (),
+        }
+    }
+
+    // Splits in pre-order, each with the path to its node
+    pub fn splits(&self) -> impl Iterator<Item = (Vec<Direction>, Axis, f32)> {
+        let mut splits = vec![];
+
+        self.push_splits(&mut vec![], &mut splits);
+
+        splits.into_iter()
+    }
+
+    fn push_splits(
+        &self,
+        path: &mut Vec<Direction>,
+        splits: &mut Vec<(Vec<Direction>, Axis, f32)>,
+    ) {
+        if let Pane::Split { axis, ratio, a, b } = self {
+            splits.push((path.clone(), *axis, *ratio));
+
+            path.push(Direction::A);
+            a.push_splits(path, splits);
+            path.pop();
+
+            path.push(Direction::B);
+            b.push_splits(path, splits);
+            path.pop();
+        }
+    }
+
+    // Channel buffers, classifying each target by name against the server's
+    // CHANTYPES (rather than the buffer's variant, which may have been
+    // determined before ISUPPORT was received)
+    pub fn channel_buffers<'a>(
+        &'a self,
+        isupport: &'a HashMap<isupport::Kind, isupport::Parameter>,
+    ) -> impl Iterator<Item = &'a Buffer> {
+        self.buffers().filter(|buffer| {
+            let target = match buffer {
+                Buffer::Upstream(Upstream::Channel(_, channel)) => {
+                    channel.as_str()
+                }
+                Buffer::Upstream(Upstream::Query(_, query)) => query.as_str(),
+                Buffer::Upstream(Upstream::Server(_)) | Buffer::Internal(_) => {
+                    return false;
+                }
+            };
+
+            target::route_target(isupport, target).kind == TargetKind::Channel
+        })
+    }
+
+    // Checks the invariants the layout helpers maintain
+    pub fn validate(&self) -> Result<(), PaneError> {
+        self.validate_at(1)
+    }
+
+    fn validate_at(&self, depth: usize) -> Result<(), PaneError> {
+        let Pane::Split { ratio, a, b, .. } = self else {
+            return Ok(());
+        };
+
+        if depth > MAX_DEPTH {
+            return Err(PaneError::TooDeep(depth));
+        }
+
+        if !(*ratio > 0.0 && *ratio < 1.0) {
+            return Err(PaneError::InvalidRatio(*ratio));
+        }
+
+        if matches!((a.as_ref(), b.as_ref()), (Pane::Empty, Pane::Empty)) {
+            return Err(PaneError::EmptySplit);
+        }
+
+        a.validate_at(depth + 1)?;
+        b.validate_at(depth + 1)
+    }
+
+    // Brings a layout (e.g. as saved) back in line with the invariants checked
+    // by validate, returning the violations found: empty splits are
+    // normalized away, invalid ratios are reset to an even split, and splits
+    // nested too deeply are replaced by an empty pane
+    pub fn repair(&mut self) -> Vec<PaneError> {
+        let mut errors = vec![];
+
+        self.repair_at(1, &mut errors);
+        self.normalize();
+
+        errors
+    }
+
+    fn repair_at(&mut self, depth: usize, errors: &mut Vec<PaneError>) {
+        let Pane::Split { ratio, a, b, .. } = self else {
+            return;
+        };
+
+        if depth > MAX_DEPTH {
+            errors.push(PaneError::TooDeep(depth));
+            *self = Pane::Empty;
+
+            return;
+        }
+
+        if !(*ratio > 0.0 && *ratio < 1.0) {
+            errors.push(PaneError::InvalidRatio(*ratio));
+            *ratio = 0.5;
+        }
+
+        a.repair_at(depth + 1, errors);
+        b.repair_at(depth + 1, errors);
+    }
+
+    pub fn get(&self, path: &[Direction]) -> Option<&Pane> {
+        match (self, path.split_first()) {
+            (_, None) => Some(self),
+            (Pane::Split { a, .. }, Some((Direction::A, rest))) => a.get(rest),
+            (Pane::Split { b, .. }, Some((Direction::B, rest))) => b.get(rest),
+            (Pane::Buffer { .. } | Pane::Empty, Some(_)) => None,
+        }
+    }
+
+    fn get_mut(&mut self, path: &[Direction]) -> Option<&mut Pane> {
+        match (self, path.split_first()) {
+            (pane, None) => Some(pane),
+            (Pane::Split { a, .. }, Some((Direction::A, rest))) => {
+                a.get_mut(rest)
+            }
+            (Pane::Split { b, .. }, Some((Direction::B, rest))) => {
+                b.get_mut(rest)
+            }
+            (Pane::Buffer { .. } | Pane::Empty, Some(_)) => None,
+        }
+    }
+
+    // Removes the pane at path (e.g. to move it into a new window), leaving
+    // Empty in its place
+    pub fn take_subtree(
+        &mut self,
+        path: &[Direction],
+    ) -> Result<Pane, PaneError> {
+        let pane = self.get_mut(path).ok_or(PaneError::InvalidPath)?;
+
+        let subtree = std::mem::replace(pane, Pane::Empty);
+
+        self.normalize();
+
+        Ok(subtree)
+    }
+
+    // Collapses splits left with two empty panes into Empty
+    pub fn normalize(&mut self) {
+        if let Pane::Split { a, b, .. } = self {
+            a.normalize();
+            b.normalize();
+
+            if matches!((a.as_ref(), b.as_ref()), (Pane::Empty, Pane::Empty)) {
+                *self = Pane::Empty;
+            }
+        }
+    }
+
+    // Path (in the tree before removal) of the buffer to focus once the buffer
+    // at removed is closed: the first buffer of its sibling, else the
+    // previous buffer in pre-order, else the next one
+    pub fn focus_after_removal(
+        &self,
+        removed: &[Direction],
+    ) -> Option<Vec<Direction>> {
+        if let Some((last, parent)) = removed.split_last() {
+            let sibling = match last {
+                Direction::A => Direction::B,
+                Direction::B => Direction::A,
+            };
+
+            let mut sibling_path = parent.to_vec();
+            sibling_path.push(sibling);
+
+            if let Some(path) =
+                self.get(&sibling_path).and_then(Pane::first_buffer)
+            {
+                sibling_path.extend(path);
+
+                return Some(sibling_path);
+            }
+        }
+
+        let paths = self.iter_paths().map(|(path, _)| path).collect::<Vec<_>>();
+        let position = paths.iter().position(|path| path == removed)?;
+
+        position
+            .checked_sub(1)
+            .or((position + 1 < paths.len()).then_some(position + 1))
+            .map(|position| paths[position].clone())
+    }
+
+    // Fills the focused pane if it is empty, otherwise splits it unless that
+    // would nest splits too deeply, in which case its buffer is replaced; if
+    // the focused path is not a leaf, the first buffer is used in its place
+    pub fn open_target(&self, focused: &[Direction]) -> OpenTarget {
+        let path = match self.get(focused) {
+            Some(Pane::Empty) => {
+                return OpenTarget::FillEmpty(focused.to_vec());
+            }
+            Some(Pane::Buffer { .. }) => focused.to_vec(),
+            Some(Pane::Split { .. }) | None => match self.first_buffer() {
+                Some(path) => path,
+                // No buffers, so the whole tree is filled
+                None => return OpenTarget::FillEmpty(vec![]),
+            },
+        };
+
+        if path.len() < MAX_DEPTH {
+            OpenTarget::SplitLeaf(path)
+        } else {
+            OpenTarget::ReplaceCurrent(path)
+        }
+    }
+
+    // Replaces every occurrence of the buffer, returning whether any was found
+    pub fn replace_buffer(&mut self, old: &Buffer, new: &Buffer) -> bool {
+        match self {
+            Pane::Split { a, b, .. } => {
+                let replaced_a = a.replace_buffer(old, new);
+                let replaced_b = b.replace_buffer(old, new);
+
+                replaced_a || replaced_b
+            }
+            Pane::Buffer { buffer } if buffer == old => {
+                *buffer = new.clone();
+
+                true
+            }
+            Pane::Buffer { .. } | Pane::Empty => false,
+        }
+    }
+
+    // Splits that would give either side less than min_size (width, height)
+    // are collapsed to their larger side, which takes the whole area
+    pub fn layout_rects(&self, area: Rect, min_size: (u16, u16)) -> Layout {
+        let mut layout = Layout::default();
+
+        self.push_rects(area, min_size, &mut layout);
+
+        layout
+    }
+
+    fn push_rects(
+        &self,
+        area: Rect,
+        min_size: (u16, u16),
+        layout: &mut Layout,
+    ) {
+        match self {
+            Pane::Split { axis, ratio, a, b } => {
+                let (a_area, b_area) = area.split(*axis, *ratio);
+
+                if a_area.fits(min_size) && b_area.fits(min_size) {
+                    a.push_rects(a_area, min_size, layout);
+                    b.push_rects(b_area, min_size, layout);
+                } else {
+                    let (shown, hidden) = match axis {
+                        Axis::Horizontal if a_area.height >= b_area.height => {
+                            (a, b)
+                        }
+                        Axis::Vertical if a_area.width >= b_area.width => {
+                            (a, b)
+                        }
+                        Axis::Horizontal | Axis::Vertical => (b, a),
+                    };
+
+                    shown.push_rects(area, min_size, layout);
+                    layout.hidden.extend(hidden.buffers().cloned());
+                }
+            }
+            Pane::Buffer { buffer } => {
+                layout.rects.push((buffer.clone(), area))
+            }
+            Pane::Empty => (),
+        }
+    }
+
+    pub fn buffer_widths(&self, area: Rect) -> Vec<(Buffer, u16)> {
+        self.layout_rects(area, (0, 0))
+            .rects
+            .into_iter()
+            .map(|(buffer, rect)| (buffer, rect.width))
+            .collect()
+    }
+
+    pub fn to_ops(&self) -> Vec<PaneOp> {
+        let mut ops = vec![];
+
+        self.push_ops(&mut ops);
+
+        ops
+    }
+
+    fn push_ops(&self, ops: &mut Vec<PaneOp>) {
+        match self {
+            Pane::Split { axis, ratio, a, b } => {
+                ops.push(PaneOp::Split(*axis, *ratio));
+                a.push_ops(ops);
+                b.push_ops(ops);
+            }
+            Pane::Buffer { buffer } => ops.push(PaneOp::Buffer(buffer.clone())),
+            Pane::Empty => ops.push(PaneOp::Empty),
+        }
+    }
+
+    // Missing trailing ops (from a truncated list) are filled with Empty
+    pub fn from_ops(ops: &[PaneOp]) -> Pane {
+        Pane::pop_ops(&mut ops.iter())
+    }
+
+    fn pop_ops<'a>(ops: &mut impl Iterator<Item = &'a PaneOp>) -> Pane {
+        match ops.next() {
+            Some(PaneOp::Split(axis, ratio)) => {
+                let a = Pane::pop_ops(ops);
+                let b = Pane::pop_ops(ops);
+
+                Pane::Split {
+                    axis: *axis,
+                    ratio: *ratio,
+                    a: Box::new(a),
+                    b: Box::new(b),
+                }
+            }
+            Some(PaneOp::Buffer(buffer)) => Pane::Buffer {
+                buffer: buffer.clone(),
+            },
+            Some(PaneOp::Empty) | None => Pane::Empty,
+        }
+    }
+
+    // Buffers are placed in reading order into evenly sized cells, with any
+    // cells left over in the last row filled with Empty
+    pub fn grid(buffers: &[Buffer], columns: usize) -> Pane {
+        if buffers.is_empty() {
+            return Pane::Empty;
+        }
+
+        // More columns than buffers would leave entire columns empty
+        let columns = columns.clamp(1, buffers.len());
+        let rows = buffers.len().div_ceil(columns);
+
+        let column_panes = (0..columns)
+            .map(|column| {
+                let cells = (0..rows)
+                    .map(|row| {
+                        buffers.get(row * columns + column).map_or(
+                            Pane::Empty,
+                            |buffer| Pane::Buffer {
+                                buffer: buffer.clone(),
+                            },
+                        )
+                    })
+                    .collect();
+
+                Pane::even_split(Axis::Horizontal, cells)
+            })
+            .collect();
+
+        Pane::even_split(Axis::Vertical, column_panes)
+    }
+
+    // Balanced tree of splits giving each pane an equal share of the space
+    fn even_split(axis: Axis, mut panes: Vec<Pane>) -> Pane {
+        if panes.len() <= 1 {
+            return panes.pop().unwrap_or(Pane::Empty);
+        }
+
+        let b = panes.split_off(panes.len() / 2);
+        let ratio = panes.len() as f32 / (panes.len() + b.len()) as f32;
+
+        Pane::Split {
+            axis,
+            ratio,
+            a: Box::new(Pane::even_split(axis, panes)),
+            b: Box::new(Pane::even_split(axis, b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Server;
+    use crate::buffer::Internal;
+
+    fn server_buffer(name: &str) -> Buffer {
+        Buffer::Upstream(Upstream::Server(Server::from(
+            crate::server::ServerName::from(name),
+        )))
+    }
+
+    fn buffer(buffer: Buffer) -> Box<Pane> {
+        Box::new(Pane::Buffer { buffer })
+    }
+
+    #[test]
+    fn ops_round_trip() {
+        let layouts = [
+            Pane::Empty,
+            Pane::Buffer {
+                buffer: Buffer::Internal(Internal::Logs),
+            },
+            Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: buffer(server_buffer("libera")),
+                b: Box::new(Pane::Empty),
+            },
+            Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.3,
+                a: Box::new(Pane::Split {
+                    axis: Axis::Vertical,
+                    ratio: 0.25,
+                    a: buffer(server_buffer("libera")),
+                    b: buffer(Buffer::Internal(Internal::Highlights)),
+                }),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Horizontal,
+                    ratio: 0.75,
+                    a: buffer(Buffer::Internal(Internal::FileTransfers)),
+                    b: buffer(server_buffer("oftc")),
+                }),
+            },
+        ];
+
+        for layout in layouts {
+            assert_eq!(Pane::from_ops(&layout.to_ops()), layout);
+        }
+    }
+
+    #[test]
+    fn ops_pre_order() {
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Empty),
+        };
+
+        assert_eq!(
+            layout.to_ops(),
+            vec![
+                PaneOp::Split(Axis::Vertical, 0.5),
+                PaneOp::Buffer(Buffer::Internal(Internal::Logs)),
+                PaneOp::Empty,
+            ]
+        );
+    }
+
+    #[test]
+    fn buffer_widths_match_layout_rects() {
+        let area = Rect {
+            x: 0,
+            y: 0,
+            width: 1000,
+            height: 600,
+        };
+
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: buffer(Buffer::Internal(Internal::Highlights)),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Vertical,
+                    ratio: 0.25,
+                    a: buffer(Buffer::Internal(Internal::FileTransfers)),
+                    b: buffer(server_buffer("libera")),
+                }),
+            }),
+        };
+
+        let widths = layout.buffer_widths(area);
+
+        assert_eq!(
+            widths,
+            layout
+                .layout_rects(area, (0, 0))
+                .rects
+                .into_iter()
+                .map(|(buffer, rect)| (buffer, rect.width))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            widths,
+            vec![
+                (Buffer::Internal(Internal::Logs), 498),
+                (Buffer::Internal(Internal::Highlights), 498),
+                (Buffer::Internal(Internal::FileTransfers), 124),
+                (server_buffer("libera"), 370),
+            ]
+        );
+    }
+
+    #[test]
+    fn first_buffer_empty_tree() {
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: Box::new(Pane::Empty),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: Box::new(Pane::Empty),
+                b: Box::new(Pane::Empty),
+            }),
+        };
+
+        assert!(layout.is_empty());
+        assert_eq!(layout.first_buffer(), None);
+        assert!(Pane::Empty.is_empty());
+    }
+
+    #[test]
+    fn first_buffer_populated_tree() {
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: Box::new(Pane::Empty),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: Box::new(Pane::Empty),
+                b: buffer(Buffer::Internal(Internal::Logs)),
+            }),
+        };
+
+        assert!(!layout.is_empty());
+        assert_eq!(
+            layout.first_buffer(),
+            Some(vec![Direction::B, Direction::B])
+        );
+
+        let layout = Pane::Buffer {
+            buffer: Buffer::Internal(Internal::Logs),
+        };
+
+        assert_eq!(layout.first_buffer(), Some(vec![]));
+    }
+
+    #[test]
+    fn replace_buffer_in_tree() {
+        let mut layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(server_buffer("libera")),
+            b: buffer(Buffer::Internal(Internal::Logs)),
+        };
+
+        assert!(
+            layout.replace_buffer(
+                &server_buffer("libera"),
+                &server_buffer("oftc")
+            )
+        );
+        assert_eq!(
+            layout,
+            Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: buffer(server_buffer("oftc")),
+                b: buffer(Buffer::Internal(Internal::Logs)),
+            }
+        );
+        assert!(
+            !layout.replace_buffer(
+                &server_buffer("libera"),
+                &server_buffer("oftc")
+            )
+        );
+    }
+
+    #[test]
+    fn iter_paths_resolve_to_buffers() {
+        let layout = Pane::Split {
+            axis: Axis::Horizontal,
+            ratio: 0.5,
+            a: Box::new(Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: buffer(server_buffer("libera")),
+                b: Box::new(Pane::Empty),
+            }),
+            b: Box::new(Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: buffer(Buffer::Internal(Internal::Logs)),
+                b: buffer(Buffer::Internal(Internal::Highlights)),
+            }),
+        };
+
+        let paths = layout.iter_paths().collect::<Vec<_>>();
+
+        assert_eq!(
+            paths
+                .iter()
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![Direction::A, Direction::A],
+                vec![Direction::B, Direction::A],
+                vec![Direction::B, Direction::B],
+            ]
+        );
+
+        for (path, buffer) in paths {
+            assert_eq!(
+                layout.get(&path),
+                Some(&Pane::Buffer {
+                    buffer: buffer.clone()
+                })
+            );
+        }
+
+        assert_eq!(
+            layout.buffers().cloned().collect::<Vec<_>>(),
+            vec![
+                server_buffer("libera"),
+                Buffer::Internal(Internal::Logs),
+                Buffer::Internal(Internal::Highlights),
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_buffers_with_custom_chantypes() {
+        let casemapping = isupport::CaseMap::default();
+
+        let channel = |server: &str, name: &str, chantypes: &[char]| {
+            Buffer::Upstream(Upstream::Channel(
+                Server::from(crate::server::ServerName::from(server)),
+                target::Channel::from_str(name, chantypes, casemapping),
+            ))
+        };
+
+        let query = Buffer::Upstream(Upstream::Query(
+            Server::from(crate::server::ServerName::from("libera")),
+            target::Query::parse("dan", &['+'], &[], casemapping).unwrap(),
+        ));
+
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: buffer(channel("libera", "+halloy", &['+'])),
+                b: buffer(query.clone()),
+            }),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: buffer(channel("libera", "#halloy", &['#'])),
+                b: buffer(server_buffer("libera")),
+            }),
+        };
+
+        let isupport = HashMap::from([(
+            isupport::Kind::CHANTYPES,
+            isupport::Parameter::CHANTYPES(Some(vec!['+'])),
+        )]);
+
+        assert_eq!(
+            layout.channel_buffers(&isupport).collect::<Vec<_>>(),
+            vec![&channel("libera", "+halloy", &['+'])]
+        );
+
+        assert_eq!(
+            layout.channel_buffers(&HashMap::new()).collect::<Vec<_>>(),
+            vec![&channel("libera", "#halloy", &['#'])]
+        );
+    }
+
+    // Serialized layouts are persisted, so changes to this shape need a
+    // migration for existing data
+    const PANE_JSON: &str = concat!(
+        r##"{"Split":{"axis":"Vertical","ratio":0.25,"##,
+        r##""a":{"Buffer":{"buffer":{"Server":{"name":"libera","network":null}}}},"##,
+        r##""b":{"Split":{"axis":"Horizontal","ratio":0.5,"##,
+        r##""a":{"Buffer":{"buffer":{"Channel":[{"name":"libera","network":null},"##,
+        r##"{"prefixes":[],"normalized":"#halloy","raw":"#halloy"}]}}},"##,
+        r##""b":{"Split":{"axis":"Vertical","ratio":0.75,"##,
+        r##""a":{"Buffer":{"buffer":"Logs"}},"b":"Empty"}}}}}}"##,
+    );
+
+    fn golden_layout() -> Pane {
+        Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.25,
+            a: buffer(server_buffer("libera")),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: buffer(Buffer::Upstream(Upstream::Channel(
+                    Server::from(crate::server::ServerName::from("libera")),
+                    target::Channel::from_str(
+                        "#halloy",
+                        isupport::DEFAULT_CHANTYPES,
+                        isupport::CaseMap::default(),
+                    ),
+                ))),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Vertical,
+                    ratio: 0.75,
+                    a: buffer(Buffer::Internal(Internal::Logs)),
+                    b: Box::new(Pane::Empty),
+                }),
+            }),
+        }
+    }
+
+    #[test]
+    fn serialize_golden() {
+        assert_eq!(serde_json::to_string(&golden_layout()).unwrap(), PANE_JSON);
+    }
+
+    #[test]
+    fn deserialize_golden() {
+        assert_eq!(
+            serde_json::from_str::<Pane>(PANE_JSON).unwrap(),
+            golden_layout()
+        );
+    }
+
+    #[test]
+    fn validate_valid_tree() {
+        assert_eq!(golden_layout().validate(), Ok(()));
+        assert_eq!(Pane::Empty.validate(), Ok(()));
+    }
+
+    #[test]
+    fn repair_invariant_violations() {
+        let mut layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.0,
+            a: buffer(server_buffer("libera")),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: Box::new(Pane::Empty),
+                b: Box::new(Pane::Empty),
+            }),
+        };
+
+        assert_eq!(layout.repair(), vec![PaneError::InvalidRatio(0.0)]);
+        assert_eq!(
+            layout,
+            Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: buffer(server_buffer("libera")),
+                b: Box::new(Pane::Empty),
+            }
+        );
+        assert_eq!(layout.validate(), Ok(()));
+
+        let mut layout = golden_layout();
+        assert!(layout.repair().is_empty());
+        assert_eq!(layout, golden_layout());
+    }
+
+    #[test]
+    fn validate_invariant_violations() {
+        let split = |ratio: f32, a: Box<Pane>, b: Box<Pane>| Pane::Split {
+            axis: Axis::Vertical,
+            ratio,
+            a,
+            b,
+        };
+
+        let empty_split = split(
+            0.5,
+            buffer(server_buffer("libera")),
+            Box::new(split(0.5, Box::new(Pane::Empty), Box::new(Pane::Empty))),
+        );
+
+        assert_eq!(empty_split.validate(), Err(PaneError::EmptySplit));
+
+        for ratio in [0.0, 1.0, -0.5, f32::NAN] {
+            let layout = split(
+                ratio,
+                buffer(server_buffer("libera")),
+                Box::new(Pane::Empty),
+            );
+
+            assert!(matches!(
+                layout.validate(),
+                Err(PaneError::InvalidRatio(_))
+            ));
+        }
+
+        let mut deep = Pane::Buffer {
+            buffer: server_buffer("libera"),
+        };
+
+        for _ in 0..MAX_DEPTH {
+            deep = split(0.5, Box::new(deep), Box::new(Pane::Empty));
+        }
+
+        assert_eq!(deep.validate(), Ok(()));
+
+        let deep = split(0.5, Box::new(deep), Box::new(Pane::Empty));
+
+        assert_eq!(deep.validate(), Err(PaneError::TooDeep(MAX_DEPTH + 1)));
+    }
+
+    #[test]
+    fn grid_even() {
+        let buffers = ["a", "b", "c", "d"].map(server_buffer);
+
+        let grid = Pane::grid(&buffers, 2);
+
+        assert_eq!(
+            grid,
+            Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: Box::new(Pane::Split {
+                    axis: Axis::Horizontal,
+                    ratio: 0.5,
+                    a: buffer(server_buffer("a")),
+                    b: buffer(server_buffer("c")),
+                }),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Horizontal,
+                    ratio: 0.5,
+                    a: buffer(server_buffer("b")),
+                    b: buffer(server_buffer("d")),
+                }),
+            }
+        );
+        assert_eq!(grid.validate(), Ok(()));
+    }
+
+    #[test]
+    fn grid_non_divisible() {
+        let buffers = ["a", "b", "c", "d", "e"].map(server_buffer);
+
+        let grid = Pane::grid(&buffers, 2);
+
+        assert_eq!(
+            grid,
+            Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: Box::new(Pane::Split {
+                    axis: Axis::Horizontal,
+                    ratio: 1.0 / 3.0,
+                    a: buffer(server_buffer("a")),
+                    b: Box::new(Pane::Split {
+                        axis: Axis::Horizontal,
+                        ratio: 0.5,
+                        a: buffer(server_buffer("c")),
+                        b: buffer(server_buffer("e")),
+                    }),
+                }),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Horizontal,
+                    ratio: 1.0 / 3.0,
+                    a: buffer(server_buffer("b")),
+                    b: Box::new(Pane::Split {
+                        axis: Axis::Horizontal,
+                        ratio: 0.5,
+                        a: buffer(server_buffer("d")),
+                        b: Box::new(Pane::Empty),
+                    }),
+                }),
+            }
+        );
+        assert_eq!(grid.validate(), Ok(()));
+
+        assert_eq!(
+            Pane::grid(&buffers[..1], 4),
+            Pane::Buffer {
+                buffer: server_buffer("a"),
+            }
+        );
+        assert_eq!(Pane::grid(&[], 2), Pane::Empty);
+    }
+
+    #[test]
+    fn shares_buffer_between_layouts() {
+        let layout = golden_layout();
+
+        let overlapping = Pane::Split {
+            axis: Axis::Horizontal,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: buffer(server_buffer("oftc")),
+        };
+        let disjoint = Pane::Split {
+            axis: Axis::Horizontal,
+            ratio: 0.5,
+            a: buffer(server_buffer("oftc")),
+            b: Box::new(Pane::Empty),
+        };
+
+        assert!(layout.shares_buffer(&overlapping));
+        assert!(overlapping.shares_buffer(&layout));
+        assert!(!layout.shares_buffer(&disjoint));
+        assert!(!layout.shares_buffer(&Pane::Empty));
+    }
+
+    #[test]
+    fn layout_rects_collapses_below_min_size() {
+        let area = Rect {
+            x: 0,
+            y: 0,
+            width: 100,
+            height: 60,
+        };
+
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.75,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: buffer(Buffer::Internal(Internal::Highlights)),
+        };
+
+        assert_eq!(
+            layout.layout_rects(area, (40, 20)),
+            Layout {
+                rects: vec![(Buffer::Internal(Internal::Logs), area)],
+                hidden: vec![Buffer::Internal(Internal::Highlights)],
+            }
+        );
+        assert!(layout.layout_rects(area, (20, 20)).hidden.is_empty());
+    }
+
+    #[test]
+    fn layout_rects_collapses_deep_tree() {
+        let area = Rect {
+            x: 0,
+            y: 0,
+            width: 200,
+            height: 100,
+        };
+
+        // Each level halves the remaining width: 98, 47, 22 and 21, 9 and 8
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.5,
+                a: buffer(Buffer::Internal(Internal::Highlights)),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Vertical,
+                    ratio: 0.5,
+                    a: buffer(Buffer::Internal(Internal::FileTransfers)),
+                    b: Box::new(Pane::Split {
+                        axis: Axis::Vertical,
+                        ratio: 0.5,
+                        a: buffer(server_buffer("libera")),
+                        b: buffer(server_buffer("oftc")),
+                    }),
+                }),
+            }),
+        };
+
+        let collapsed = layout.layout_rects(area, (30, 20));
+
+        assert_eq!(
+            collapsed
+                .rects
+                .iter()
+                .map(|(buffer, rect)| (buffer.clone(), rect.width))
+                .collect::<Vec<_>>(),
+            vec![
+                (Buffer::Internal(Internal::Logs), 98),
+                (Buffer::Internal(Internal::Highlights), 47),
+                (Buffer::Internal(Internal::FileTransfers), 47),
+            ]
+        );
+        assert_eq!(
+            collapsed.hidden,
+            vec![server_buffer("libera"), server_buffer("oftc")]
+        );
+    }
+
+    #[test]
+    fn splits_nested() {
+        let layout = Pane::Split {
+            axis: Axis::Horizontal,
+            ratio: 0.3,
+            a: Box::new(Pane::Split {
+                axis: Axis::Vertical,
+                ratio: 0.25,
+                a: buffer(server_buffer("libera")),
+                b: buffer(Buffer::Internal(Internal::Highlights)),
+            }),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.75,
+                a: buffer(Buffer::Internal(Internal::FileTransfers)),
+                b: Box::new(Pane::Split {
+                    axis: Axis::Vertical,
+                    ratio: 0.5,
+                    a: buffer(server_buffer("oftc")),
+                    b: Box::new(Pane::Empty),
+                }),
+            }),
+        };
+
+        assert_eq!(
+            layout.splits().collect::<Vec<_>>(),
+            vec![
+                (vec![], Axis::Horizontal, 0.3),
+                (vec![Direction::A], Axis::Vertical, 0.25),
+                (vec![Direction::B], Axis::Horizontal, 0.75),
+                (vec![Direction::B, Direction::B], Axis::Vertical, 0.5),
+            ]
+        );
+        assert_eq!(Pane::Empty.splits().count(), 0);
+        assert_eq!(
+            Pane::Buffer {
+                buffer: Buffer::Internal(Internal::Logs)
+            }
+            .splits()
+            .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn take_subtree_normalizes_source() {
+        let subtree = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.25,
+            a: buffer(server_buffer("libera")),
+            b: buffer(Buffer::Internal(Internal::Highlights)),
+        };
+
+        let mut layout = Pane::Split {
+            axis: Axis::Horizontal,
+            ratio: 0.3,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.75,
+                a: Box::new(subtree.clone()),
+                b: Box::new(Pane::Empty),
+            }),
+        };
+
+        assert_eq!(
+            layout.take_subtree(&[Direction::B, Direction::A]),
+            Ok(subtree)
+        );
+
+        // The split left with two empty panes collapses
+        assert_eq!(
+            layout,
+            Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.3,
+                a: buffer(Buffer::Internal(Internal::Logs)),
+                b: Box::new(Pane::Empty),
+            }
+        );
+        assert!(layout.validate().is_ok());
+
+        assert_eq!(
+            layout.take_subtree(&[Direction::A, Direction::A]),
+            Err(PaneError::InvalidPath)
+        );
+        assert_eq!(
+            layout.take_subtree(&[]),
+            Ok(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.3,
+                a: buffer(Buffer::Internal(Internal::Logs)),
+                b: Box::new(Pane::Empty),
+            })
+        );
+        assert_eq!(layout, Pane::Empty);
+    }
+
+    #[test]
+    fn open_target_policy() {
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Empty),
+        };
+
+        assert_eq!(
+            layout.open_target(&[Direction::B]),
+            OpenTarget::FillEmpty(vec![Direction::B])
+        );
+        assert_eq!(
+            layout.open_target(&[Direction::A]),
+            OpenTarget::SplitLeaf(vec![Direction::A])
+        );
+        assert_eq!(Pane::Empty.open_target(&[]), OpenTarget::FillEmpty(vec![]));
+
+        // A focused split (or missing pane) falls back to the first buffer
+        assert_eq!(
+            layout.open_target(&[]),
+            OpenTarget::SplitLeaf(vec![Direction::A])
+        );
+        assert_eq!(
+            layout.open_target(&[Direction::A, Direction::B]),
+            OpenTarget::SplitLeaf(vec![Direction::A])
+        );
+
+        // Splitting a leaf at the maximum depth would nest too deeply
+        let deep = (0..MAX_DEPTH).fold(
+            Pane::Buffer {
+                buffer: Buffer::Internal(Internal::Logs),
+            },
+            |pane, _| Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: Box::new(pane),
+                b: Box::new(Pane::Empty),
+            },
+        );
+        let deepest = vec![Direction::A; MAX_DEPTH];
+
+        assert_eq!(
+            deep.open_target(&deepest),
+            OpenTarget::ReplaceCurrent(deepest)
+        );
+    }
+
+    #[test]
+    fn focus_after_removal_prefers_sibling() {
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: Box::new(Pane::Split {
+                    axis: Axis::Vertical,
+                    ratio: 0.5,
+                    a: buffer(Buffer::Internal(Internal::Highlights)),
+                    b: buffer(server_buffer("libera")),
+                }),
+                b: Box::new(Pane::Empty),
+            }),
+        };
+
+        // The sibling split's first buffer
+        assert_eq!(
+            layout.focus_after_removal(&[Direction::A]),
+            Some(vec![Direction::B, Direction::A, Direction::A])
+        );
+        assert_eq!(
+            layout.focus_after_removal(&[
+                Direction::B,
+                Direction::A,
+                Direction::B
+            ]),
+            Some(vec![Direction::B, Direction::A, Direction::A])
+        );
+
+        // Without a sibling buffer, the previous buffer in pre-order
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Split {
+                axis: Axis::Horizontal,
+                ratio: 0.5,
+                a: buffer(Buffer::Internal(Internal::Highlights)),
+                b: Box::new(Pane::Empty),
+            }),
+        };
+
+        assert_eq!(
+            layout.focus_after_removal(&[Direction::B, Direction::A]),
+            Some(vec![Direction::A])
+        );
+    }
+
+    #[test]
+    fn focus_after_removal_last_buffer() {
+        let layout = Pane::Split {
+            axis: Axis::Vertical,
+            ratio: 0.5,
+            a: buffer(Buffer::Internal(Internal::Logs)),
+            b: Box::new(Pane::Empty),
+        };
+
+        assert_eq!(layout.focus_after_removal(&[Direction::A]), None);
+
+        let layout = Pane::Buffer {
+            buffer: Buffer::Internal(Internal::Logs),
+        };
+
+        assert_eq!(layout.focus_after_removal(&[]), None);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::Arc;
 use std::{cmp, fmt};
@@ -78,6 +79,29 @@ impl Target {
             }))
         }
     }
+
+    pub fn renormalized(&self, casemapping: isupport::CaseMap) -> Self {
+        match self {
+            Target::Channel(channel) => {
+                Target::Channel(channel.renormalized(casemapping))
+            }
+            Target::Query(query) => {
+                Target::Query(query.renormalized(casemapping))
+            }
+        }
+    }
+}
+
+// Targets created before CASEMAPPING is received are normalized with the
+// default casemapping, so maps keyed by them must be re-keyed when it arrives
+pub fn renormalize_keys<V>(
+    map: &mut HashMap<Target, V>,
+    casemapping: isupport::CaseMap,
+) {
+    *map = map
+        .drain()
+        .map(|(target, value)| (target.renormalized(casemapping), value))
+        .collect();
 }
 
 impl PartialEq for Target {
@@ -208,6 +232,21 @@ impl Channel {
         &self.0.prefixes
     }
 
+    pub fn renormalized(&self, casemapping: isupport::CaseMap) -> Self {
+        let channel = self
+            .0
+            .raw
+            .char_indices()
+            .nth(self.0.prefixes.len())
+            .map_or("", |(index, _)| &self.0.raw[index..]);
+
+        Channel::from(ChannelData {
+            prefixes: self.0.prefixes.clone(),
+            normalized: casemapping.normalize(channel),
+            raw: self.0.raw.clone(),
+        })
+    }
+
     pub fn to_target(&self) -> Target {
         Target::Channel(self.clone())
     }
@@ -316,6 +355,13 @@ impl Query {
         }
     }
 
+    pub fn renormalized(&self, casemapping: isupport::CaseMap) -> Self {
+        Query::from(QueryData {
+            normalized: casemapping.normalize(&self.0.raw),
+            raw: self.0.raw.clone(),
+        })
+    }
+
     pub fn to_target(&self) -> Target {
         Target::Query(self.clone())
     }
@@ -360,3 +406,196 @@ pub enum ParseError {
     #[error("unable to parse query from {0}")]
     InvalidQuery(String),
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetKind {
+    Channel,
+    Query,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoutedTarget {
+    pub kind: TargetKind,
+    pub key: String,
+    pub status_prefixes: Vec<char>,
+}
+
+// Route an incoming message target to its buffer, stripping any STATUSMSG
+// prefixes and normalizing the remaining channel or query via casemapping
+pub fn route_target(
+    isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    raw_target: &str,
+) -> RoutedTarget {
+    let casemapping = isupport::get_casemapping_or_default(isupport);
+
+    if let Some((status_prefixes, channel)) = proto::parse_channel_from_target(
+        raw_target,
+        isupport::get_chantypes_or_default(isupport),
+        isupport::get_statusmsg_or_default(isupport),
+    ) {
+        RoutedTarget {
+            kind: TargetKind::Channel,
+            key: casemapping.normalize(&channel),
+            status_prefixes,
+        }
+    } else {
+        RoutedTarget {
+            kind: TargetKind::Query,
+            key: casemapping.normalize(raw_target),
+            status_prefixes: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route_status_prefixed_channel() {
+        let isupport = HashMap::from([
+            (
+                isupport::Kind::CASEMAPPING,
+                isupport::Parameter::CASEMAPPING(isupport::CaseMap::ASCII),
+            ),
+            (
+                isupport::Kind::STATUSMSG,
+                isupport::Parameter::STATUSMSG(vec!['@', '+']),
+            ),
+        ]);
+
+        assert_eq!(
+            route_target(&isupport, "@#Chan"),
+            RoutedTarget {
+                kind: TargetKind::Channel,
+                key: "#chan".to_string(),
+                status_prefixes: vec!['@'],
+            }
+        );
+        assert_eq!(
+            route_target(&isupport, "#Chan"),
+            RoutedTarget {
+                kind: TargetKind::Channel,
+                key: "#chan".to_string(),
+                status_prefixes: vec![],
+            }
+        );
+        assert_eq!(
+            route_target(&isupport, "Alice"),
+            RoutedTarget {
+                kind: TargetKind::Query,
+                key: "alice".to_string(),
+                status_prefixes: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn route_with_rfc1459_casemapping() {
+        let isupport = HashMap::from([
+            (
+                isupport::Kind::CASEMAPPING,
+                isupport::Parameter::CASEMAPPING(isupport::CaseMap::RFC1459),
+            ),
+            (
+                isupport::Kind::STATUSMSG,
+                isupport::Parameter::STATUSMSG(vec!['@']),
+            ),
+        ]);
+
+        assert_eq!(
+            route_target(&isupport, "@#Halloy[Dev]"),
+            RoutedTarget {
+                kind: TargetKind::Channel,
+                key: "#halloy{dev}".to_string(),
+                status_prefixes: vec!['@'],
+            }
+        );
+        assert_eq!(
+            route_target(&isupport, "Nick[Away]"),
+            RoutedTarget {
+                kind: TargetKind::Query,
+                key: "nick{away}".to_string(),
+                status_prefixes: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn route_with_overlapping_chantypes_and_statusmsg() {
+        let isupport = HashMap::from([
+            (
+                isupport::Kind::CHANTYPES,
+                isupport::Parameter::CHANTYPES(Some(vec!['#', '+'])),
+            ),
+            (
+                isupport::Kind::STATUSMSG,
+                isupport::Parameter::STATUSMSG(vec!['@', '+']),
+            ),
+        ]);
+
+        assert_eq!(
+            route_target(&isupport, "+#Chan"),
+            RoutedTarget {
+                kind: TargetKind::Channel,
+                key: "#chan".to_string(),
+                status_prefixes: vec!['+'],
+            }
+        );
+        assert_eq!(
+            route_target(&isupport, "+Chan"),
+            RoutedTarget {
+                kind: TargetKind::Channel,
+                key: "+chan".to_string(),
+                status_prefixes: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn renormalize_keys_after_late_casemapping() {
+        let chantypes = ['#'];
+
+        // Targets created before CASEMAPPING is received use the default
+        let mut map = HashMap::from([
+            (
+                Target::parse(
+                    "@#Halloy[dev]",
+                    &chantypes,
+                    &['@'],
+                    isupport::CaseMap::default(),
+                ),
+                1,
+            ),
+            (
+                Target::parse(
+                    "Casper[m]",
+                    &chantypes,
+                    &['@'],
+                    isupport::CaseMap::default(),
+                ),
+                2,
+            ),
+        ]);
+
+        // CASEMAPPING arrives in a later RPL_ISUPPORT line
+        let Ok(isupport::Operation::Add(isupport::Parameter::CASEMAPPING(
+            casemapping,
+        ))) = "CASEMAPPING=rfc1459".parse::<isupport::Operation>()
+        else {
+            panic!("CASEMAPPING not parsed");
+        };
+
+        let channel =
+            Target::parse("#halloy{DEV}", &chantypes, &['@'], casemapping);
+        let query = Target::parse("casper{M}", &chantypes, &['@'], casemapping);
+
+        assert_eq!(map.get(&channel), None);
+
+        renormalize_keys(&mut map, casemapping);
+
+        assert_eq!(map.get(&channel), Some(&1));
+        assert_eq!(map.get(&query), Some(&2));
+        assert!(map.keys().any(|target| target.as_str() == "@#Halloy[dev]"));
+    }
+}
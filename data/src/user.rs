@@ -1,5 +1,5 @@
 use std::cmp::Reverse;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::hash::Hash;
 
@@ -263,6 +263,21 @@ impl User {
         self.access_levels.contains(&access_level)
     }
 
+    pub fn membership(
+        &self,
+        isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    ) -> Membership {
+        let mut membership = Membership::default();
+
+        for prefix in
+            self.access_levels.iter().filter_map(|level| level.prefix())
+        {
+            membership.add_prefix(isupport, prefix);
+        }
+
+        membership
+    }
+
     pub fn update_access_level(
         &mut self,
         operation: mode::Operation,
@@ -611,9 +626,10 @@ pub enum ProtectedPrefix {
     Alternative,
 }
 
-impl std::fmt::Display for AccessLevel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let access_level = match self {
+impl AccessLevel {
+    // PREFIX character of the access level (members have none)
+    pub fn prefix(self) -> Option<char> {
+        match self {
             AccessLevel::Founder => Some(proto::FOUNDER_PREFIX),
             AccessLevel::Protected(prefix) => match prefix {
                 ProtectedPrefix::Standard => Some(proto::PROTECTED_PREFIX_STD),
@@ -625,9 +641,13 @@ impl std::fmt::Display for AccessLevel {
             AccessLevel::HalfOp => Some(proto::HALF_OPERATOR_PREFIX),
             AccessLevel::Voice => Some(proto::VOICED_PREFIX),
             AccessLevel::Member => None,
-        };
+        }
+    }
+}
 
-        if let Some(access_level) = access_level {
+impl std::fmt::Display for AccessLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(access_level) = self.prefix() {
             write!(f, "{access_level}")
         } else {
             write!(f, "")
@@ -670,6 +690,154 @@ impl TryFrom<mode::Channel> for AccessLevel {
     }
 }
 
+// A user's channel status as PREFIX characters, kept ordered by PREFIX rank
+// (highest first)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Membership {
+    prefixes: Vec<char>,
+}
+
+impl Membership {
+    pub fn prefixes(&self) -> &[char] {
+        &self.prefixes
+    }
+
+    pub fn highest(
+        &self,
+        isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+    ) -> Option<char> {
+        let prefix = isupport::get_prefix_or_default(isupport);
+
+        self.prefixes
+            .iter()
+            .filter_map(|c| Some((prefix_rank(prefix, *c)?, *c)))
+            .min()
+            .map(|(_, c)| c)
+    }
+
+    pub fn has_mode(
+        &self,
+        isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+        mode: char,
+    ) -> bool {
+        isupport::get_prefix_or_default(isupport)
+            .iter()
+            .find(|prefix_map| prefix_map.mode == mode)
+            .is_some_and(|prefix_map| {
+                self.prefixes.contains(&prefix_map.prefix)
+            })
+    }
+
+    // Prefixes not listed in PREFIX are ignored
+    pub fn add_prefix(
+        &mut self,
+        isupport: &HashMap<isupport::Kind, isupport::Parameter>,
+        prefix: char,
+    ) {
+        let prefix_maps = isupport::get_prefix_or_default(isupport);
+
+        if self.prefixes.contains(&prefix) {
+            return;
+        }
+
+        let Some(rank) = prefix_rank(prefix_maps, prefix) else {
+            return;
+        };
+
+        let index = self
+            .prefixes
+            .iter()
+            .position(|c| {
+                prefix_rank(prefix_maps, *c).is_none_or(|other| other > rank)
+            })
+            .unwrap_or(self.prefixes.len());
+
+        self.prefixes.insert(index, prefix);
+    }
+
+    pub fn remove_prefix(&mut self, prefix: char) {
+        self.prefixes.retain(|c| *c != prefix);
+    }
+}
+
+fn prefix_rank(
+    prefix_maps: &[isupport::PrefixMap],
+    prefix: char,
+) -> Option<usize> {
+    prefix_maps
+        .iter()
+        .position(|prefix_map| prefix_map.prefix == prefix)
+}
+
+// Accounts of users keyed by nick (as reported by WHOX, ACCOUNT and
+// extended-join), so matching on account keeps working as nicks change.
+// Entries are removed once the user is no longer seen, so that a nick does
+// not keep the account of a previous user.
+#[derive(Debug, Clone, Default)]
+pub struct AccountIndex(HashMap<Nick, String>);
+
+impl AccountIndex {
+    // Only replies to polls requesting the account field are applied
+    pub fn update(
+        &mut self,
+        record: &isupport::WhoxRecord,
+        casemapping: isupport::CaseMap,
+    ) {
+        if !record.token.as_ref().is_some_and(|token| {
+            isupport::WhoXPollParameters::WithAccountName.matches_token(token)
+        }) {
+            return;
+        }
+
+        let nick = Nick::from_str(&record.nick, casemapping);
+
+        if let Some(account) = &record.account {
+            self.0.insert(nick, account.clone());
+        } else {
+            self.0.remove(&nick);
+        }
+    }
+
+    // An accountname of * indicates the user is not logged in
+    pub fn set(&mut self, nick: NickRef, accountname: &str) {
+        if accountname == "*" {
+            self.remove(nick);
+        } else {
+            self.0.insert(Nick::from(nick), accountname.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, nick: NickRef) {
+        self.0.remove(&Nick::from(nick));
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(NickRef) -> bool) {
+        self.0.retain(|nick, _| f(nick.as_nickref()));
+    }
+
+    pub fn rename(&mut self, old_nick: NickRef, new_nick: Nick) {
+        if let Some(account) = self.0.remove(&Nick::from(old_nick)) {
+            self.0.insert(new_nick, account);
+        }
+    }
+
+    pub fn renormalize(&mut self, casemapping: isupport::CaseMap) {
+        self.0 = self
+            .0
+            .drain()
+            .map(|(mut nick, account)| {
+                nick.renormalize(casemapping);
+
+                (nick, account)
+            })
+            .collect();
+    }
+
+    pub fn account_for_nick(&self, nick: NickRef) -> Option<&str> {
+        self.0.get(&Nick::from(nick)).map(String::as_str)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -889,4 +1057,128 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn account_index() {
+        let casemapping = isupport::CaseMap::default();
+
+        let record = |nick: &str, account: Option<&str>| isupport::WhoxRecord {
+            token: Some("99".parse().unwrap()),
+            channel: "#halloy".to_string(),
+            user: None,
+            host: None,
+            server: None,
+            nick: nick.to_string(),
+            flags: isupport::WhoFlags {
+                away: Some(false),
+                oper: false,
+                prefixes: vec![],
+            },
+            account: account.map(String::from),
+            realname: None,
+        };
+
+        let mut accounts = AccountIndex::default();
+
+        accounts.update(&record("Dan", Some("dan")), casemapping);
+
+        let dan = Nick::from_str("dan", casemapping);
+
+        assert_eq!(accounts.account_for_nick(dan.as_nickref()), Some("dan"));
+
+        let danny = Nick::from_str("danny", casemapping);
+
+        accounts.rename(dan.as_nickref(), danny.clone());
+
+        assert_eq!(accounts.account_for_nick(dan.as_nickref()), None);
+        assert_eq!(accounts.account_for_nick(danny.as_nickref()), Some("dan"));
+
+        accounts.update(&record("danny", None), casemapping);
+
+        assert_eq!(accounts.account_for_nick(danny.as_nickref()), None);
+
+        // Replies to polls without the account field are not applied
+        let mut reply = record("danny", None);
+        reply.token = Some("9".parse().unwrap());
+        accounts.set(danny.as_nickref(), "dan");
+        accounts.update(&reply, casemapping);
+
+        assert_eq!(accounts.account_for_nick(danny.as_nickref()), Some("dan"));
+
+        accounts.set(danny.as_nickref(), "*");
+
+        assert_eq!(accounts.account_for_nick(danny.as_nickref()), None);
+    }
+
+    #[test]
+    fn account_index_quit_then_reuse_nick() {
+        let casemapping = isupport::CaseMap::default();
+        let dan = Nick::from_str("dan", casemapping);
+
+        let mut accounts = AccountIndex::default();
+
+        accounts.set(dan.as_nickref(), "dan");
+        assert_eq!(accounts.account_for_nick(dan.as_nickref()), Some("dan"));
+
+        // dan quits, and someone else (not logged in) takes the nick
+        accounts.remove(dan.as_nickref());
+
+        let other = Nick::from_str("Dan", casemapping);
+
+        assert_eq!(accounts.account_for_nick(other.as_nickref()), None);
+
+        // Users no longer seen in any channel are forgotten
+        accounts.set(dan.as_nickref(), "dan");
+        accounts.set(Nick::from_str("rust", casemapping).as_nickref(), "rust");
+        accounts.retain(|nick| nick == other.as_nickref());
+
+        assert_eq!(accounts.account_for_nick(other.as_nickref()), Some("dan"));
+        assert_eq!(
+            accounts.account_for_nick(
+                Nick::from_str("rust", casemapping).as_nickref()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn membership() {
+        let isupport = HashMap::from([(
+            isupport::Kind::PREFIX,
+            isupport::Parameter::PREFIX(
+                [('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')]
+                    .into_iter()
+                    .map(|(mode, prefix)| isupport::PrefixMap { prefix, mode })
+                    .collect(),
+            ),
+        )]);
+
+        let mut membership = Membership::default();
+
+        assert_eq!(membership.highest(&isupport), None);
+
+        membership.add_prefix(&isupport, '+');
+        membership.add_prefix(&isupport, '~');
+        membership.add_prefix(&isupport, '%');
+        membership.add_prefix(&isupport, '+');
+        // Not in PREFIX
+        membership.add_prefix(&isupport, '!');
+
+        assert_eq!(membership.prefixes(), &['~', '%', '+']);
+        assert_eq!(membership.highest(&isupport), Some('~'));
+        assert!(membership.has_mode(&isupport, 'h'));
+        assert!(!membership.has_mode(&isupport, 'o'));
+
+        membership.add_prefix(&isupport, '@');
+        membership.remove_prefix('~');
+
+        assert_eq!(membership.prefixes(), &['@', '%', '+']);
+        assert_eq!(membership.highest(&isupport), Some('@'));
+
+        membership.remove_prefix('@');
+        membership.remove_prefix('%');
+
+        assert_eq!(membership.highest(&isupport), Some('+'));
+        assert!(membership.has_mode(&isupport, 'v'));
+    }
 }
@@ -73,28 +73,32 @@ pub fn is_channel(target: &str, chantypes: &[char]) -> bool {
 /// https://modern.ircdocs.horse/#channels
 ///
 /// Given a target, split it into a channel name (beginning with a character in `chantypes`) and a
-/// possible list of prefixes (given in `statusmsg_prefixes`). If these two lists overlap, the
-/// behaviour is unspecified.
+/// possible list of prefixes (given in `statusmsg_prefixes`). If these two lists overlap, a
+/// character in both is only treated as a prefix when it is followed by a `chantypes` character.
 pub fn parse_channel_from_target(
     target: &str,
     chantypes: &[char],
     statusmsg_prefixes: &[char],
 ) -> Option<(Vec<char>, String)> {
-    // We parse the target by finding the first character in chantypes, and returning (even if that
-    // character is in statusmsg_prefixes)
-    // If the characters before the first chantypes character are all valid prefixes, then we have
-    // a valid channel name with those prefixes.
-    let chan_index = target.find(chantypes)?;
-
-    // This will not panic, since `find` always returns a valid codepoint index.
-    // We call `find` -> `split_at` because it is an _inclusive_ split, which includes the match.
-    // We need to return this since the channel target includes its chantype.
-    let (prefix, chan) = target.split_at(chan_index);
-    if prefix.chars().all(|ref c| statusmsg_prefixes.contains(c)) {
-        Some((prefix.chars().collect(), chan.to_owned()))
-    } else {
-        None
+    // We parse the target by finding the longest run of statusmsg prefixes which is followed by a
+    // character in chantypes.  If the run is empty, then the target is a channel without prefixes.
+    let mut chan_index = None;
+
+    for (index, c) in target.char_indices() {
+        if target[index..].starts_with(chantypes) {
+            chan_index = Some(index);
+        }
+
+        if !statusmsg_prefixes.contains(&c) {
+            break;
+        }
     }
+
+    // This will not panic, since `char_indices` always returns a valid codepoint index.
+    // We need to return the chantype since the channel target includes it.
+    let (prefix, chan) = target.split_at(chan_index?);
+
+    Some((prefix.chars().collect(), chan.to_owned()))
 }
 
 #[macro_export]
@@ -161,6 +165,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_channel_overlapping_prefixes() {
+        let chantypes = &['#', '+'];
+        let prefixes = &['@', '+'];
+        assert_eq!(
+            parse_channel_from_target("+#foo", chantypes, prefixes),
+            Some((vec!['+'], "#foo".to_owned()))
+        );
+        assert_eq!(
+            parse_channel_from_target("+foo", chantypes, prefixes),
+            Some((vec![], "+foo".to_owned()))
+        );
+        assert_eq!(
+            parse_channel_from_target("@+foo", chantypes, prefixes),
+            Some((vec!['@'], "+foo".to_owned()))
+        );
+    }
+
     #[test]
     fn invalid_channels() {
         let chantypes = DEFAULT_CHANNEL_PREFIXES;
@@ -204,6 +204,7 @@ impl State {
                     &clients.get_isupport(buffer.server()),
                 ) && match error {
                     input::Error::ExceedsByteLimit { .. } => true,
+                    input::Error::InvalidCharacter(_) => true,
                     input::Error::Command(
                         command::Error::IncorrectArgCount {
                             actual, max, ..
@@ -1769,7 +1769,7 @@ fn join_command(
                 channels_tooltip.push_str(
                     format!(
                         "\nup to {limit} {} channels per client",
-                        channel_limit.prefix
+                        channel_limit.prefixes
                     )
                     .as_str(),
                 );
@@ -1777,7 +1777,7 @@ fn join_command(
                 channels_tooltip.push_str(
                     format!(
                         "\nunlimited {} channels per client",
-                        channel_limit.prefix
+                        channel_limit.prefixes
                     )
                     .as_str(),
                 );
@@ -1822,6 +1822,7 @@ fn kick_command(
         if target_limit != 1 {
             users_tooltip.push('s');
         }
+        users_tooltip.push_str(" per KICK (more are sent separately)");
     }
 
     let comment_tooltip =